@@ -15,14 +15,8 @@ pub fn load_vm_hello_world(c: &mut Criterion) {
 		&uhyvelib::vm::Parameter {
 			mem_size: 1024 * 100000,
 			num_cpus: 1,
-			verbose: false,
 			hugepage: true,
-			mergeable: false,
-			ip: None,
-			gateway: None,
-			mask: None,
-			nic: None,
-			gdbport: None,
+			..Default::default()
 		},
 	)
 	.expect("Unable to create VM");
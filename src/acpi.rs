@@ -0,0 +1,235 @@
+//! Minimal ACPI tables, which describe the vCPUs, the IOAPIC and the power
+//! management of the VM.
+//!
+//! The tables are placed in the BIOS area, where guests search the RSDP. The
+//! FADT points to the PM1a event and control blocks, which uhyve emulates.
+//! The DSDT only defines `\_S5`, so that guests are able to power off the VM.
+
+use crate::consts::*;
+
+/// Power management profile "unspecified" of the FADT
+const PM_PROFILE_UNSPECIFIED: u8 = 0;
+/// FADT flags: WBINVD, C1 support, no fixed power and sleep button
+const FADT_FLAGS: u32 = (1 << 0) | (1 << 2) | (1 << 4) | (1 << 5);
+/// IA-PC boot architecture flags of the FADT: no VGA
+const IAPC_BOOT_ARCH: u16 = 1 << 2;
+/// MADT flags: the VM has a dual 8259 PIC
+const MADT_PCAT_COMPAT: u32 = 1;
+
+/// Sleep type, which the DSDT defines for S5 (soft off)
+pub const SLP_TYP_S5: u16 = 5;
+/// Bit of PM1 control, which enables the sleep state `SLP_TYP`
+pub const SLP_EN: u16 = 1 << 13;
+/// Bit of PM1 control, which reports that the hardware is in ACPI mode
+pub const SCI_EN: u16 = 1;
+
+/// `Name (_S5, Package () { 5, 5, 0, 0 })` in AML
+const DSDT_AML: [u8; 14] = [
+	0x08, b'_', b'S', b'5', b'_', 0x12, 0x08, 0x04, 0x0a, 0x05, 0x0a, 0x05, 0x00, 0x00,
+];
+
+const OEM_ID: &[u8; 6] = b"UHYVE ";
+const OEM_TABLE_ID: &[u8; 8] = b"UHYVETBL";
+
+fn checksum(bytes: &[u8]) -> u8 {
+	bytes
+		.iter()
+		.fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+		.wrapping_neg()
+}
+
+/// Returns a table with `signature`, `revision` and `body`.
+fn table(signature: &[u8; 4], revision: u8, body: &[u8]) -> Vec<u8> {
+	let length = 36 + body.len();
+	let mut table = Vec::with_capacity(length);
+	table.extend_from_slice(signature);
+	table.extend_from_slice(&(length as u32).to_le_bytes());
+	table.push(revision);
+	table.push(0); // checksum
+	table.extend_from_slice(OEM_ID);
+	table.extend_from_slice(OEM_TABLE_ID);
+	table.extend_from_slice(&1u32.to_le_bytes()); // OEM revision
+	table.extend_from_slice(b"UHYV"); // creator ID
+	table.extend_from_slice(&1u32.to_le_bytes()); // creator revision
+	table.extend_from_slice(body);
+	table[9] = checksum(&table);
+	table
+}
+
+/// Generic address structure of a register in the I/O space
+fn io_register(port: u16, bit_width: u8, access_size: u8) -> [u8; 12] {
+	let mut gas = [0u8; 12];
+	gas[0] = 1; // system I/O
+	gas[1] = bit_width;
+	gas[3] = access_size;
+	gas[4..].copy_from_slice(&u64::from(port).to_le_bytes());
+	gas
+}
+
+fn fadt(dsdt: u64) -> Vec<u8> {
+	// offsets are relative to the end of the header
+	let mut body = vec![0u8; 276 - 36];
+	body[4..8].copy_from_slice(&(dsdt as u32).to_le_bytes());
+	body[9] = PM_PROFILE_UNSPECIFIED;
+	body[10..12].copy_from_slice(&ACPI_SCI_IRQ.to_le_bytes());
+	body[20..24].copy_from_slice(&u32::from(PM1A_EVT_PORT).to_le_bytes());
+	body[28..32].copy_from_slice(&u32::from(PM1A_CNT_PORT).to_le_bytes());
+	body[52] = 4; // PM1_EVT_LEN
+	body[53] = 2; // PM1_CNT_LEN
+	body[60..62].copy_from_slice(&101u16.to_le_bytes()); // no C2
+	body[62..64].copy_from_slice(&1001u16.to_le_bytes()); // no C3
+	body[72] = CMOS_CENTURY_REG;
+	body[73..75].copy_from_slice(&IAPC_BOOT_ARCH.to_le_bytes());
+	body[76..80].copy_from_slice(&FADT_FLAGS.to_le_bytes());
+	body[104..112].copy_from_slice(&dsdt.to_le_bytes());
+	body[112..124].copy_from_slice(&io_register(PM1A_EVT_PORT, 32, 2));
+	body[136..148].copy_from_slice(&io_register(PM1A_CNT_PORT, 16, 2));
+
+	table(b"FACP", 6, &body)
+}
+
+fn madt(num_cpus: u32) -> Vec<u8> {
+	let mut body = Vec::new();
+	body.extend_from_slice(&(APIC_DEFAULT_BASE as u32).to_le_bytes());
+	body.extend_from_slice(&MADT_PCAT_COMPAT.to_le_bytes());
+
+	// the APIC ID of a vCPU is its id
+	for id in 0..num_cpus {
+		if id < 0xff {
+			// processor local APIC, enabled
+			body.extend_from_slice(&[0, 8, id as u8, id as u8]);
+			body.extend_from_slice(&1u32.to_le_bytes());
+		} else {
+			// processor local x2APIC, enabled
+			body.extend_from_slice(&[9, 16, 0, 0]);
+			body.extend_from_slice(&id.to_le_bytes());
+			body.extend_from_slice(&1u32.to_le_bytes());
+			body.extend_from_slice(&id.to_le_bytes());
+		}
+	}
+
+	// IOAPIC with GSI base 0
+	body.extend_from_slice(&[1, 12, 0, 0]);
+	body.extend_from_slice(&(IOAPIC_BASE as u32).to_le_bytes());
+	body.extend_from_slice(&0u32.to_le_bytes());
+
+	// the timer (IRQ 0) is connected to GSI 2
+	body.extend_from_slice(&[2, 10, 0, 0]);
+	body.extend_from_slice(&2u32.to_le_bytes());
+	body.extend_from_slice(&0u16.to_le_bytes());
+
+	// the SCI is level-triggered and active high
+	body.extend_from_slice(&[2, 10, 0, ACPI_SCI_IRQ as u8]);
+	body.extend_from_slice(&u32::from(ACPI_SCI_IRQ).to_le_bytes());
+	body.extend_from_slice(&0x000du16.to_le_bytes());
+
+	table(b"APIC", 4, &body)
+}
+
+fn rsdp(rsdt: u64, xsdt: u64) -> [u8; 36] {
+	let mut rsdp = [0u8; 36];
+	rsdp[..8].copy_from_slice(b"RSD PTR ");
+	rsdp[9..15].copy_from_slice(OEM_ID);
+	rsdp[15] = 2; // ACPI 2.0+
+	rsdp[16..20].copy_from_slice(&(rsdt as u32).to_le_bytes());
+	rsdp[20..24].copy_from_slice(&36u32.to_le_bytes());
+	rsdp[24..32].copy_from_slice(&xsdt.to_le_bytes());
+	rsdp[8] = checksum(&rsdp[..20]);
+	rsdp[32] = checksum(&rsdp);
+	rsdp
+}
+
+/// Creates the ACPI tables of a VM with `num_cpus` vCPUs, which are placed
+/// at the guest physical address `base`. The RSDP is at `base`.
+pub fn create_tables(base: u64, num_cpus: u32) -> Vec<u8> {
+	let mut tables = vec![0u8; 64];
+	let mut append = |table: Vec<u8>| {
+		let addr = base + tables.len() as u64;
+		tables.extend_from_slice(&table);
+		tables.resize((tables.len() + 15) & !15, 0);
+		addr
+	};
+
+	let dsdt = append(table(b"DSDT", 2, &DSDT_AML));
+	let fadt = append(fadt(dsdt));
+	let madt = append(madt(num_cpus));
+	let rsdt = append(table(
+		b"RSDT",
+		1,
+		&[(fadt as u32).to_le_bytes(), (madt as u32).to_le_bytes()].concat(),
+	));
+	let xsdt = append(table(
+		b"XSDT",
+		1,
+		&[fadt.to_le_bytes(), madt.to_le_bytes()].concat(),
+	));
+
+	tables[..36].copy_from_slice(&rsdp(rsdt, xsdt));
+	tables
+}
+
+/// Returns true if the guest requests to power off the VM by writing
+/// `value` into the PM1 control register.
+pub fn is_power_off(value: u16) -> bool {
+	value & SLP_EN != 0 && (value >> 10) & 0x7 == SLP_TYP_S5
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+		u32::from_le_bytes([
+			bytes[offset],
+			bytes[offset + 1],
+			bytes[offset + 2],
+			bytes[offset + 3],
+		])
+	}
+
+	/// Returns the table at the guest physical address `addr` and checks its
+	/// signature and checksum.
+	fn get_table<'a>(tables: &'a [u8], base: u64, addr: u32, signature: &[u8]) -> &'a [u8] {
+		let offset = (u64::from(addr) - base) as usize;
+		let length = read_u32(tables, offset + 4) as usize;
+		let table = &tables[offset..offset + length];
+		assert_eq!(&table[..4], signature);
+		assert_eq!(checksum(table), 0);
+		table
+	}
+
+	#[test]
+	fn test_tables() {
+		let base = 0xe0000;
+		let tables = create_tables(base, 4);
+
+		let rsdp = &tables[..36];
+		assert_eq!(&rsdp[..8], b"RSD PTR ");
+		assert_eq!(checksum(&rsdp[..20]), 0);
+		assert_eq!(checksum(rsdp), 0);
+
+		let rsdt = get_table(&tables, base, read_u32(rsdp, 16), b"RSDT");
+		assert_eq!(rsdt.len(), 36 + 8);
+		let xsdt = get_table(&tables, base, read_u32(rsdp, 24), b"XSDT");
+		assert_eq!(xsdt.len(), 36 + 16);
+
+		let fadt = get_table(&tables, base, read_u32(rsdt, 36), b"FACP");
+		assert_eq!(fadt.len(), 276);
+		assert_eq!(read_u32(fadt, 64), u32::from(PM1A_CNT_PORT));
+		assert_eq!(fadt[108], CMOS_CENTURY_REG);
+		get_table(&tables, base, read_u32(fadt, 40), b"DSDT");
+
+		let madt = get_table(&tables, base, read_u32(rsdt, 40), b"APIC");
+		// 4 local APICs, 1 IOAPIC and 2 interrupt source overrides
+		assert_eq!(madt.len(), 44 + 4 * 8 + 12 + 2 * 10);
+		assert_eq!(read_u32(madt, 44 + 3 * 8 + 4), 1);
+		assert_eq!(read_u32(madt, 44 + 4 * 8 + 4), IOAPIC_BASE as u32);
+	}
+
+	#[test]
+	fn test_power_off() {
+		assert!(is_power_off(SLP_EN | (SLP_TYP_S5 << 10) | SCI_EN));
+		assert!(!is_power_off(SLP_TYP_S5 << 10));
+		assert!(!is_power_off(SLP_EN | (1 << 10)));
+	}
+}
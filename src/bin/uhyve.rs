@@ -11,19 +11,38 @@ extern crate rftrace_frontend;
 use std::env;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::Arc;
 
+use uhyvelib::stats::VmStats;
+#[cfg(not(target_os = "linux"))]
 use uhyvelib::uhyve_run;
 use uhyvelib::utils;
 use uhyvelib::vm;
+use uhyvelib::vm::VmOutcome;
+#[cfg(target_os = "linux")]
+use uhyvelib::{uhyve_restore_with, uhyve_run_with};
 
 use clap::{App, Arg};
 #[cfg(feature = "instrument")]
 use rftrace_frontend::Events;
-use uhyvelib::utils::{filter_cpu_affinity, parse_cpu_affinity};
+use uhyvelib::utils::{filter_cpu_affinity, parse_cpu_affinity, parse_numa_nodes};
 
 const MINIMAL_GUEST_SIZE: usize = 16 * 1024 * 1024;
 const DEFAULT_GUEST_SIZE: usize = 64 * 1024 * 1024;
 
+/// Exit code, if uhyve is unable to create, load or restore the VM
+const EXIT_SETUP_ERROR: i32 = 125;
+/// Exit code, if a vCPU of the guest has crashed
+const EXIT_GUEST_CRASH: i32 = 126;
+/// Exit code, if the guest hasn't finished within `--timeout`
+const EXIT_TIMEOUT: i32 = 124;
+/// Exit code, if the watchdog has detected a hanging vCPU
+const EXIT_GUEST_HANG: i32 = 123;
+/// Exit code, if the guest has reported a panic by pvpanic
+const EXIT_GUEST_PANIC: i32 = 122;
+/// Exit codes, which uhyve reserves for the reasons above
+const RESERVED_EXIT_CODES: std::ops::RangeInclusive<i32> = EXIT_GUEST_PANIC..=EXIT_GUEST_CRASH;
+
 #[cfg(feature = "instrument")]
 static mut EVENTS: Option<&mut Events> = None;
 
@@ -37,6 +56,59 @@ extern "C" fn dump_trace() {
 	}
 }
 
+/// Maps the outcome of the VM to the exit code of uhyve.
+fn exit_code(outcome: VmOutcome) -> i32 {
+	match outcome {
+		VmOutcome::Exit(code) => {
+			// the shell sees only the lowest 8 bits
+			if RESERVED_EXIT_CODES.contains(&(code & 0xff)) {
+				eprintln!(
+					"{}, which is also the exit code of uhyve for its own errors",
+					outcome
+				);
+			}
+			code
+		}
+		VmOutcome::Crash { .. } => {
+			eprintln!("{}", outcome);
+			EXIT_GUEST_CRASH
+		}
+		VmOutcome::Error(_) => {
+			eprintln!("{}", outcome);
+			EXIT_SETUP_ERROR
+		}
+		VmOutcome::Timeout(_) => {
+			eprintln!("{}", outcome);
+			EXIT_TIMEOUT
+		}
+		VmOutcome::Hang { .. } => {
+			eprintln!("{}", outcome);
+			EXIT_GUEST_HANG
+		}
+		VmOutcome::Panic { .. } => {
+			eprintln!("{}", outcome);
+			EXIT_GUEST_PANIC
+		}
+	}
+}
+
+/// Prints the statistics of the VM (if requested) and exits uhyve.
+/// Tells the user, to which pty the console of the guest is connected.
+#[cfg(target_os = "linux")]
+fn announce_console(vm: &mut vm::Uhyve) -> uhyvelib::error::Result<()> {
+	if let Some(path) = vm.console().pty_path() {
+		println!("The console of the guest is connected to {}", path);
+	}
+	Ok(())
+}
+
+fn finish(outcome: VmOutcome, stats: Option<&VmStats>) -> ! {
+	if let Some(stats) = stats {
+		eprint!("{}", stats);
+	}
+	std::process::exit(exit_code(outcome));
+}
+
 // Note that we end main with `std::process::exit` to set the return value and
 // as a result destructors are not run and cleanup may not happen.
 fn main() {
@@ -75,6 +147,53 @@ fn main() {
 				.long("mergeable")
 				.help("Enable kernel feature to merge same pages"),
 		)
+		.arg(
+			Arg::with_name("HUGETLB")
+				.long("hugepages")
+				.value_name("SIZE")
+				.help("Back the guest memory by explicit huge pages (2M or 1G)")
+				.long_help(
+					"Allocates the guest memory from the hugetlb pool of the host
+					 instead of relying on transparent huge pages. The pool must be
+					 large enough to hold the whole guest memory. Because of the PCI
+					 gap below 4 GiB, 1G pages support at most 3328M guest memory.",
+				)
+				.takes_value(true)
+				.possible_values(&["2M", "1G"])
+				.conflicts_with_all(&["MEM_PATH", "MEMFD"])
+				.env("HERMIT_HUGETLB"),
+		)
+		.arg(
+			Arg::with_name("MEM_PATH")
+				.long("mem-path")
+				.value_name("PATH")
+				.help("Back the guest memory by a file (e.g. on tmpfs or hugetlbfs)")
+				.long_help(
+					"Maps the guest memory shared from the given file. If PATH is a
+					 directory (e.g. the mount point of a hugetlbfs), a temporary
+					 file is created in it. The file system decides the page size,
+					 so the option excludes --disable-hugepages and --mergeable.",
+				)
+				.takes_value(true)
+				.conflicts_with_all(&["MEMFD", "DISABLE_HUGEPAGE", "MERGEABLE"])
+				.env("HERMIT_MEM_PATH"),
+		)
+		.arg(Arg::with_name("MEMFD").long("memfd").help(
+			"Back the guest memory by a sealed memfd, which can be shared with other processes",
+		))
+		.arg(Arg::with_name("PREFAULT").long("prefault").help(
+			"Populate the whole guest memory before the guest starts to avoid page faults at runtime",
+		))
+		.arg(
+			Arg::with_name("MLOCK")
+				.long("mlock")
+				.help("Lock the guest memory into RAM (limited by RLIMIT_MEMLOCK)"),
+		)
+		.arg(
+			Arg::with_name("STATS")
+				.long("stats")
+				.help("Print statistics of the vCPU exits and hypercalls at exit (Linux only)"),
+		)
 		.arg(
 			Arg::with_name("MEM")
 				.short("m")
@@ -93,6 +212,126 @@ fn main() {
 				.takes_value(true)
 				.env("HERMIT_CPUS"),
 		)
+		.arg(
+			Arg::with_name("CPU_MODEL")
+				.long("cpu")
+				.value_name("MODEL")
+				.help("CPU model of the guest: host, baseline, x86-64[-v2|-v3|-v4] or a template file")
+				.long_help(
+					"Defines the CPUID, which is exposed to the guest. `host` passes the
+					 CPUID of the host through. `x86-64`, `x86-64-v2`, `x86-64-v3` and
+					 `x86-64-v4` are the microarchitecture levels of the x86-64 psABI
+					 and `baseline` is an alias for `x86-64-v2`. Otherwise, MODEL is the
+					 path of a JSON template. uhyve refuses to start, if the host
+					 doesn't support the CPU model.",
+				)
+				.takes_value(true)
+				.env("HERMIT_CPU_MODEL"),
+		)
+		.arg(
+			Arg::with_name("TSC_KHZ")
+				.long("tsc-khz")
+				.value_name("KHZ")
+				.help("TSC frequency of the guest in kHz (Linux only)")
+				.long_help(
+					"Sets the TSC frequency of each vCPU to KHZ instead of the TSC
+					 frequency of the host, which KVM reports. A frequency other than the
+					 host's requires TSC scaling. The guest sees the frequency in the
+					 boot information and in the CPUID leaves 0x15 and 0x40000010.",
+				)
+				.takes_value(true)
+				.env("HERMIT_TSC_KHZ"),
+		)
+		.arg(
+			Arg::with_name("PROFILE")
+				.long("profile")
+				.value_name("FILE")
+				.help("Profile the guest and write the samples to FILE (Linux only)")
+				.long_help(
+					"Samples the stacks of all vCPUs 99 times per second by walking the
+					 frame pointers of the guest. At exit, the symbolized samples are
+					 written as folded stacks (e.g. for flamegraph.pl) to FILE and like
+					 `perf script` to FILE with the extension .perf.",
+				)
+				.takes_value(true)
+				.env("HERMIT_PROFILE"),
+		)
+		.arg(
+			Arg::with_name("KVM_PV")
+				.long("kvm-pv")
+				.help("Announce the paravirtual features of KVM to the guest (Linux only)")
+				.long_help(
+					"Announces the paravirtual features of KVM in the CPUID leaf
+					 0x40000001, if the host supports them: kvmclock, steal time, PV EOI
+					 and the NOP I/O delay. Guests, which use kvmclock, get a stable time
+					 source instead of the TSC frequency of the boot information.",
+				),
+		)
+		.arg(
+			Arg::with_name("TIMEOUT")
+				.long("timeout")
+				.value_name("DURATION")
+				.help("Stop the guest after DURATION (e.g. 90s, 5m or 1h)")
+				.takes_value(true)
+				.env("HERMIT_TIMEOUT"),
+		)
+		.arg(
+			Arg::with_name("WATCHDOG")
+				.long("watchdog")
+				.value_name("DURATION")
+				.help("Stop the guest if a vCPU makes no progress for DURATION (Linux only)")
+				.long_help(
+					"Stops the guest if the RIP of a vCPU hasn't changed and the vCPU
+					 hasn't exited for DURATION (e.g. 10s). Before, the registers and the
+					 stack of each hanging vCPU are printed. A halted vCPU, which waits
+					 for an interrupt, isn't considered as hanging.",
+				)
+				.takes_value(true)
+				.env("HERMIT_WATCHDOG"),
+		)
+		.arg(
+			Arg::with_name("UNKNOWN_ACCESS")
+				.long("unknown-access")
+				.value_name("POLICY")
+				.help("Handling of accesses to unknown I/O ports and MMIO addresses (Linux only)")
+				.long_help(
+					"Determines how uhyve handles accesses of the guest to I/O ports
+					 and MMIO addresses, which it doesn't emulate. `strict` stops the
+					 guest with a diagnostic of the access, `ignore` (default) logs the
+					 access and returns all-ones for reads, and `debug` traps to the
+					 attached debugger.",
+				)
+				.takes_value(true)
+				.possible_values(&["strict", "ignore", "debug"])
+				.env("HERMIT_UNKNOWN_ACCESS"),
+		)
+		.arg(
+			Arg::with_name("CONSOLE")
+				.long("console")
+				.value_name("CONSOLE")
+				.help("Backend of the guest console (stdio, pty or file:PATH, Linux only)")
+				.long_help(
+					"Connects the console of the guest, i.e. the emulated UART on COM1,
+					 the UART hypercall and reads of fd 0, to stdin and stdout of uhyve
+					 (stdio, default) or to a new pseudo-terminal, whose path is printed
+					 at startup (pty). file:PATH appends the output to a file.",
+				)
+				.takes_value(true)
+				.env("HERMIT_CONSOLE"),
+		)
+		.arg(
+			Arg::with_name("RTC_START")
+				.long("rtc-start")
+				.value_name("TIME")
+				.help("Start time of the emulated RTC (Linux only)")
+				.long_help(
+					"Starts the emulated CMOS RTC, from which the guest reads the wall-clock
+					 time, at TIME instead of the current time of the host. TIME is given in
+					 seconds since the epoch or as YYYY-MM-DDTHH:MM:SS in UTC.",
+				)
+				.takes_value(true)
+				.env("HERMIT_RTC_START"),
+		)
 		.arg(
 			Arg::with_name("CPU_AFFINITY")
 				.short("a")
@@ -106,6 +345,19 @@ fn main() {
 					",
 				),
 		)
+		.arg(
+			Arg::with_name("NUMA_NODE")
+				.long("numa-node")
+				.value_name("nodelist|affinity")
+				.help("Bind the guest memory to the given NUMA nodes")
+				.long_help(
+					"A list of NUMA nodes delimited by commas, to which the guest
+					 memory is bound. With \"affinity\", the guest memory follows
+					 the nodes of the CPUs, which are specified by --affinity.",
+				)
+				.takes_value(true)
+				.env("HERMIT_NUMA_NODE"),
+		)
 		.arg(
 			Arg::with_name("GDB_PORT")
 				.short("s")
@@ -123,7 +375,7 @@ fn main() {
 				.takes_value(true)
 				.env("HERMIT_NETIF"),
 		)
-		/*.arg(
+		.arg(
 			Arg::with_name("IP")
 				.long("ip")
 				.value_name("IP")
@@ -147,7 +399,7 @@ fn main() {
 				.takes_value(true)
 				.env("HERMIT_MASK"),
 		)
-		.arg(
+		/*.arg(
 			Arg::with_name("MAC")
 				.long("mac")
 				.value_name("MAC")
@@ -155,10 +407,45 @@ fn main() {
 				.takes_value(true)
 				.env("HERMIT_MASK"),
 		)*/
+		.arg(
+			Arg::with_name("SNAPSHOT_ON")
+				.long("snapshot-on")
+				.value_name("EVENT")
+				.help("Write a snapshot of the VM, if the event occurs")
+				.long_help(
+					"Writes a snapshot of the complete VM, when uhyve receives SIGUSR1
+					 (signal) or the guest requests it (hypercall). The VM continues
+					 its execution afterwards.",
+				)
+				.takes_value(true)
+				.possible_values(&["signal", "hypercall"])
+				.env("HERMIT_SNAPSHOT_ON"),
+		)
+		.arg(
+			Arg::with_name("SNAPSHOT_PATH")
+				.long("snapshot-path")
+				.value_name("FILE")
+				.help("Path of the snapshot file (default: uhyve.snapshot)")
+				.takes_value(true)
+				.env("HERMIT_SNAPSHOT_PATH"),
+		)
+		.arg(
+			Arg::with_name("RESTORE")
+				.long("restore")
+				.value_name("FILE")
+				.help("Resume the VM from a snapshot file instead of booting a kernel (Linux only)")
+				.long_help(
+					"Resumes the VM from a snapshot file instead of booting a kernel (Linux only).
+					 Every restored VM passes its own arguments (after --), environment
+					 variables and --ip, --gateway and --mask to the guest, which reads
+					 them again after a hypercall snapshot.",
+				)
+				.takes_value(true),
+		)
 		.arg(
 			Arg::with_name("KERNEL")
 				.help("Sets path to the kernel")
-				.required(true)
+				.required_unless("RESTORE")
 				.index(1),
 		)
 		.arg(
@@ -170,12 +457,16 @@ fn main() {
 		)
 		.get_matches();
 
-	let path = PathBuf::from_str(
-		matches
-			.value_of("KERNEL")
-			.expect("Expect path to the kernel!"),
-	)
-	.expect("Invalid kernel path");
+	// only the KVM backend is able to restore snapshots
+	#[cfg(not(target_os = "linux"))]
+	if matches.is_present("RESTORE") {
+		eprintln!("Restoring a snapshot (--restore) is only supported on Linux");
+		std::process::exit(EXIT_SETUP_ERROR);
+	}
+
+	let path = matches
+		.value_of("KERNEL")
+		.map(|kernel| PathBuf::from_str(kernel).expect("Invalid kernel path"));
 	let mem_size: usize = matches
 		.value_of("MEM")
 		.map(|x| {
@@ -221,9 +512,19 @@ fn main() {
 	} else {
 		None
 	};
-	let ip = None; //matches.value_of("IP").or(None);
-	let gateway = None; // matches.value_of("GATEWAY").or(None);
-	let mask = None; //matches.value_of("MASK").or(None);
+	let numa_nodes: Option<Vec<u32>> = matches.value_of("NUMA_NODE").map(|x| {
+		if x == "affinity" {
+			let core_ids = cpu_affinity
+				.as_ref()
+				.expect("NUMA node \"affinity\" requires --affinity");
+			utils::numa_nodes_of_cores(core_ids).expect("Unable to determine the NUMA nodes")
+		} else {
+			parse_numa_nodes(x).expect("Invalid NUMA nodes")
+		}
+	});
+	let ip = matches.value_of("IP");
+	let gateway = matches.value_of("GATEWAY");
+	let mask = matches.value_of("MASK");
 	let nic = None; //matches.value_of("NETIF").or(None);
 
 	let mut mergeable: bool = utils::parse_bool("HERMIT_MERGEABLE", false);
@@ -243,6 +544,47 @@ fn main() {
 	if matches.is_present("VERBOSE") {
 		verbose = true;
 	}
+	let hugetlb = matches
+		.value_of("HUGETLB")
+		.map(|x| utils::parse_mem(x).expect("Invalid huge page size"));
+	let mem_path = matches.value_of("MEM_PATH");
+	let mut memfd: bool = utils::parse_bool("HERMIT_MEMFD", false);
+	if matches.is_present("MEMFD") {
+		memfd = true;
+	}
+	let mut prefault: bool = utils::parse_bool("HERMIT_PREFAULT", false);
+	if matches.is_present("PREFAULT") {
+		prefault = true;
+	}
+	let mut mlock: bool = utils::parse_bool("HERMIT_MLOCK", false);
+	if matches.is_present("MLOCK") {
+		mlock = true;
+	}
+	let mut stats = None;
+	if utils::parse_bool("HERMIT_STATS", false) || matches.is_present("STATS") {
+		stats = Some(Arc::new(VmStats::new()));
+	}
+	let timeout = matches
+		.value_of("TIMEOUT")
+		.map(|x| utils::parse_duration(x).expect("Invalid timeout"));
+	let watchdog = matches
+		.value_of("WATCHDOG")
+		.map(|x| utils::parse_duration(x).expect("Invalid watchdog timeout"));
+	let snapshot_on = matches.value_of("SNAPSHOT_ON").map(|x| {
+		x.parse::<vm::SnapshotTrigger>()
+			.expect("Invalid snapshot event")
+	});
+	let snapshot_path = matches.value_of("SNAPSHOT_PATH");
+	let unknown_access = matches.value_of("UNKNOWN_ACCESS").map(|x| {
+		x.parse::<vm::UnknownAccessPolicy>()
+			.expect("Invalid policy for unknown accesses")
+	});
+	let rtc_start = matches
+		.value_of("RTC_START")
+		.map(|x| utils::parse_time(x).expect("Invalid RTC start time"));
+	let tsc_khz = matches
+		.value_of("TSC_KHZ")
+		.map(|x| x.parse::<u32>().expect("Invalid TSC frequency"));
 	let gdbport = matches
 		.value_of("GDB_PORT")
 		.map(|p| p.parse::<u32>().expect("Could not parse gdb port"))
@@ -263,7 +605,42 @@ fn main() {
 		mask,
 		nic,
 		gdbport,
+		mem_path,
+		memfd,
+		hugetlb,
+		numa_nodes: numa_nodes.as_deref(),
+		prefault,
+		mlock,
+		snapshot_on,
+		snapshot_path,
+		cpu_model: matches.value_of("CPU_MODEL"),
+		stats: stats.as_ref(),
+		timeout,
+		watchdog,
+		unknown_access,
+		console: matches.value_of("CONSOLE"),
+		rtc_start,
+		kvm_pv: matches.is_present("KVM_PV"),
+		tsc_khz,
+		profile: matches.value_of("PROFILE"),
 	};
-	let ret_val = uhyve_run(path, &params, cpu_affinity);
-	std::process::exit(ret_val);
+	#[cfg(target_os = "linux")]
+	{
+		if let Some(snapshot) = matches.value_of("RESTORE") {
+			let outcome = uhyve_restore_with(
+				PathBuf::from(snapshot),
+				&params,
+				cpu_affinity,
+				announce_console,
+			);
+			finish(outcome, stats.as_deref());
+		}
+	}
+
+	let path = path.expect("Expect path to the kernel!");
+	#[cfg(target_os = "linux")]
+	let outcome = uhyve_run_with(path, &params, cpu_affinity, announce_console);
+	#[cfg(not(target_os = "linux"))]
+	let outcome = uhyve_run(path, &params, cpu_affinity);
+	finish(outcome, stats.as_deref());
 }
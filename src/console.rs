@@ -0,0 +1,317 @@
+//! Console of the guest.
+//!
+//! The emulated UART, the UART hypercall and the read hypercall on fd 0 use
+//! the console. Input of the console is read by a separate thread into a
+//! buffer, from which the guest reads. The thread starts with the first use
+//! of the input.
+
+use crate::error::*;
+use log::debug;
+use nix::errno::errno;
+use nix::fcntl::OFlag;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::pty::{grantpt, posix_openpt, ptsname_r, unlockpt, PtyMaster};
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex, Once};
+use std::thread;
+use std::time::Duration;
+
+/// Backend of the console
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConsoleKind {
+	/// stdin and stdout of uhyve
+	Stdio,
+	/// A pseudo-terminal, which uhyve allocates
+	Pty,
+	/// The output is appended to a file. The console has no input.
+	File(PathBuf),
+}
+
+impl FromStr for ConsoleKind {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self> {
+		match s {
+			"stdio" => Ok(ConsoleKind::Stdio),
+			"pty" => Ok(ConsoleKind::Pty),
+			_ if s.starts_with("file:") && s.len() > "file:".len() => {
+				Ok(ConsoleKind::File(PathBuf::from(&s["file:".len()..])))
+			}
+			_ => Err(Error::InvalidArgument(format!("unknown console {}", s))),
+		}
+	}
+}
+
+/// Interval, in which a blocking read checks whether it is interrupted
+const INTERRUPT_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Handler, which is called when the console has received input
+type InputHandler = Box<dyn Fn() + Send>;
+
+#[derive(Debug, Default)]
+struct ConsoleInput {
+	buffer: VecDeque<u8>,
+	/// The input has reached its end or failed.
+	closed: bool,
+}
+
+pub struct Console {
+	output: Mutex<Box<dyn Write + Send>>,
+	input_fd: Option<RawFd>,
+	input: Mutex<ConsoleInput>,
+	input_cond: Condvar,
+	input_started: Once,
+	input_handler: Mutex<Option<InputHandler>>,
+	/// Path of the pty and its slave side, which uhyve keeps open. Otherwise,
+	/// reading the master side fails as long as nobody is connected.
+	pty: Option<(String, File)>,
+}
+
+impl Console {
+	pub fn new(kind: &ConsoleKind) -> Result<Console> {
+		let mut pty = None;
+		let (output, input_fd): (Box<dyn Write + Send>, _) = match kind {
+			ConsoleKind::Stdio => (Box::new(io::stdout()), Some(libc::STDIN_FILENO)),
+			ConsoleKind::Pty => {
+				let (master, path, slave) = open_pty()?;
+				let fd = master.as_raw_fd();
+				pty = Some((path, slave));
+				(Box::new(master), Some(fd))
+			}
+			ConsoleKind::File(path) => {
+				let file: File = OpenOptions::new()
+					.create(true)
+					.append(true)
+					.open(path)
+					.map_err(|_| Error::InvalidFile(path.clone()))?;
+				(Box::new(file), None)
+			}
+		};
+
+		Ok(Console {
+			output: Mutex::new(output),
+			input_fd,
+			input: Mutex::new(ConsoleInput::default()),
+			input_cond: Condvar::new(),
+			input_started: Once::new(),
+			input_handler: Mutex::new(None),
+			pty,
+		})
+	}
+
+	/// Returns the path of the pty, if the console is a pty.
+	pub fn pty_path(&self) -> Option<&str> {
+		self.pty.as_ref().map(|(path, _)| path.as_str())
+	}
+
+	/// Writes `buf` to the console. If nobody reads the pty and its buffer
+	/// is full, the output is dropped instead of blocking the guest.
+	pub fn write(&self, buf: &[u8]) -> io::Result<()> {
+		let mut output = self.output.lock().unwrap();
+		match output.write_all(buf).and_then(|_| output.flush()) {
+			Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+				debug!("Drop console output, because nobody reads the pty");
+				Ok(())
+			}
+			result => result,
+		}
+	}
+
+	/// Sets the handler, which is called whenever the console receives input.
+	pub fn set_input_handler(&self, handler: InputHandler) {
+		*self.input_handler.lock().unwrap() = Some(handler);
+	}
+
+	/// Starts reading the input of the console, if not done yet.
+	pub fn start_input(self: &Arc<Self>) {
+		let fd = match self.input_fd {
+			Some(fd) => fd,
+			None => return,
+		};
+
+		self.input_started.call_once(|| {
+			let console = self.clone();
+			thread::spawn(move || {
+				let mut buf = [0u8; 256];
+				loop {
+					// the pty is non-blocking => wait for the input
+					let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+					if poll(&mut fds, -1).is_err() && errno() != libc::EINTR {
+						break;
+					}
+
+					let len =
+						unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+					if len > 0 {
+						console.push_input(&buf[..len as usize]);
+					} else if len == 0 || ![libc::EINTR, libc::EAGAIN].contains(&errno()) {
+						break;
+					}
+				}
+
+				debug!("Stop reading the console input");
+				console.input.lock().unwrap().closed = true;
+				console.input_cond.notify_all();
+			});
+		});
+	}
+
+	/// Returns the next byte of the input.
+	pub fn read_byte(&self) -> Option<u8> {
+		self.input.lock().unwrap().buffer.pop_front()
+	}
+
+	/// Returns true if input is available.
+	pub fn has_input(&self) -> bool {
+		!self.input.lock().unwrap().buffer.is_empty()
+	}
+
+	/// Reads the available input into `buf`. Blocks until input is available.
+	/// Returns 0 at the end of the input and if the console has no input.
+	/// While blocked, `interrupted` is checked periodically. Returns `None`
+	/// without input, as soon as it returns true.
+	pub fn read<F: Fn() -> bool>(
+		self: &Arc<Self>,
+		buf: &mut [u8],
+		interrupted: F,
+	) -> Option<usize> {
+		if self.input_fd.is_none() || buf.is_empty() {
+			return Some(0);
+		}
+		self.start_input();
+
+		let mut input = self.input.lock().unwrap();
+		while input.buffer.is_empty() && !input.closed {
+			if interrupted() {
+				return None;
+			}
+			input = self
+				.input_cond
+				.wait_timeout(input, INTERRUPT_INTERVAL)
+				.unwrap()
+				.0;
+		}
+		let len = buf.len().min(input.buffer.len());
+		for (dst, src) in buf.iter_mut().zip(input.buffer.drain(..len)) {
+			*dst = src;
+		}
+		Some(len)
+	}
+
+	fn push_input(&self, buf: &[u8]) {
+		self.input.lock().unwrap().buffer.extend(buf);
+		self.input_cond.notify_all();
+		if let Some(handler) = &*self.input_handler.lock().unwrap() {
+			handler();
+		}
+	}
+}
+
+/// Allocates a pty in raw mode. Returns its non-blocking master side, its
+/// path and its opened slave side.
+fn open_pty() -> Result<(PtyMaster, String, File)> {
+	let master = posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_NONBLOCK)
+		.map_err(|_| Error::OsError(errno()))?;
+	grantpt(&master).map_err(|_| Error::OsError(errno()))?;
+	unlockpt(&master).map_err(|_| Error::OsError(errno()))?;
+	let path = ptsname_r(&master).map_err(|_| Error::OsError(errno()))?;
+
+	let slave = OpenOptions::new()
+		.read(true)
+		.write(true)
+		.custom_flags(libc::O_NOCTTY)
+		.open(&path)
+		.map_err(|_| Error::InvalidFile(PathBuf::from(&path)))?;
+	let mut termios = tcgetattr(slave.as_raw_fd()).map_err(|_| Error::OsError(errno()))?;
+	cfmakeraw(&mut termios);
+	tcsetattr(slave.as_raw_fd(), SetArg::TCSANOW, &termios).map_err(|_| Error::OsError(errno()))?;
+
+	Ok((master, path, slave))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::fs;
+	use std::io::Read;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	#[test]
+	fn test_console_kind() {
+		assert_eq!("stdio".parse::<ConsoleKind>().unwrap(), ConsoleKind::Stdio);
+		assert_eq!("pty".parse::<ConsoleKind>().unwrap(), ConsoleKind::Pty);
+		assert_eq!(
+			"file:/tmp/console.log".parse::<ConsoleKind>().unwrap(),
+			ConsoleKind::File(PathBuf::from("/tmp/console.log"))
+		);
+		assert!("file:".parse::<ConsoleKind>().is_err());
+		assert!("tty".parse::<ConsoleKind>().is_err());
+	}
+
+	#[test]
+	fn test_file_console() {
+		let path = std::env::temp_dir().join(format!("uhyve-console-{}.log", std::process::id()));
+		let console = Arc::new(Console::new(&ConsoleKind::File(path.clone())).unwrap());
+		console.write(b"hello ").unwrap();
+		console.write(b"world").unwrap();
+		assert_eq!(fs::read(&path).unwrap(), b"hello world");
+
+		// the file console has no input
+		let mut buf = [0u8; 4];
+		assert_eq!(console.read(&mut buf, || false), Some(0));
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn test_input() {
+		let path = std::env::temp_dir().join(format!("uhyve-input-{}.log", std::process::id()));
+		let console = Console::new(&ConsoleKind::File(path.clone())).unwrap();
+		let calls = Arc::new(AtomicUsize::new(0));
+		let counter = calls.clone();
+		console.set_input_handler(Box::new(move || {
+			counter.fetch_add(1, Ordering::SeqCst);
+		}));
+
+		assert!(!console.has_input());
+		console.push_input(b"ab");
+		assert_eq!(calls.load(Ordering::SeqCst), 1);
+		assert!(console.has_input());
+		assert_eq!(console.read_byte(), Some(b'a'));
+		assert_eq!(console.read_byte(), Some(b'b'));
+		assert_eq!(console.read_byte(), None);
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn test_pty_console() {
+		let console = Arc::new(Console::new(&ConsoleKind::Pty).unwrap());
+		let mut client = OpenOptions::new()
+			.read(true)
+			.write(true)
+			.open(console.pty_path().unwrap())
+			.unwrap();
+
+		console.write(b"out").unwrap();
+		let mut buf = [0u8; 3];
+		client.read_exact(&mut buf).unwrap();
+		assert_eq!(&buf, b"out");
+
+		client.write_all(b"in").unwrap();
+		let mut buf = [0u8; 8];
+		let mut len = 0;
+		while len < 2 {
+			len += console.read(&mut buf[len..], || false).unwrap();
+		}
+		assert_eq!(&buf[..len], b"in");
+
+		// without input, the read returns as soon as it is interrupted
+		assert_eq!(console.read(&mut buf, || true), None);
+	}
+}
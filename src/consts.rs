@@ -10,6 +10,8 @@ pub const BOOT_GDT_MAX: u64 = 3;
 pub const BOOT_PML4: u64 = 0x10000;
 pub const BOOT_PDPTE: u64 = 0x11000;
 pub const BOOT_PDE: u64 = 0x12000;
+/// End of the area for the boot page directories, which starts at `BOOT_PDE`
+pub const BOOT_PDE_END: u64 = SHAREDQUEUE_START as u64;
 pub const BOOT_INFO_ADDR: u64 = 0x9000;
 pub const EFER_SCE: u64 = 1; /* System Call Extensions */
 pub const EFER_LME: u64 = 1 << 8; /* Long mode enable */
@@ -18,11 +20,43 @@ pub const EFER_NXE: u64 = 1 << 11; /* PTE No-Execute bit enable */
 pub const IOAPIC_BASE: u64 = 0xfec00000;
 pub const IOAPIC_SIZE: u64 = 0x1000;
 pub const KERNEL_STACK_SIZE: u64 = 32_768;
+/// isa-debug-exit device of QEMU. A write of `value` exits the guest with
+/// `(value << 1) | 1`.
 pub const SHUTDOWN_PORT: u16 = 0xf4;
+/// pvpanic device, by which the guest reports a panic
+pub const PVPANIC_PORT: u16 = 0x505;
+pub const PVPANIC_PANICKED: u8 = 1 << 0;
+pub const PVPANIC_CRASH_LOADED: u8 = 1 << 1;
 pub const SHAREDQUEUE_START: usize = 0x80000;
 pub const UHYVE_NET_MTU: usize = 1500;
 pub const UHYVE_QUEUE_SIZE: usize = 8;
 pub const UHYVE_IRQ_NET: u32 = 11;
+/// I/O ports of the emulated 16550 UART (COM1)
+pub const COM1_PORT: u16 = 0x3f8;
+pub const COM1_PORT_MAX: u16 = 0x3ff;
+pub const COM1_IRQ: u32 = 4;
+/// Index and data port of the CMOS, which contains the RTC
+pub const CMOS_INDEX_PORT: u16 = 0x70;
+pub const CMOS_DATA_PORT: u16 = 0x71;
+pub const RTC_IRQ: u32 = 8;
+/// CMOS register of the century, which the FADT announces
+pub const CMOS_CENTURY_REG: u8 = 0x32;
+
+/// Guest physical address of the ACPI tables in the BIOS area, where guests
+/// search the RSDP
+pub const ACPI_TABLES_ADDR: u64 = 0xe0000;
+/// End of the BIOS area
+pub const ACPI_TABLES_END: u64 = 0x100000;
+/// Interrupt of the ACPI power management
+pub const ACPI_SCI_IRQ: u16 = 9;
+/// I/O ports of the PM1a event (status and enable) and control registers
+pub const PM1A_EVT_PORT: u16 = 0x600;
+pub const PM1A_EVT_PORT_MAX: u16 = 0x603;
+pub const PM1A_CNT_PORT: u16 = 0x604;
+
+pub const KVM_32BIT_MAX_MEM_SIZE: usize = 1 << 32;
+pub const KVM_32BIT_GAP_SIZE: usize = 768 << 20;
+pub const KVM_32BIT_GAP_START: usize = KVM_32BIT_MAX_MEM_SIZE - KVM_32BIT_GAP_SIZE;
 
 pub const GUEST_PAGE_SIZE: u64 = 0x200000; /* 2 MB pages in guest */
 
@@ -45,3 +79,11 @@ pub const UHYVE_PORT_CMDVAL: u16 = 0x780;
 
 pub const UHYVE_UART_PORT: u16 = 0x800;
 pub const UHYVE_PORT_UNLINK: u16 = 0x840;
+/// The guest requests a snapshot of the VM (see `--snapshot-on hypercall`).
+/// With the address of a flag, the guest waits until the snapshot is written.
+pub const UHYVE_PORT_SNAPSHOT: u16 = 0x880;
+/// The guest starts an application processor
+pub const UHYVE_PORT_CPU_START: u16 = 0x8c0;
+
+/// Default path of the snapshot file
+pub const DEFAULT_SNAPSHOT_PATH: &str = "uhyve.snapshot";
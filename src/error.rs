@@ -10,8 +10,22 @@ pub enum Error {
 	NotEnoughMemory,
 	ParseMemory,
 	InvalidArgument(String),
+	/// The guest has reported a panic (e.g. by pvpanic)
+	GuestPanic,
 	#[cfg(target_os = "linux")]
 	UnknownExitReason,
+	#[cfg(target_os = "linux")]
+	NotEnoughHugePages(usize, u64, u64),
+	#[cfg(target_os = "linux")]
+	MemlockLimit(u64, usize),
+	#[cfg(target_os = "linux")]
+	Snapshot(String),
+	#[cfg(target_os = "linux")]
+	UnsupportedCpuModel(String, String),
+	#[cfg(target_os = "linux")]
+	UnknownAccess(String),
+	#[cfg(target_os = "linux")]
+	UnsupportedTscFrequency(u32),
 	#[cfg(target_os = "macos")]
 	InternalError,
 	#[cfg(target_os = "macos")]
@@ -52,8 +66,43 @@ impl fmt::Display for Error {
 				"Couldn't parse the guest memory size from the environment"
 			),
 			Error::InvalidArgument(ref arg) => write!(f, "Invalid argument passed: {}", arg),
+			Error::GuestPanic => write!(f, "The guest has panicked"),
 			#[cfg(target_os = "linux")]
 			Error::UnknownExitReason => write!(f, "Unknown exit reason."),
+			#[cfg(target_os = "linux")]
+			Error::NotEnoughHugePages(size, required, available) => write!(
+				f,
+				"The guest memory requires {} huge pages of size {} KiB, but only {} are available. \
+				 Please increase /sys/kernel/mm/hugepages/hugepages-{}kB/nr_hugepages.",
+				required,
+				size >> 10,
+				available,
+				size >> 10
+			),
+			#[cfg(target_os = "linux")]
+			Error::MemlockLimit(limit, size) => write!(
+				f,
+				"Unable to lock {} KiB of guest memory, because RLIMIT_MEMLOCK is {} KiB. \
+				 Please increase the limit (e.g. with `ulimit -l`).",
+				size >> 10,
+				limit >> 10
+			),
+			#[cfg(target_os = "linux")]
+			Error::Snapshot(ref msg) => write!(f, "Snapshot failed: {}", msg),
+			#[cfg(target_os = "linux")]
+			Error::UnsupportedCpuModel(ref model, ref missing) => write!(
+				f,
+				"The host doesn't support the CPU model {}. Missing features: {}",
+				model, missing
+			),
+			#[cfg(target_os = "linux")]
+			Error::UnknownAccess(ref access) => write!(f, "Unhandled {}", access),
+			#[cfg(target_os = "linux")]
+			Error::UnsupportedTscFrequency(khz) => write!(
+				f,
+				"The host is unable to scale the TSC to {} kHz (no TSC scaling)",
+				khz
+			),
 			#[cfg(target_os = "macos")]
 			Error::InternalError => write!(f, "An internal error has occurred, please report."),
 			#[cfg(target_os = "macos")]
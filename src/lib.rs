@@ -8,7 +8,9 @@ mod macros;
 #[macro_use]
 extern crate log;
 
+pub mod acpi;
 pub mod arch;
+pub mod console;
 pub mod consts;
 pub mod debug_manager;
 pub mod error;
@@ -20,17 +22,19 @@ pub mod macos;
 pub mod paging;
 #[cfg(target_os = "linux")]
 pub mod shared_queue;
+pub mod stats;
+pub mod symbols;
 pub mod utils;
 pub mod vm;
 
 pub use arch::*;
 use core_affinity::CoreId;
-use std::hint;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::PathBuf;
-use std::sync::mpsc::channel;
 use std::sync::Arc;
-use std::thread;
-use vm::Vm;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use vm::{Vm, VmOutcome};
 
 /// Creates a uhyve vm and runs the binary given by `path` in it.
 /// Blocks until the VM has finished execution.
@@ -38,69 +42,235 @@ pub fn uhyve_run(
 	path: PathBuf,
 	vm_params: &vm::Parameter<'_>,
 	cpu_affinity: Option<Vec<core_affinity::CoreId>>,
-) -> i32 {
+) -> VmOutcome {
+	uhyve_run_with(path, vm_params, cpu_affinity, |_| Ok(()))
+}
+
+/// Runs a uhyve vm like `uhyve_run`, but calls `hook` before the vCPUs are
+/// started.
+pub fn uhyve_run_with<F>(
+	path: PathBuf,
+	vm_params: &vm::Parameter<'_>,
+	cpu_affinity: Option<Vec<core_affinity::CoreId>>,
+	hook: F,
+) -> VmOutcome
+where
+	F: FnOnce(&mut vm::Uhyve) -> error::Result<()>,
+{
 	// create and initialize the VM
-	let vm = Arc::new({
-		let mut vm = vm::create_vm(path, vm_params)
-			.expect("Unable to create VM! Is the hypervisor interface (e.g. KVM) activated?");
-		unsafe {
-			vm.load_kernel().expect("Unabled to load the kernel");
+	let mut vm = match create_and_load_vm(path, vm_params) {
+		Ok(vm) => vm,
+		Err(err) => return VmOutcome::Error(err),
+	};
+	if let Err(err) = hook(&mut vm) {
+		return VmOutcome::Error(err);
+	}
+	let vm = Arc::new(vm);
+
+	#[cfg(target_os = "linux")]
+	let (threads, profiler) = {
+		let mut threads = linux::snapshot::spawn_snapshot_threads(vm.clone());
+		threads.extend(linux::watchdog::spawn_watchdog_thread(vm.clone()));
+		(threads, linux::profiler::spawn_profiler_thread(vm.clone()))
+	};
+	#[cfg(not(target_os = "linux"))]
+	let threads = Vec::new();
+
+	let outcome = run_cpus(vm, cpu_affinity, vm_params.timeout, threads, start_cpu);
+
+	// wait until the profile is written
+	#[cfg(target_os = "linux")]
+	if let Some(profiler) = profiler {
+		let _ = profiler.join();
+	}
+
+	outcome
+}
+
+/// Restores a uhyve vm from the snapshot file `snapshot` and continues its
+/// execution. Blocks until the VM has finished execution.
+#[cfg(target_os = "linux")]
+pub fn uhyve_restore(
+	snapshot: PathBuf,
+	vm_params: &vm::Parameter<'_>,
+	cpu_affinity: Option<Vec<core_affinity::CoreId>>,
+) -> VmOutcome {
+	uhyve_restore_with(snapshot, vm_params, cpu_affinity, |_| Ok(()))
+}
+
+/// Restores a uhyve vm like `uhyve_restore`, but calls `hook` before the
+/// vCPUs are started. Clones of one snapshot use the hook to customize
+/// each instance, e.g. by `Uhyve::set_command`, `Uhyve::set_network_identity`
+/// or `Uhyve::write_guest_mem`.
+#[cfg(target_os = "linux")]
+pub fn uhyve_restore_with<F>(
+	snapshot: PathBuf,
+	vm_params: &vm::Parameter<'_>,
+	cpu_affinity: Option<Vec<core_affinity::CoreId>>,
+	hook: F,
+) -> VmOutcome
+where
+	F: FnOnce(&mut vm::Uhyve) -> error::Result<()>,
+{
+	let (mut vm, states) = match vm::restore_vm(&snapshot, vm_params) {
+		Ok(restored) => restored,
+		Err(err) => return VmOutcome::Error(err),
+	};
+	if let Err(err) = hook(&mut vm) {
+		return VmOutcome::Error(err);
+	}
+	let vm = Arc::new(vm);
+
+	let mut threads = linux::snapshot::spawn_snapshot_threads(vm.clone());
+	threads.extend(linux::watchdog::spawn_watchdog_thread(vm.clone()));
+	let profiler = linux::profiler::spawn_profiler_thread(vm.clone());
+
+	let outcome = run_cpus(
+		vm,
+		cpu_affinity,
+		vm_params.timeout,
+		threads,
+		move |vm, tid| match &states[tid as usize] {
+			Some(state) => Ok(Some(vm.restore_cpu(tid, state)?)),
+			None => start_cpu(vm, tid),
+		},
+	);
+
+	// wait until the profile is written
+	if let Some(profiler) = profiler {
+		let _ = profiler.join();
+	}
+
+	outcome
+}
+
+/// Creates the CPU `tid` of a VM, which runs from the entry point. The
+/// application processors wait until the guest starts them.
+fn start_cpu(vm: &vm::Uhyve, tid: u32) -> error::Result<Option<Box<dyn vm::VirtualCPU>>> {
+	let mut cpu = vm.create_cpu(tid)?;
+	cpu.init(vm.get_entry_point())?;
+
+	if tid != 0 {
+		if !vm.cpu_startup().wait(tid, || tid == vm.cpu_online()) {
+			return Ok(None);
 		}
-		vm
-	});
+		vm.set_current_boot_id(tid);
+	}
 
-	// For communication of the exit code from one vcpu to this thread as return
-	// value.
-	let (exit_tx, exit_rx) = channel();
+	Ok(Some(cpu))
+}
 
-	(0..vm.num_cpus()).for_each(|tid| {
-		let vm = vm.clone();
-		let exit_tx = exit_tx.clone();
-
-		let local_cpu_affinity: Option<CoreId> = match &cpu_affinity {
-			Some(vec) => vec.get(tid as usize).cloned(),
-			None => None,
-		};
-
-		// create thread for each CPU
-		thread::spawn(move || {
-			debug!("Create thread for CPU {}", tid);
-			match local_cpu_affinity {
-				Some(core_id) => {
-					debug!("Trying to pin thread {} to CPU {}", tid, core_id.id);
-					core_affinity::set_for_current(core_id); // This does not return an error if it fails :(
-				}
-				None => debug!("No affinity specified, not binding thread"),
-			}
+/// Creates a thread for each CPU of `vm`, which sets up the CPU by
+/// `create_cpu` and runs it. `create_cpu` returns `None` if the VM stops
+/// before the CPU is started.
+///
+/// The first CPU, which exits, crashes or shuts down the guest, stops all
+/// other CPUs. The VM is also stopped after `timeout`. Returns the outcome
+/// of the VM after all threads, including the helper `threads` of the VM,
+/// are joined.
+fn run_cpus<F>(
+	vm: Arc<vm::Uhyve>,
+	cpu_affinity: Option<Vec<core_affinity::CoreId>>,
+	timeout: Option<Duration>,
+	mut threads: Vec<JoinHandle<()>>,
+	create_cpu: F,
+) -> VmOutcome
+where
+	F: Fn(&vm::Uhyve, u32) -> error::Result<Option<Box<dyn vm::VirtualCPU>>>
+		+ Send
+		+ Sync
+		+ 'static,
+{
+	let create_cpu = Arc::new(create_cpu);
 
-			let mut cpu = vm.create_cpu(tid).unwrap();
-			cpu.init(vm.get_entry_point()).unwrap();
+	let cpu_threads = (0..vm.num_cpus())
+		.map(|tid| {
+			let vm = vm.clone();
+			let create_cpu = create_cpu.clone();
 
-			// only one core is able to enter startup code
-			// => the wait for the predecessor core
-			while tid != vm.cpu_online() {
-				hint::spin_loop();
-			}
+			let local_cpu_affinity: Option<CoreId> = match &cpu_affinity {
+				Some(vec) => vec.get(tid as usize).cloned(),
+				None => None,
+			};
 
-			// jump into the VM and execute code of the guest
-			let result = cpu.run();
-			match result {
-				Err(x) => {
-					error!("CPU {} crashes! {}", tid, x);
+			// create thread for each CPU
+			thread::spawn(move || {
+				debug!("Create thread for CPU {}", tid);
+				match local_cpu_affinity {
+					Some(core_id) => {
+						debug!("Trying to pin thread {} to CPU {}", tid, core_id.id);
+						core_affinity::set_for_current(core_id); // This does not return an error if it fails :(
+					}
+					None => debug!("No affinity specified, not binding thread"),
 				}
-				Ok(exit_code) => {
-					if let Some(code) = exit_code {
-						exit_tx.send(code).unwrap();
+
+				// jump into the VM and execute code of the guest
+				let result =
+					panic::catch_unwind(AssertUnwindSafe(|| match create_cpu(&vm, tid)? {
+						Some(mut cpu) => cpu.run(),
+						None => Ok(None),
+					}));
+				match result {
+					Ok(Ok(Some(exit_code))) => vm.stop(VmOutcome::Exit(exit_code)),
+					Ok(Err(error::Error::GuestPanic)) => vm.stop(VmOutcome::Panic { cpu: tid }),
+					Ok(Ok(None)) => {
+						if !vm.shutdown().is_requested() {
+							error!("CPU {} has stopped without an exit code", tid);
+						}
+						vm.stop(VmOutcome::Crash {
+							cpu: tid,
+							error: None,
+						});
+					}
+					Ok(Err(err)) => {
+						error!("CPU {} crashes! {}", tid, err);
+						vm.stop(VmOutcome::Crash {
+							cpu: tid,
+							error: Some(err),
+						});
+					}
+					Err(payload) => {
+						// don't let the other threads wait for the panicked CPU
+						vm.stop(VmOutcome::Crash {
+							cpu: tid,
+							error: None,
+						});
+						panic::resume_unwind(payload);
 					}
 				}
+			})
+		})
+		.collect::<Vec<_>>();
+
+	if let Some(timeout) = timeout {
+		let vm = vm.clone();
+		threads.push(thread::spawn(move || {
+			if vm.shutdown().wait_timeout(timeout).is_none() {
+				vm.stop(VmOutcome::Timeout(timeout));
 			}
-		});
-	});
-
-	// This is a semi-bad design. We don't wait for the other cpu's threads to
-	// finish, but as soon as one cpu sends an exit code, we return it and
-	// ignore the remaining running threads. A better design would be to force
-	// the VCPUs externally to stop, so that the other threads don't block and
-	// can be terminated correctly.
-	exit_rx.recv().unwrap()
+		}));
+	}
+
+	let outcome = vm.shutdown().wait();
+	for (tid, thread) in cpu_threads.into_iter().enumerate() {
+		if thread.join().is_err() {
+			error!("Thread of CPU {} has panicked", tid);
+		}
+	}
+	for thread in threads {
+		if thread.join().is_err() {
+			error!("Helper thread of the VM has panicked");
+		}
+	}
+
+	outcome
+}
+
+fn create_and_load_vm(path: PathBuf, vm_params: &vm::Parameter<'_>) -> error::Result<vm::Uhyve> {
+	let mut vm = vm::create_vm(path, vm_params)?;
+	unsafe {
+		vm.load_kernel()?;
+	}
+
+	Ok(vm)
 }
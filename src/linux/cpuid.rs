@@ -0,0 +1,828 @@
+//! CPUID, which uhyve exposes to the guest.
+//!
+//! With the CPU model `host`, the guest sees the CPUID supported by KVM. Any
+//! other model is a template, which replaces bits of the host's CPUID, so that
+//! the guest sees the same CPU on every host. Besides the built-in baselines
+//! (see `BASELINES`), templates are read from JSON files:
+//!
+//! ```json
+//! {
+//!     "cpuid": [
+//!         { "leaf": "0x1", "subleaf": 0, "ecx": { "value": "0x00b82201" } },
+//!         { "leaf": "0x7", "subleaf": 0, "ebx": { "value": "0x1", "mask": "0xff" } }
+//!     ]
+//! }
+//! ```
+//!
+//! The bits in `mask` (default: all bits) are set to `value`, the remaining
+//! bits are taken from the host. Finally, uhyve applies its own tweaks (e.g.
+//! the brand string and the hypervisor bit).
+//!
+//! The hypervisor leaves (0x40000000 and 0x40000001) always carry the KVM
+//! signature. The paravirtual features of KVM (e.g. kvmclock, steal time and
+//! PV EOI) are only announced on request.
+
+use crate::error::*;
+use crate::linux::KVM;
+use kvm_bindings::*;
+use log::debug;
+use rustc_serialize::json::Json;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+const CPUID_EXT_HYPERVISOR: u32 = 1 << 31;
+const CPUID_TSC_DEADLINE: u32 = 1 << 24;
+const CPUID_OSXSAVE: u32 = 1 << 27;
+const CPUID_ENABLE_MSR: u32 = 1 << 5;
+const CPUID_PDPE1GB: u32 = 1 << 26;
+
+/// Hypervisor leaves of KVM
+const KVM_CPUID_SIGNATURE: u32 = 0x4000_0000;
+const KVM_CPUID_FEATURES: u32 = 0x4000_0001;
+/// Timing leaf: TSC and APIC bus frequency in kHz
+const HYPERVISOR_TIMING_LEAF: u32 = 0x4000_0010;
+/// Last leaf, which is reserved for hypervisors
+const HYPERVISOR_LEAF_MAX: u32 = 0x4000_00ff;
+/// "KVMKVMKVM\0\0\0" in ebx, ecx and edx
+const KVM_SIGNATURE: [u32; 3] = [0x4b4d_564b, 0x564b_4d56, 0x0000_004d];
+
+const KVM_FEATURE_CLOCKSOURCE: u32 = 1 << 0;
+const KVM_FEATURE_NOP_IO_DELAY: u32 = 1 << 1;
+const KVM_FEATURE_CLOCKSOURCE2: u32 = 1 << 3;
+const KVM_FEATURE_STEAL_TIME: u32 = 1 << 5;
+const KVM_FEATURE_PV_EOI: u32 = 1 << 6;
+const KVM_FEATURE_CLOCKSOURCE_STABLE_BIT: u32 = 1 << 24;
+/// Paravirtual features, which uhyve announces if the host supports them
+const KVM_PV_FEATURES: u32 = KVM_FEATURE_CLOCKSOURCE
+	| KVM_FEATURE_NOP_IO_DELAY
+	| KVM_FEATURE_CLOCKSOURCE2
+	| KVM_FEATURE_STEAL_TIME
+	| KVM_FEATURE_PV_EOI
+	| KVM_FEATURE_CLOCKSOURCE_STABLE_BIT;
+
+/// KVM emulates the local APIC with a bus frequency of 1 GHz.
+const KVM_APIC_BUS_KHZ: u32 = 1_000_000;
+/// Time stamp counter and nominal core crystal clock information leaf
+const TSC_LEAF: u32 = 0x15;
+
+/// Name of the CPU model, which passes the host's CPUID through
+pub const HOST_CPU_MODEL: &str = "host";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Register {
+	Eax,
+	Ebx,
+	Ecx,
+	Edx,
+}
+
+impl Register {
+	const ALL: [Register; 4] = [Register::Eax, Register::Ebx, Register::Ecx, Register::Edx];
+
+	fn name(self) -> &'static str {
+		match self {
+			Register::Eax => "eax",
+			Register::Ebx => "ebx",
+			Register::Ecx => "ecx",
+			Register::Edx => "edx",
+		}
+	}
+
+	fn get(self, entry: &kvm_cpuid_entry2) -> u32 {
+		match self {
+			Register::Eax => entry.eax,
+			Register::Ebx => entry.ebx,
+			Register::Ecx => entry.ecx,
+			Register::Edx => entry.edx,
+		}
+	}
+
+	fn get_mut(self, entry: &mut kvm_cpuid_entry2) -> &mut u32 {
+		match self {
+			Register::Eax => &mut entry.eax,
+			Register::Ebx => &mut entry.ebx,
+			Register::Ecx => &mut entry.ecx,
+			Register::Edx => &mut entry.edx,
+		}
+	}
+}
+
+/// Registers, whose bits announce CPU features. The host has to support each
+/// feature, which a template enables.
+const FEATURE_REGISTERS: [(u32, u32, Register); 9] = [
+	(0x1, 0, Register::Ecx),
+	(0x1, 0, Register::Edx),
+	(0x7, 0, Register::Ebx),
+	(0x7, 0, Register::Ecx),
+	(0x7, 0, Register::Edx),
+	(0xd, 0, Register::Eax),
+	(0xd, 1, Register::Eax),
+	(0x8000_0001, 0, Register::Ecx),
+	(0x8000_0001, 0, Register::Edx),
+];
+
+/// Returns the feature bits of a register, which uhyve provides independently
+/// of the host (by emulation or because KVM updates them at runtime).
+fn emulated_features(leaf: u32, subleaf: u32, register: Register) -> u32 {
+	match (leaf, subleaf, register) {
+		(0x1, 0, Register::Ecx) => CPUID_EXT_HYPERVISOR | CPUID_TSC_DEADLINE | CPUID_OSXSAVE,
+		_ => 0,
+	}
+}
+
+/// Bits of a CPUID register, which a template defines
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RegisterTemplate {
+	pub leaf: u32,
+	pub subleaf: u32,
+	pub register: Register,
+	pub value: u32,
+	pub mask: u32,
+}
+
+impl fmt::Display for RegisterTemplate {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"leaf 0x{:x}, subleaf {}, {}",
+			self.leaf,
+			self.subleaf,
+			self.register.name()
+		)
+	}
+}
+
+/// Leaf 1, ecx and edx, of all baselines: the features, which every x86-64
+/// host provides, and x2APIC, which is emulated by KVM.
+const BASE_LEAF1_ECX: u32 = 0x0020_0000;
+const BASE_LEAF1_EDX: u32 = 0x078b_fbff;
+
+/// Built-in baselines, which follow the x86-64 microarchitecture levels of
+/// the psABI. Each entry defines leaf 1 ecx, leaf 7 ebx, leaf 0x80000001
+/// ecx and the XSAVE state components.
+const BASELINES: [(&str, u32, u32, u32, u32); 4] = [
+	("x86-64", 0, 0, 0, 0x03),
+	("x86-64-v2", 0x0098_2201, 0, 0x01, 0x03),
+	("x86-64-v3", 0x34d8_3201, 0x0000_0128, 0x21, 0x07),
+	("x86-64-v4", 0x34d8_3201, 0xd003_0128, 0x21, 0xe7),
+];
+
+/// Name of the default baseline
+const DEFAULT_BASELINE: &str = "x86-64-v2";
+
+/// Template of the CPUID, which is exposed to the guest
+#[derive(Debug, Clone, Default)]
+pub struct CpuidTemplate {
+	pub registers: Vec<RegisterTemplate>,
+}
+
+impl CpuidTemplate {
+	/// Returns the template of a CPU model, which is either the name of a
+	/// baseline, `baseline` (for the default baseline) or the path of a
+	/// template file. Returns `None` for the model `host`.
+	pub fn from_model(model: &str) -> Result<Option<CpuidTemplate>> {
+		if model == HOST_CPU_MODEL {
+			return Ok(None);
+		}
+
+		let name = if model == "baseline" {
+			DEFAULT_BASELINE
+		} else {
+			model
+		};
+		match CpuidTemplate::baseline(name) {
+			Some(template) => Ok(Some(template)),
+			None => CpuidTemplate::from_file(Path::new(model)).map(Some),
+		}
+	}
+
+	/// Returns the built-in baseline `name`.
+	pub fn baseline(name: &str) -> Option<CpuidTemplate> {
+		let (_, leaf1_ecx, leaf7_ebx, ext_ecx, xsave) =
+			*BASELINES.iter().find(|baseline| baseline.0 == name)?;
+		let exact = |leaf, subleaf, register, value| RegisterTemplate {
+			leaf,
+			subleaf,
+			register,
+			value,
+			mask: !0,
+		};
+
+		Some(CpuidTemplate {
+			registers: vec![
+				exact(0x1, 0, Register::Ecx, BASE_LEAF1_ECX | leaf1_ecx),
+				exact(0x1, 0, Register::Edx, BASE_LEAF1_EDX),
+				exact(0x7, 0, Register::Ebx, leaf7_ebx),
+				exact(0x7, 0, Register::Ecx, 0),
+				exact(0x7, 0, Register::Edx, 0),
+				RegisterTemplate {
+					mask: 0xff,
+					..exact(0xd, 0, Register::Eax, xsave)
+				},
+				RegisterTemplate {
+					mask: 0xf,
+					..exact(0xd, 1, Register::Eax, 0)
+				},
+				exact(0x8000_0001, 0, Register::Ecx, ext_ecx),
+				// syscall, NX and long mode, 1 GiB pages are taken from the
+				// host and decide the page size of the boot page tables
+				RegisterTemplate {
+					mask: !CPUID_PDPE1GB,
+					..exact(0x8000_0001, 0, Register::Edx, 0x2010_0800)
+				},
+			],
+		})
+	}
+
+	/// Reads a template from a JSON file.
+	pub fn from_file(path: &Path) -> Result<CpuidTemplate> {
+		let json = fs::read_to_string(path).map_err(|_| Error::InvalidFile(path.to_path_buf()))?;
+		CpuidTemplate::from_json(&json).map_err(|err| {
+			Error::InvalidArgument(format!("CPUID template {}: {}", path.display(), err))
+		})
+	}
+
+	pub fn from_json(json: &str) -> std::result::Result<CpuidTemplate, String> {
+		let json = Json::from_str(json).map_err(|err| err.to_string())?;
+		let leaves = json
+			.find("cpuid")
+			.and_then(|leaves| leaves.as_array())
+			.ok_or("expected an array \"cpuid\"")?;
+
+		let mut registers = Vec::new();
+		for entry in leaves {
+			let leaf = parse_u32(entry.find("leaf").ok_or("missing \"leaf\"")?)?;
+			let subleaf = match entry.find("subleaf") {
+				Some(subleaf) => parse_u32(subleaf)?,
+				None => 0,
+			};
+
+			for register in Register::ALL.iter() {
+				if let Some(bits) = entry.find(register.name()) {
+					let value = parse_u32(bits.find("value").ok_or("missing \"value\"")?)?;
+					let mask = match bits.find("mask") {
+						Some(mask) => parse_u32(mask)?,
+						None => !0,
+					};
+					registers.push(RegisterTemplate {
+						leaf,
+						subleaf,
+						register: *register,
+						value,
+						mask,
+					});
+				}
+			}
+		}
+
+		Ok(CpuidTemplate { registers })
+	}
+
+	/// Returns the feature bits, which the template enables, but `host`
+	/// doesn't support.
+	pub fn missing_features(&self, host: &CpuId) -> Vec<(RegisterTemplate, u32)> {
+		self.registers
+			.iter()
+			.filter(|template| {
+				FEATURE_REGISTERS.contains(&(template.leaf, template.subleaf, template.register))
+			})
+			.filter_map(|template| {
+				let supported = find_entry(host.as_slice(), template.leaf, template.subleaf)
+					.map_or(0, |entry| template.register.get(entry))
+					| emulated_features(template.leaf, template.subleaf, template.register);
+				let missing = template.value & template.mask & !supported;
+				if missing != 0 {
+					Some((*template, missing))
+				} else {
+					None
+				}
+			})
+			.collect()
+	}
+
+	/// Replaces the bits of `cpuid`, which are defined by the template. The
+	/// maximal leaves are raised to cover the leaves of the template.
+	pub fn apply(&self, cpuid: &mut CpuId) -> Result<()> {
+		for template in self.registers.iter() {
+			if find_entry(cpuid.as_slice(), template.leaf, template.subleaf).is_none() {
+				let flags = if template.subleaf != 0 {
+					KVM_CPUID_FLAG_SIGNIFCANT_INDEX
+				} else {
+					0
+				};
+				cpuid
+					.push(kvm_cpuid_entry2 {
+						function: template.leaf,
+						index: template.subleaf,
+						flags,
+						..Default::default()
+					})
+					.map_err(|_| Error::InvalidArgument("too many CPUID leaves".to_string()))?;
+			}
+
+			let entry = cpuid
+				.as_mut_slice()
+				.iter_mut()
+				.find(|entry| entry.function == template.leaf && entry.index == template.subleaf)
+				.unwrap();
+			let register = template.register.get_mut(entry);
+			*register = (*register & !template.mask) | (template.value & template.mask);
+		}
+
+		for template in self.registers.iter() {
+			raise_max_leaf(cpuid, template.leaf);
+		}
+
+		Ok(())
+	}
+}
+
+fn parse_u32(json: &Json) -> std::result::Result<u32, String> {
+	let value = match json {
+		Json::U64(value) => Some(*value),
+		Json::I64(value) if *value >= 0 => Some(*value as u64),
+		Json::String(value) => match value.strip_prefix("0x") {
+			Some(hex) => u64::from_str_radix(hex, 16).ok(),
+			None => value.parse().ok(),
+		},
+		_ => None,
+	};
+
+	value
+		.filter(|value| *value <= u64::from(u32::MAX))
+		.map(|value| value as u32)
+		.ok_or_else(|| format!("invalid 32-bit value {}", json))
+}
+
+fn find_entry(entries: &[kvm_cpuid_entry2], leaf: u32, subleaf: u32) -> Option<&kvm_cpuid_entry2> {
+	entries
+		.iter()
+		.find(|entry| entry.function == leaf && entry.index == subleaf)
+}
+
+/// Returns true if `cpuid` announces 1 GiB pages.
+pub fn has_1gib_pages(cpuid: &CpuId) -> bool {
+	find_entry(cpuid.as_slice(), 0x8000_0001, 0).map(|entry| entry.edx & CPUID_PDPE1GB != 0)
+		== Some(true)
+}
+
+/// Returns the CPUID of the guest for the CPU model `model`. If `kvm_pv` is
+/// set, the guest sees the paravirtual features of KVM. If `tsc_khz` is
+/// known, the guest sees the TSC frequency in the leaves 0x15 and 0x40000010.
+/// Fails if the host doesn't support all features of the model.
+pub fn guest_cpuid(model: &str, kvm_pv: bool, tsc_khz: Option<u32>) -> Result<CpuId> {
+	let mut cpuid = KVM
+		.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)
+		.or_else(to_error)?;
+
+	if let Some(template) = CpuidTemplate::from_model(model)? {
+		let missing = template.missing_features(&cpuid);
+		if !missing.is_empty() {
+			let missing = missing
+				.iter()
+				.map(|(register, bits)| format!("{} (0x{:08x})", register, bits))
+				.collect::<Vec<_>>()
+				.join(", ");
+			return Err(Error::UnsupportedCpuModel(model.to_string(), missing));
+		}
+
+		debug!("Apply CPU model {}", model);
+		template.apply(&mut cpuid)?;
+	}
+
+	set_uhyve_leaves(cpuid.as_mut_slice());
+	set_kvm_leaves(&mut cpuid, kvm_pv, tsc_khz)?;
+	if let Some(tsc_khz) = tsc_khz {
+		set_tsc_leaf(&mut cpuid, tsc_khz)?;
+	}
+
+	Ok(cpuid)
+}
+
+/// Replaces the entry of `cpuid` with the leaf and subleaf of `entry` or
+/// adds `entry`.
+fn set_entry(cpuid: &mut CpuId, entry: kvm_cpuid_entry2) -> Result<()> {
+	match cpuid
+		.as_mut_slice()
+		.iter_mut()
+		.find(|old| old.function == entry.function && old.index == entry.index)
+	{
+		Some(old) => *old = entry,
+		None => cpuid
+			.push(entry)
+			.map_err(|_| Error::InvalidArgument("too many CPUID leaves".to_string()))?,
+	}
+
+	Ok(())
+}
+
+/// Replaces the hypervisor leaves by the KVM signature and the features
+/// leaf. The features leaf contains the paravirtual features of the host,
+/// which uhyve supports, if `kvm_pv` is set. Otherwise, it is empty. The
+/// timing leaf 0x40000010 contains the TSC frequency, if it is known.
+fn set_kvm_leaves(cpuid: &mut CpuId, kvm_pv: bool, tsc_khz: Option<u32>) -> Result<()> {
+	let features = match find_entry(cpuid.as_slice(), KVM_CPUID_FEATURES, 0) {
+		Some(entry) if kvm_pv => entry.eax & KVM_PV_FEATURES,
+		_ => 0,
+	};
+	cpuid.retain(|entry| !(KVM_CPUID_SIGNATURE..=HYPERVISOR_LEAF_MAX).contains(&entry.function));
+
+	set_entry(
+		cpuid,
+		kvm_cpuid_entry2 {
+			function: KVM_CPUID_SIGNATURE,
+			eax: if tsc_khz.is_some() {
+				HYPERVISOR_TIMING_LEAF
+			} else {
+				KVM_CPUID_FEATURES
+			},
+			ebx: KVM_SIGNATURE[0],
+			ecx: KVM_SIGNATURE[1],
+			edx: KVM_SIGNATURE[2],
+			..Default::default()
+		},
+	)?;
+	set_entry(
+		cpuid,
+		kvm_cpuid_entry2 {
+			function: KVM_CPUID_FEATURES,
+			eax: features,
+			..Default::default()
+		},
+	)?;
+	if let Some(tsc_khz) = tsc_khz {
+		set_entry(
+			cpuid,
+			kvm_cpuid_entry2 {
+				function: HYPERVISOR_TIMING_LEAF,
+				eax: tsc_khz,
+				ebx: KVM_APIC_BUS_KHZ,
+				..Default::default()
+			},
+		)?;
+	}
+
+	Ok(())
+}
+
+/// Announces the TSC frequency by the TSC leaf 0x15, whose nominal crystal
+/// frequency (ecx) times the ratio ebx / eax is the TSC frequency.
+fn set_tsc_leaf(cpuid: &mut CpuId, tsc_khz: u32) -> Result<()> {
+	// a divisor of 1000 keeps the crystal frequency in Hz exact and within
+	// 32 bits up to 42.9 GHz
+	let hz = u64::from(tsc_khz) * 1000;
+	let ratio = [1u32, 2, 4, 5, 8, 10]
+		.iter()
+		.copied()
+		.find(|ratio| hz / u64::from(*ratio) <= u64::from(u32::MAX))
+		.unwrap_or(10);
+
+	set_entry(
+		cpuid,
+		kvm_cpuid_entry2 {
+			function: TSC_LEAF,
+			eax: 1,
+			ebx: ratio,
+			ecx: (hz / u64::from(ratio)) as u32,
+			..Default::default()
+		},
+	)?;
+
+	// the guest has to see the leaf
+	raise_max_leaf(cpuid, TSC_LEAF);
+
+	Ok(())
+}
+
+/// Raises the maximal leaf in leaf 0 or 0x80000000, so that the guest sees
+/// the basic or extended leaf `leaf`.
+fn raise_max_leaf(cpuid: &mut CpuId, leaf: u32) {
+	let max_leaf = match leaf {
+		0..=0x3fff_ffff => 0,
+		0x8000_0000..=0x8fff_ffff => 0x8000_0000,
+		_ => return,
+	};
+	if let Some(entry) = cpuid
+		.as_mut_slice()
+		.iter_mut()
+		.find(|entry| entry.function == max_leaf)
+	{
+		entry.eax = entry.eax.max(leaf);
+	}
+}
+
+/// Applies the modifications of uhyve, which every CPU model contains.
+fn set_uhyve_leaves(kvm_cpuid_entries: &mut [kvm_cpuid_entry2]) {
+	let i = kvm_cpuid_entries
+		.iter()
+		.position(|&r| r.function == 0x80000002)
+		.unwrap();
+
+	// create own processor string (first part)
+	let mut id_reg_values: [u32; 4] = [0; 4];
+	let id = b"uhyve - unikerne";
+	unsafe {
+		std::ptr::copy_nonoverlapping(id.as_ptr(), id_reg_values.as_mut_ptr() as *mut u8, id.len());
+	}
+	kvm_cpuid_entries[i].eax = id_reg_values[0];
+	kvm_cpuid_entries[i].ebx = id_reg_values[1];
+	kvm_cpuid_entries[i].ecx = id_reg_values[2];
+	kvm_cpuid_entries[i].edx = id_reg_values[3];
+
+	let i = kvm_cpuid_entries
+		.iter()
+		.position(|&r| r.function == 0x80000003)
+		.unwrap();
+
+	// create own processor string (second part)
+	let id = b"l hypervisor\0";
+	unsafe {
+		std::ptr::copy_nonoverlapping(id.as_ptr(), id_reg_values.as_mut_ptr() as *mut u8, id.len());
+	}
+	kvm_cpuid_entries[i].eax = id_reg_values[0];
+	kvm_cpuid_entries[i].ebx = id_reg_values[1];
+	kvm_cpuid_entries[i].ecx = id_reg_values[2];
+	kvm_cpuid_entries[i].edx = id_reg_values[3];
+
+	let i = kvm_cpuid_entries
+		.iter()
+		.position(|&r| r.function == 0x80000004)
+		.unwrap();
+
+	// create own processor string (third part)
+	kvm_cpuid_entries[i].eax = 0;
+	kvm_cpuid_entries[i].ebx = 0;
+	kvm_cpuid_entries[i].ecx = 0;
+	kvm_cpuid_entries[i].edx = 0;
+
+	let i = kvm_cpuid_entries
+		.iter()
+		.position(|&r| r.function == 1)
+		.unwrap();
+
+	// CPUID to define basic cpu features
+	kvm_cpuid_entries[i].ecx |= CPUID_EXT_HYPERVISOR; // propagate that we are running on a hypervisor
+	kvm_cpuid_entries[i].ecx |= CPUID_TSC_DEADLINE; // enable TSC deadline feature
+	kvm_cpuid_entries[i].edx |= CPUID_ENABLE_MSR; // enable msr support
+
+	let i = kvm_cpuid_entries
+		.iter()
+		.position(|&r| r.function == 0x0A)
+		.unwrap();
+
+	// disable performance monitor
+	kvm_cpuid_entries[i].eax = 0x00;
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn host(entries: &[kvm_cpuid_entry2]) -> CpuId {
+		CpuId::from_entries(entries).unwrap()
+	}
+
+	#[test]
+	fn test_baselines() {
+		for (name, ..) in BASELINES.iter() {
+			let template = CpuidTemplate::baseline(name).unwrap();
+			assert_eq!(template.registers.len(), FEATURE_REGISTERS.len());
+		}
+		assert!(CpuidTemplate::baseline("x86-64-v5").is_none());
+		assert!(CpuidTemplate::from_model(HOST_CPU_MODEL).unwrap().is_none());
+		assert!(CpuidTemplate::from_model("baseline").unwrap().is_some());
+		assert!(CpuidTemplate::from_model("/does/not/exist.json").is_err());
+	}
+
+	#[test]
+	fn test_template_from_json() {
+		let template = CpuidTemplate::from_json(
+			r#"{ "cpuid": [
+				{ "leaf": "0x1", "ecx": { "value": "0x201" }, "edx": { "value": 3, "mask": "0xf" } },
+				{ "leaf": 13, "subleaf": 1, "eax": { "value": "0" } }
+			] }"#,
+		)
+		.unwrap();
+		assert_eq!(
+			template.registers,
+			vec![
+				RegisterTemplate {
+					leaf: 1,
+					subleaf: 0,
+					register: Register::Ecx,
+					value: 0x201,
+					mask: !0
+				},
+				RegisterTemplate {
+					leaf: 1,
+					subleaf: 0,
+					register: Register::Edx,
+					value: 3,
+					mask: 0xf
+				},
+				RegisterTemplate {
+					leaf: 13,
+					subleaf: 1,
+					register: Register::Eax,
+					value: 0,
+					mask: !0
+				},
+			]
+		);
+
+		assert!(CpuidTemplate::from_json("{}").is_err());
+		assert!(CpuidTemplate::from_json(r#"{ "cpuid": [ { "ecx": { "value": 1 } } ] }"#).is_err());
+		assert!(CpuidTemplate::from_json(
+			r#"{ "cpuid": [ { "leaf": 1, "ecx": { "value": "0x100000000" } } ] }"#
+		)
+		.is_err());
+	}
+
+	#[test]
+	fn test_apply_template() {
+		let mut cpuid = host(&[
+			kvm_cpuid_entry2 {
+				function: 0,
+				eax: 1,
+				..Default::default()
+			},
+			kvm_cpuid_entry2 {
+				function: 1,
+				ecx: 0xffff_0000,
+				edx: 0x1234,
+				..Default::default()
+			},
+			kvm_cpuid_entry2 {
+				function: 0x8000_0000,
+				eax: 0x8000_0001,
+				..Default::default()
+			},
+		]);
+		let template = CpuidTemplate {
+			registers: vec![
+				RegisterTemplate {
+					leaf: 1,
+					subleaf: 0,
+					register: Register::Ecx,
+					value: 0x00ff_00ff,
+					mask: 0x0fff_0000,
+				},
+				RegisterTemplate {
+					leaf: 7,
+					subleaf: 0,
+					register: Register::Ebx,
+					value: 1,
+					mask: !0,
+				},
+				RegisterTemplate {
+					leaf: 0x8000_0008,
+					subleaf: 0,
+					register: Register::Eax,
+					value: 0x3030,
+					mask: !0,
+				},
+			],
+		};
+		template.apply(&mut cpuid).unwrap();
+
+		let entries = cpuid.as_slice();
+		assert_eq!(entries.len(), 5);
+		assert_eq!(entries[0].eax, 7);
+		assert_eq!(entries[1].ecx, 0xf0ff_0000);
+		assert_eq!(entries[1].edx, 0x1234);
+		assert_eq!(entries[2].eax, 0x8000_0008);
+		assert_eq!(entries[3].function, 7);
+		assert_eq!(entries[3].ebx, 1);
+	}
+
+	#[test]
+	fn test_missing_features() {
+		let cpuid = host(&[
+			kvm_cpuid_entry2 {
+				function: 1,
+				ecx: 0x1,
+				..Default::default()
+			},
+			kvm_cpuid_entry2 {
+				function: 0x8000_0002,
+				..Default::default()
+			},
+		]);
+		let template = CpuidTemplate {
+			registers: vec![
+				RegisterTemplate {
+					leaf: 1,
+					subleaf: 0,
+					register: Register::Ecx,
+					value: 0x3 | CPUID_EXT_HYPERVISOR,
+					mask: !0,
+				},
+				// no feature register => not checked
+				RegisterTemplate {
+					leaf: 0x8000_0002,
+					subleaf: 0,
+					register: Register::Eax,
+					value: 0x1234,
+					mask: !0,
+				},
+			],
+		};
+
+		let missing = template.missing_features(&cpuid);
+		assert_eq!(missing.len(), 1);
+		assert_eq!(missing[0].0.register, Register::Ecx);
+		assert_eq!(missing[0].1, 0x2);
+	}
+
+	#[test]
+	fn test_guest_cpuid() {
+		if !crate::linux::tests::has_vm_support() {
+			return;
+		}
+
+		// every x86-64 host satisfies the first level
+		let cpuid = guest_cpuid("x86-64", false, None).unwrap();
+		let leaf1 = find_entry(cpuid.as_slice(), 1, 0).unwrap();
+		assert_eq!(leaf1.edx, BASE_LEAF1_EDX | CPUID_ENABLE_MSR);
+		assert_ne!(leaf1.ecx & CPUID_EXT_HYPERVISOR, 0);
+
+		guest_cpuid(HOST_CPU_MODEL, true, Some(2_000_000)).unwrap();
+	}
+
+	#[test]
+	fn test_kvm_leaves() {
+		let entries = [
+			kvm_cpuid_entry2 {
+				function: KVM_CPUID_SIGNATURE,
+				eax: KVM_CPUID_FEATURES,
+				..Default::default()
+			},
+			kvm_cpuid_entry2 {
+				function: KVM_CPUID_FEATURES,
+				eax: KVM_PV_FEATURES | (1 << 4),
+				edx: 1,
+				..Default::default()
+			},
+			kvm_cpuid_entry2 {
+				function: 0x4000_0010,
+				eax: 1,
+				..Default::default()
+			},
+		];
+
+		let mut cpuid = host(&entries);
+		set_kvm_leaves(&mut cpuid, true, None).unwrap();
+		assert_eq!(cpuid.as_slice().len(), 2);
+		let signature = find_entry(cpuid.as_slice(), KVM_CPUID_SIGNATURE, 0).unwrap();
+		assert_eq!(signature.eax, KVM_CPUID_FEATURES);
+		let mut name = Vec::new();
+		for register in [signature.ebx, signature.ecx, signature.edx].iter() {
+			name.extend_from_slice(&register.to_le_bytes());
+		}
+		assert_eq!(&name, b"KVMKVMKVM\0\0\0");
+		let features = find_entry(cpuid.as_slice(), KVM_CPUID_FEATURES, 0).unwrap();
+		assert_eq!(features.eax, KVM_PV_FEATURES);
+		assert_eq!(features.edx, 0);
+
+		let mut cpuid = host(&entries);
+		set_kvm_leaves(&mut cpuid, false, Some(2_100_000)).unwrap();
+		let features = find_entry(cpuid.as_slice(), KVM_CPUID_FEATURES, 0).unwrap();
+		assert_eq!(features.eax, 0);
+		let signature = find_entry(cpuid.as_slice(), KVM_CPUID_SIGNATURE, 0).unwrap();
+		assert_eq!(signature.eax, HYPERVISOR_TIMING_LEAF);
+		let timing = find_entry(cpuid.as_slice(), HYPERVISOR_TIMING_LEAF, 0).unwrap();
+		assert_eq!(timing.eax, 2_100_000);
+		assert_eq!(timing.ebx, KVM_APIC_BUS_KHZ);
+	}
+
+	#[test]
+	fn test_tsc_leaf() {
+		for tsc_khz in [2_100_000u32, 2_399_999, 4_800_000].iter() {
+			let mut cpuid = host(&[kvm_cpuid_entry2 {
+				function: 0,
+				eax: 0xd,
+				..Default::default()
+			}]);
+			set_tsc_leaf(&mut cpuid, *tsc_khz).unwrap();
+			assert_eq!(find_entry(cpuid.as_slice(), 0, 0).unwrap().eax, TSC_LEAF);
+			let leaf = find_entry(cpuid.as_slice(), TSC_LEAF, 0).unwrap();
+			assert_eq!(
+				u64::from(leaf.ecx) * u64::from(leaf.ebx) / u64::from(leaf.eax),
+				u64::from(*tsc_khz) * 1000
+			);
+		}
+	}
+
+	#[test]
+	fn test_1gib_pages() {
+		let leaf = |edx| kvm_cpuid_entry2 {
+			function: 0x8000_0001,
+			edx,
+			..Default::default()
+		};
+		assert!(has_1gib_pages(&host(&[leaf(CPUID_PDPE1GB)])));
+		assert!(!has_1gib_pages(&host(&[leaf(0)])));
+		assert!(!has_1gib_pages(&host(&[])));
+	}
+}
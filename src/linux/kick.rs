@@ -0,0 +1,178 @@
+//! Kicks vCPU threads out of `KVM_RUN`.
+//!
+//! A kick is a signal to the vCPU thread. Its handler sets `immediate_exit`
+//! of the vCPU, which runs on this thread. Therefore, `KVM_RUN` returns with
+//! `EINTR` even if the signal arrives shortly before the vCPU enters the guest.
+//! The handler is installed with `SA_RESTART`, so that a kick doesn't
+//! interrupt the system calls, which uhyve executes on behalf of the guest.
+//!
+//! The watchdog and the profiler sample the vCPUs by periodic kicks
+//! (see `sample_periodically`).
+
+use crate::vm::Vm;
+use kvm_ioctls::VcpuFd;
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use std::cell::Cell;
+use std::ptr;
+use std::sync::{Mutex, Once};
+use std::time::Duration;
+
+/// Signal, which kicks a vCPU thread out of `KVM_RUN`
+pub const KICK_SIGNAL: Signal = Signal::SIGUSR2;
+
+thread_local! {
+	/// vCPU, which runs on the current thread
+	static RUNNING_VCPU: Cell<*const VcpuFd> = Cell::new(ptr::null());
+}
+
+extern "C" fn handle_kick(_signal: libc::c_int) {
+	RUNNING_VCPU.with(|vcpu| {
+		let vcpu = vcpu.get();
+		if !vcpu.is_null() {
+			unsafe { (*vcpu).set_kvm_immediate_exit(1) };
+		}
+	});
+}
+
+/// Installs the handler of the kick signal. `KVM_RUN` returns `EINTR` in
+/// spite of SA_RESTART, other system calls are restarted.
+pub fn install_handler() {
+	static INSTALL: Once = Once::new();
+
+	INSTALL.call_once(|| {
+		let action = SigAction::new(
+			SigHandler::Handler(handle_kick),
+			SaFlags::SA_RESTART,
+			SigSet::empty(),
+		);
+		unsafe { sigaction(KICK_SIGNAL, &action) }.expect("Unable to install signal handler");
+	});
+}
+
+/// Sends the kick signal to `thread`.
+pub fn kick(thread: libc::pthread_t) {
+	unsafe { libc::pthread_kill(thread, KICK_SIGNAL as libc::c_int) };
+}
+
+/// Threads of the running vCPUs
+pub struct VcpuThreads {
+	threads: Mutex<Vec<Option<libc::pthread_t>>>,
+}
+
+impl VcpuThreads {
+	pub fn new(num_cpus: u32) -> Self {
+		install_handler();
+
+		VcpuThreads {
+			threads: Mutex::new(vec![None; num_cpus as usize]),
+		}
+	}
+
+	/// Registers the calling thread as the thread of vCPU `id`. `vcpu` must
+	/// neither move nor be dropped until `unregister` is called.
+	pub fn register(&self, id: u32, vcpu: &VcpuFd) {
+		RUNNING_VCPU.with(|running| running.set(vcpu));
+		self.threads.lock().unwrap()[id as usize] = Some(unsafe { libc::pthread_self() });
+	}
+
+	/// Removes vCPU `id`, whose thread doesn't run the guest anymore.
+	pub fn unregister(&self, id: u32) {
+		self.threads.lock().unwrap()[id as usize] = None;
+		RUNNING_VCPU.with(|running| running.set(ptr::null()));
+	}
+
+	/// Kicks vCPU `id` out of the guest. Returns false if the vCPU isn't
+	/// registered.
+	pub fn kick(&self, id: u32) -> bool {
+		match self.threads.lock().unwrap()[id as usize] {
+			Some(thread) => {
+				kick(thread);
+				true
+			}
+			None => false,
+		}
+	}
+
+	/// Kicks all registered vCPUs out of the guest.
+	pub fn kick_all(&self) {
+		for thread in self.threads.lock().unwrap().iter().flatten() {
+			kick(*thread);
+		}
+	}
+}
+
+/// Requests samples of the vCPUs of `vm` by `request` and kicks them every
+/// `period`, until the VM shuts down or `sampled` returns `false`. `sampled`
+/// is called after each period, in which the VM hasn't shut down.
+pub fn sample_periodically<V, R, F>(vm: &V, period: Duration, request: R, mut sampled: F)
+where
+	V: Vm + ?Sized,
+	R: Fn(),
+	F: FnMut() -> bool,
+{
+	loop {
+		request();
+		vm.kick_cpus();
+		if vm.shutdown().wait_timeout(period).is_some() || !sampled() {
+			return;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::linux::memory::MmapMemory;
+	use crate::linux::{MemoryRegion, KVM};
+	use kvm_bindings::kvm_userspace_memory_region;
+	use std::sync::Arc;
+	use std::thread;
+	use std::time::Duration;
+
+	#[test]
+	fn test_kick_running_vcpu() {
+		if !crate::linux::tests::has_vm_support() {
+			return;
+		}
+
+		// the guest spins on `jmp $` in real mode
+		let mem = MmapMemory::new(0, 0x1000, 0, false, false);
+		unsafe {
+			ptr::copy_nonoverlapping([0xeb, 0xfe].as_ptr(), mem.host_address() as *mut u8, 2)
+		};
+		let vm = KVM.create_vm().unwrap();
+		let region = kvm_userspace_memory_region {
+			slot: 0,
+			flags: 0,
+			guest_phys_addr: 0,
+			memory_size: mem.memory_size() as u64,
+			userspace_addr: mem.host_address() as u64,
+		};
+		unsafe { vm.set_user_memory_region(region) }.unwrap();
+
+		let vcpu = vm.create_vcpu(0).unwrap();
+		let mut sregs = vcpu.get_sregs().unwrap();
+		sregs.cs.base = 0;
+		sregs.cs.selector = 0;
+		vcpu.set_sregs(&sregs).unwrap();
+		let mut regs = vcpu.get_regs().unwrap();
+		regs.rip = 0;
+		regs.rflags = 2;
+		vcpu.set_regs(&regs).unwrap();
+
+		let threads = Arc::new(VcpuThreads::new(1));
+		threads.register(0, &vcpu);
+		let kicker = {
+			let threads = threads.clone();
+			thread::spawn(move || {
+				thread::sleep(Duration::from_millis(20));
+				threads.kick_all();
+			})
+		};
+
+		let err = vcpu.run().unwrap_err();
+		threads.unregister(0);
+		kicker.join().unwrap();
+		assert_eq!(err.errno(), libc::EINTR);
+	}
+}
@@ -0,0 +1,193 @@
+//! Runs a vCPU by `KVM_RUN`.
+//!
+//! kvm-ioctls doesn't know the MSR exits of `KVM_CAP_X86_USER_SPACE_MSR` and
+//! panics on them. Therefore, uhyve maps `kvm_run` of the vCPU itself and
+//! decodes the exits. All other exits are returned as `VcpuExit`.
+
+use crate::error::*;
+use crate::linux::KVM;
+use kvm_bindings::*;
+use kvm_ioctls::{VcpuExit, VcpuFd};
+use log::debug;
+use std::os::unix::io::AsRawFd;
+use std::{ptr, slice};
+
+/// Exit reasons of `KVM_CAP_X86_USER_SPACE_MSR`
+const KVM_EXIT_X86_RDMSR: u32 = 29;
+const KVM_EXIT_X86_WRMSR: u32 = 30;
+
+/// MSR access of the guest, which KVM forwards to uhyve
+#[repr(C)]
+#[derive(Debug)]
+pub struct MsrExit {
+	/// Set to 1 to inject a #GP into the guest
+	pub error: u8,
+	pad: [u8; 7],
+	/// `KVM_MSR_EXIT_REASON_*`
+	pub reason: u32,
+	pub index: u32,
+	pub data: u64,
+}
+
+/// Exit of the guest
+pub enum KvmExit<'a> {
+	Vcpu(VcpuExit<'a>),
+	/// The guest reads an MSR. uhyve sets `data` or `error`.
+	RdMsr(&'a mut MsrExit),
+	/// The guest writes `data` into an MSR. uhyve may set `error`.
+	WrMsr(&'a mut MsrExit),
+}
+
+impl KvmExit<'_> {
+	/// Returns the name of the exit reason.
+	pub fn name(&self) -> &'static str {
+		match self {
+			KvmExit::RdMsr(_) => "RdMsr",
+			KvmExit::WrMsr(_) => "WrMsr",
+			KvmExit::Vcpu(exit) => match exit {
+				VcpuExit::IoOut(..) => "IoOut",
+				VcpuExit::IoIn(..) => "IoIn",
+				VcpuExit::MmioRead(..) => "MmioRead",
+				VcpuExit::MmioWrite(..) => "MmioWrite",
+				VcpuExit::Exception => "Exception",
+				VcpuExit::Hypercall => "Hypercall",
+				VcpuExit::Debug => "Debug",
+				VcpuExit::Hlt => "Hlt",
+				VcpuExit::IrqWindowOpen => "IrqWindowOpen",
+				VcpuExit::Shutdown => "Shutdown",
+				VcpuExit::FailEntry => "FailEntry",
+				VcpuExit::Intr => "Intr",
+				VcpuExit::SetTpr => "SetTpr",
+				VcpuExit::TprAccess => "TprAccess",
+				VcpuExit::Nmi => "Nmi",
+				VcpuExit::InternalError => "InternalError",
+				VcpuExit::SystemEvent(..) => "SystemEvent",
+				VcpuExit::IoapicEoi(..) => "IoapicEoi",
+				VcpuExit::Hyperv => "Hyperv",
+				_ => "Unknown",
+			},
+		}
+	}
+
+	/// Returns the I/O port of an I/O exit.
+	pub fn port(&self) -> Option<u16> {
+		match self {
+			KvmExit::Vcpu(VcpuExit::IoOut(port, _)) | KvmExit::Vcpu(VcpuExit::IoIn(port, _)) => {
+				Some(*port)
+			}
+			_ => None,
+		}
+	}
+}
+
+/// Mapping of `kvm_run` of a vCPU
+pub struct KvmRun {
+	run: *mut kvm_run,
+	size: usize,
+}
+
+// The mapping is used only by the thread, which runs the vCPU.
+unsafe impl Send for KvmRun {}
+
+impl KvmRun {
+	pub fn new(vcpu: &VcpuFd) -> Result<KvmRun> {
+		let size = KVM.get_vcpu_mmap_size().or_else(to_error)?;
+		let run = unsafe {
+			libc::mmap(
+				ptr::null_mut(),
+				size,
+				libc::PROT_READ | libc::PROT_WRITE,
+				libc::MAP_SHARED,
+				vcpu.as_raw_fd(),
+				0,
+			)
+		};
+		if run == libc::MAP_FAILED {
+			return Err(Error::OsError(unsafe { *libc::__errno_location() }));
+		}
+
+		Ok(KvmRun {
+			run: run as *mut kvm_run,
+			size,
+		})
+	}
+
+	/// Runs `vcpu`, which has been mapped by `new`, until the next exit.
+	pub fn run(&self, vcpu: &VcpuFd) -> std::result::Result<KvmExit<'_>, kvm_ioctls::Error> {
+		let ret = unsafe {
+			libc::ioctl(vcpu.as_raw_fd(), 0xae80 /* KVM_RUN */, 0)
+		};
+		if ret < 0 {
+			return Err(kvm_ioctls::Error::last());
+		}
+
+		// Safe because the kernel told us how large the mapping is and the
+		// exit reason tells us which union field to use.
+		let run = unsafe { &mut *self.run };
+		let exit = match run.exit_reason {
+			KVM_EXIT_X86_RDMSR => {
+				return Ok(KvmExit::RdMsr(unsafe {
+					&mut *(&mut run.__bindgen_anon_1 as *mut _ as *mut MsrExit)
+				}))
+			}
+			KVM_EXIT_X86_WRMSR => {
+				return Ok(KvmExit::WrMsr(unsafe {
+					&mut *(&mut run.__bindgen_anon_1 as *mut _ as *mut MsrExit)
+				}))
+			}
+			KVM_EXIT_IO => {
+				let io = unsafe { run.__bindgen_anon_1.io };
+				let data = unsafe {
+					slice::from_raw_parts_mut(
+						(self.run as *mut u8).offset(io.data_offset as isize),
+						io.count as usize * io.size as usize,
+					)
+				};
+				if u32::from(io.direction) == KVM_EXIT_IO_OUT {
+					VcpuExit::IoOut(io.port, data)
+				} else {
+					VcpuExit::IoIn(io.port, data)
+				}
+			}
+			KVM_EXIT_MMIO => {
+				let mmio = unsafe { &mut run.__bindgen_anon_1.mmio };
+				let len = mmio.len as usize;
+				if mmio.is_write != 0 {
+					VcpuExit::MmioWrite(mmio.phys_addr, &mmio.data[..len])
+				} else {
+					VcpuExit::MmioRead(mmio.phys_addr, &mut mmio.data[..len])
+				}
+			}
+			KVM_EXIT_SYSTEM_EVENT => {
+				let event = unsafe { &run.__bindgen_anon_1.system_event };
+				VcpuExit::SystemEvent(event.type_, event.flags)
+			}
+			KVM_EXIT_IOAPIC_EOI => VcpuExit::IoapicEoi(unsafe { run.__bindgen_anon_1.eoi.vector }),
+			KVM_EXIT_EXCEPTION => VcpuExit::Exception,
+			KVM_EXIT_HYPERCALL => VcpuExit::Hypercall,
+			KVM_EXIT_DEBUG => VcpuExit::Debug,
+			KVM_EXIT_HLT => VcpuExit::Hlt,
+			KVM_EXIT_IRQ_WINDOW_OPEN => VcpuExit::IrqWindowOpen,
+			KVM_EXIT_SHUTDOWN => VcpuExit::Shutdown,
+			KVM_EXIT_FAIL_ENTRY => VcpuExit::FailEntry,
+			KVM_EXIT_INTR => VcpuExit::Intr,
+			KVM_EXIT_SET_TPR => VcpuExit::SetTpr,
+			KVM_EXIT_TPR_ACCESS => VcpuExit::TprAccess,
+			KVM_EXIT_NMI => VcpuExit::Nmi,
+			KVM_EXIT_INTERNAL_ERROR => VcpuExit::InternalError,
+			KVM_EXIT_HYPERV => VcpuExit::Hyperv,
+			reason => {
+				debug!("Unknown KVM exit reason {}", reason);
+				VcpuExit::Unknown
+			}
+		};
+
+		Ok(KvmExit::Vcpu(exit))
+	}
+}
+
+impl Drop for KvmRun {
+	fn drop(&mut self) {
+		unsafe { libc::munmap(self.run as *mut libc::c_void, self.size) };
+	}
+}
@@ -0,0 +1,617 @@
+//! Memory regions, which back the guest memory.
+
+use crate::error::*;
+use crate::linux::MemoryRegion;
+use log::{debug, info};
+use nix::errno::errno;
+use nix::fcntl::{fcntl, FcntlArg, SealFlag};
+use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+use nix::sys::mman::*;
+use nix::unistd::{mkstemp, unlink, Uid};
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::mem;
+use std::os::raw::c_void;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Size of the pages, which are used by the host
+pub const PAGE_SIZE: usize = 0x1000;
+
+/// Anonymous guest memory, which is private to uhyve.
+#[derive(Debug)]
+pub struct MmapMemory {
+	flags: u32,
+	memory_size: usize,
+	guest_address: usize,
+	host_address: usize,
+}
+
+impl MmapMemory {
+	pub fn new(
+		flags: u32,
+		memory_size: usize,
+		guest_address: u64,
+		huge_pages: bool,
+		mergeable: bool,
+	) -> MmapMemory {
+		let host_address = unsafe {
+			mmap(
+				std::ptr::null_mut(),
+				memory_size,
+				ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+				MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS | MapFlags::MAP_NORESERVE,
+				-1,
+				0,
+			)
+			.expect("mmap failed")
+		};
+
+		if mergeable {
+			debug!("Enable kernel feature to merge same pages");
+			unsafe {
+				if madvise(host_address, memory_size, MmapAdvise::MADV_MERGEABLE).is_err() {
+					panic!("madvise failed");
+				}
+			}
+		}
+
+		if huge_pages {
+			debug!("Uhyve uses huge pages");
+			unsafe {
+				if madvise(host_address, memory_size, MmapAdvise::MADV_HUGEPAGE).is_err() {
+					panic!("madvise failed");
+				}
+			}
+		}
+
+		MmapMemory {
+			flags,
+			memory_size,
+			guest_address: guest_address as usize,
+			host_address: host_address as usize,
+		}
+	}
+
+	/// Maps `memory_size` bytes of `file` at `offset` copy-on-write as guest
+	/// memory. Pages are read lazily from the file on the first access and
+	/// modifications remain private to this VM.
+	pub fn from_file(
+		flags: u32,
+		memory_size: usize,
+		guest_address: u64,
+		file: &File,
+		offset: u64,
+	) -> Result<MmapMemory> {
+		let host_address = unsafe {
+			mmap(
+				std::ptr::null_mut(),
+				memory_size,
+				ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+				MapFlags::MAP_PRIVATE | MapFlags::MAP_NORESERVE,
+				file.as_raw_fd(),
+				offset as libc::off_t,
+			)
+			.map_err(|_| Error::OsError(errno()))?
+		};
+
+		Ok(MmapMemory {
+			flags,
+			memory_size,
+			guest_address: guest_address as usize,
+			host_address: host_address as usize,
+		})
+	}
+
+	/// Backs the guest memory by explicit huge pages of size `page_size` (2 MiB
+	/// or 1 GiB) from the hugetlb pool of the host.
+	pub fn hugetlb(
+		flags: u32,
+		memory_size: usize,
+		guest_address: u64,
+		page_size: usize,
+	) -> Result<MmapMemory> {
+		let size_flag = match page_size {
+			0x200000 => MapFlags::MAP_HUGE_2MB,
+			0x40000000 => MapFlags::MAP_HUGE_1GB,
+			_ => {
+				return Err(Error::InvalidArgument(format!(
+					"unsupported huge page size {} KiB",
+					page_size >> 10
+				)))
+			}
+		};
+		if memory_size % page_size != 0 {
+			return Err(Error::InvalidArgument(format!(
+				"guest memory size {} KiB isn't a multiple of the huge page size {} KiB",
+				memory_size >> 10,
+				page_size >> 10
+			)));
+		}
+
+		let pool = HugetlbPool::read(page_size)?;
+		info!(
+			"Huge pages of size {} KiB: {} in total, {} free, {} reserved",
+			page_size >> 10,
+			pool.total,
+			pool.free,
+			pool.reserved
+		);
+		let required = (memory_size / page_size) as u64;
+		if pool.available() < required {
+			return Err(Error::NotEnoughHugePages(
+				page_size,
+				required,
+				pool.available(),
+			));
+		}
+
+		// without MAP_NORESERVE, the kernel reserves the huge pages during mmap
+		let host_address = unsafe {
+			mmap(
+				std::ptr::null_mut(),
+				memory_size,
+				ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+				MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS | MapFlags::MAP_HUGETLB | size_flag,
+				-1,
+				0,
+			)
+			.map_err(|_| Error::OsError(errno()))?
+		};
+
+		Ok(MmapMemory {
+			flags,
+			memory_size,
+			guest_address: guest_address as usize,
+			host_address: host_address as usize,
+		})
+	}
+
+	#[allow(dead_code)]
+	fn as_slice_mut(&mut self) -> &mut [u8] {
+		unsafe { std::slice::from_raw_parts_mut(self.host_address as *mut u8, self.memory_size) }
+	}
+}
+
+impl MemoryRegion for MmapMemory {
+	fn flags(&self) -> u32 {
+		self.flags
+	}
+
+	fn memory_size(&self) -> usize {
+		self.memory_size
+	}
+
+	fn guest_address(&self) -> usize {
+		self.guest_address
+	}
+
+	fn host_address(&self) -> usize {
+		self.host_address
+	}
+}
+
+impl Drop for MmapMemory {
+	fn drop(&mut self) {
+		if self.memory_size() > 0 {
+			unsafe {
+				if munmap(self.host_address() as *mut c_void, self.memory_size()).is_err() {
+					panic!("munmap failed");
+				}
+			}
+		}
+	}
+}
+
+const MPOL_BIND: libc::c_int = 2;
+const MPOL_MF_MOVE: libc::c_uint = 1 << 1;
+
+/// Binds the guest memory to the NUMA nodes `nodes` by using `mbind`. Pages,
+/// which are already touched, are migrated to these nodes.
+pub fn bind_to_nodes(mem: &dyn MemoryRegion, nodes: &[u32]) -> Result<()> {
+	let bits = 8 * mem::size_of::<libc::c_ulong>();
+	let max_node =
+		nodes.iter().copied().max().ok_or_else(|| {
+			Error::InvalidArgument("at least one NUMA node is required".to_string())
+		})? as usize;
+	let mut nodemask: Vec<libc::c_ulong> = vec![0; max_node / bits + 1];
+	for node in nodes {
+		nodemask[*node as usize / bits] |= 1 << (*node as usize % bits);
+	}
+
+	// the kernel expects the number of bits in the nodemask plus one
+	let ret = unsafe {
+		libc::syscall(
+			libc::SYS_mbind,
+			mem.host_address(),
+			mem.memory_size(),
+			MPOL_BIND,
+			nodemask.as_ptr(),
+			nodemask.len() * bits + 1,
+			MPOL_MF_MOVE,
+		)
+	};
+	if ret < 0 {
+		return Err(Error::OsError(errno()));
+	}
+
+	Ok(())
+}
+
+/// Touches every page of the guest memory to avoid page faults at runtime.
+/// The memory is split into chunks, which are touched by one thread per host
+/// CPU. Returns the time, which was required to populate the memory.
+pub fn prefault(mem: &dyn MemoryRegion) -> Duration {
+	let start = Instant::now();
+	let threads = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) }.max(1) as usize;
+	let chunk_size = align_up!(mem.memory_size() / threads, PAGE_SIZE).max(PAGE_SIZE);
+
+	let handles: Vec<_> = (0..mem.memory_size())
+		.step_by(chunk_size)
+		.map(|offset| {
+			let chunk_start = mem.host_address() + offset;
+			let chunk_end = (chunk_start + chunk_size).min(mem.host_address() + mem.memory_size());
+			thread::spawn(move || {
+				for addr in (chunk_start..chunk_end).step_by(PAGE_SIZE) {
+					// keep the content, because file-backed memory may be already initialized
+					unsafe {
+						let ptr = addr as *mut u8;
+						ptr::write_volatile(ptr, ptr::read_volatile(ptr));
+					}
+				}
+			})
+		})
+		.collect();
+	for handle in handles {
+		handle.join().expect("Prefault thread panicked");
+	}
+
+	start.elapsed()
+}
+
+/// Locks the guest memory into RAM by using `mlock`. Fails, if the memory
+/// exceeds `RLIMIT_MEMLOCK` and the process isn't privileged. Returns the
+/// time, which was required to populate and lock the memory.
+pub fn lock(mem: &dyn MemoryRegion) -> Result<Duration> {
+	let mut limit = libc::rlimit {
+		rlim_cur: 0,
+		rlim_max: 0,
+	};
+	if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) } < 0 {
+		return Err(Error::OsError(errno()));
+	}
+	if limit.rlim_cur != libc::RLIM_INFINITY
+		&& limit.rlim_cur < mem.memory_size() as u64
+		&& !Uid::effective().is_root()
+	{
+		return Err(Error::MemlockLimit(limit.rlim_cur, mem.memory_size()));
+	}
+
+	let start = Instant::now();
+	unsafe { mlock(mem.host_address() as *const c_void, mem.memory_size()) }
+		.map_err(|_| Error::OsError(errno()))?;
+
+	Ok(start.elapsed())
+}
+
+/// Dirty pages of the guest memory, which are reported by KVM. Writes by
+/// uhyve itself (e.g. by hypercalls or devices) aren't tracked.
+#[derive(Debug, Clone, Default)]
+pub struct DirtyLog {
+	/// Guest-physical start address and dirty bitmap of each memory slot
+	slots: Vec<(usize, Vec<u64>)>,
+}
+
+impl DirtyLog {
+	pub fn new() -> Self {
+		Default::default()
+	}
+
+	/// Adds the dirty bitmap of a memory slot, which starts at the
+	/// guest-physical address `guest_address`.
+	pub fn add_slot(&mut self, guest_address: usize, bitmap: Vec<u64>) {
+		self.slots.push((guest_address, bitmap));
+	}
+
+	/// Returns the number of dirty pages.
+	pub fn count(&self) -> usize {
+		self.slots
+			.iter()
+			.flat_map(|(_, bitmap)| bitmap.iter())
+			.map(|bits| bits.count_ones() as usize)
+			.sum()
+	}
+
+	/// Returns an iterator over the guest-physical addresses of all dirty pages.
+	pub fn pages(&self) -> impl Iterator<Item = usize> + '_ {
+		self.slots.iter().flat_map(|(guest_address, bitmap)| {
+			bitmap.iter().enumerate().flat_map(move |(i, bits)| {
+				(0..64)
+					.filter(move |bit| bits & (1 << bit) != 0)
+					.map(move |bit| guest_address + (i * 64 + bit) * PAGE_SIZE)
+			})
+		})
+	}
+}
+
+/// State of the hugetlb pool of one huge page size, as reported by
+/// `/sys/kernel/mm/hugepages`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HugetlbPool {
+	pub page_size: usize,
+	/// Number of persistent huge pages in the pool
+	pub total: u64,
+	/// Number of huge pages, which are not yet allocated
+	pub free: u64,
+	/// Number of free huge pages, which are already promised to a mapping
+	pub reserved: u64,
+}
+
+impl HugetlbPool {
+	pub fn read(page_size: usize) -> Result<HugetlbPool> {
+		let dir = PathBuf::from(format!(
+			"/sys/kernel/mm/hugepages/hugepages-{}kB",
+			page_size >> 10
+		));
+		let read_value = |name: &str| -> Result<u64> {
+			let path = dir.join(name);
+			fs::read_to_string(&path)
+				.ok()
+				.and_then(|s| s.trim().parse::<u64>().ok())
+				.ok_or(Error::InvalidFile(path))
+		};
+
+		Ok(HugetlbPool {
+			page_size,
+			total: read_value("nr_hugepages")?,
+			free: read_value("free_hugepages")?,
+			reserved: read_value("resv_hugepages")?,
+		})
+	}
+
+	/// Returns the number of huge pages, which are usable for a new mapping.
+	pub fn available(&self) -> u64 {
+		self.free.saturating_sub(self.reserved)
+	}
+}
+
+/// Guest memory, which is shared with a file by using `MAP_SHARED`.
+///
+/// The file is either located on a tmpfs/hugetlbfs or is a sealed memfd. In
+/// both cases, other processes are able to map the guest memory.
+#[derive(Debug)]
+pub struct SharedMemory {
+	flags: u32,
+	memory_size: usize,
+	guest_address: usize,
+	host_address: usize,
+	file: File,
+}
+
+impl SharedMemory {
+	/// Uses the file at `path` as guest memory. If `path` is a directory
+	/// (e.g. the mount point of a hugetlbfs), a temporary file is created in it.
+	pub fn from_path(
+		flags: u32,
+		memory_size: usize,
+		guest_address: u64,
+		path: &Path,
+	) -> Result<SharedMemory> {
+		let file = if path.is_dir() {
+			let (fd, name) = mkstemp(&path.join("uhyve.XXXXXX"))
+				.map_err(|_| Error::InvalidFile(path.to_path_buf()))?;
+			debug!("Create temporary guest memory file {}", name.display());
+			// the file is still accessible by the file descriptor
+			unlink(&name).map_err(|_| Error::OsError(errno()))?;
+			unsafe { File::from_raw_fd(fd) }
+		} else {
+			OpenOptions::new()
+				.read(true)
+				.write(true)
+				.create(true)
+				.truncate(false)
+				.open(path)
+				.map_err(|_| Error::InvalidFile(path.to_path_buf()))?
+		};
+
+		let len = file
+			.metadata()
+			.map_err(|_| Error::InvalidFile(path.to_path_buf()))?
+			.len();
+		if len < memory_size as u64 {
+			file.set_len(memory_size as u64)
+				.map_err(|_| Error::NotEnoughMemory)?;
+		}
+
+		SharedMemory::from_file(flags, memory_size, guest_address, file)
+	}
+
+	/// Creates an anonymous memory file (memfd) as guest memory. The file is
+	/// sealed, so that its size cannot be changed anymore.
+	pub fn from_memfd(flags: u32, memory_size: usize, guest_address: u64) -> Result<SharedMemory> {
+		let name = CString::new("uhyve").unwrap();
+		let fd = memfd_create(
+			&name,
+			MemFdCreateFlag::MFD_CLOEXEC | MemFdCreateFlag::MFD_ALLOW_SEALING,
+		)
+		.map_err(|_| Error::OsError(errno()))?;
+		let file = unsafe { File::from_raw_fd(fd) };
+
+		file.set_len(memory_size as u64)
+			.map_err(|_| Error::NotEnoughMemory)?;
+		fcntl(
+			fd,
+			FcntlArg::F_ADD_SEALS(
+				SealFlag::F_SEAL_SHRINK | SealFlag::F_SEAL_GROW | SealFlag::F_SEAL_SEAL,
+			),
+		)
+		.map_err(|_| Error::OsError(errno()))?;
+		debug!(
+			"Guest memory is accessible by /proc/{}/fd/{}",
+			std::process::id(),
+			fd
+		);
+
+		SharedMemory::from_file(flags, memory_size, guest_address, file)
+	}
+
+	fn from_file(
+		flags: u32,
+		memory_size: usize,
+		guest_address: u64,
+		file: File,
+	) -> Result<SharedMemory> {
+		let host_address = unsafe {
+			mmap(
+				std::ptr::null_mut(),
+				memory_size,
+				ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+				MapFlags::MAP_SHARED | MapFlags::MAP_NORESERVE,
+				file.as_raw_fd(),
+				0,
+			)
+			.map_err(|_| Error::OsError(errno()))?
+		};
+
+		Ok(SharedMemory {
+			flags,
+			memory_size,
+			guest_address: guest_address as usize,
+			host_address: host_address as usize,
+			file,
+		})
+	}
+}
+
+impl MemoryRegion for SharedMemory {
+	fn flags(&self) -> u32 {
+		self.flags
+	}
+
+	fn memory_size(&self) -> usize {
+		self.memory_size
+	}
+
+	fn guest_address(&self) -> usize {
+		self.guest_address
+	}
+
+	fn host_address(&self) -> usize {
+		self.host_address
+	}
+
+	fn fd(&self) -> Option<RawFd> {
+		Some(self.file.as_raw_fd())
+	}
+}
+
+impl Drop for SharedMemory {
+	fn drop(&mut self) {
+		if self.memory_size() > 0 {
+			unsafe {
+				if munmap(self.host_address() as *mut c_void, self.memory_size()).is_err() {
+					panic!("munmap failed");
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::Read;
+
+	#[test]
+	fn test_memfd_is_shared() {
+		let mem = SharedMemory::from_memfd(0, 0x200000, 0).unwrap();
+		unsafe { *(mem.host_address() as *mut u8).add(0x1000) = 0x42 };
+
+		let mut file = unsafe { File::from_raw_fd(nix::unistd::dup(mem.fd().unwrap()).unwrap()) };
+		let mut buf = vec![0u8; 0x1001];
+		file.read_exact(&mut buf).unwrap();
+		assert_eq!(buf[0x1000], 0x42);
+	}
+
+	#[test]
+	fn test_hugetlb_pool() {
+		let pool = HugetlbPool {
+			page_size: 0x200000,
+			total: 16,
+			free: 8,
+			reserved: 2,
+		};
+		assert_eq!(pool.available(), 6);
+	}
+
+	#[test]
+	fn test_hugetlb_invalid_size() {
+		assert!(MmapMemory::hugetlb(0, 0x400000, 0, 0x1000).is_err());
+		assert!(MmapMemory::hugetlb(0, 0x300000, 0, 0x200000).is_err());
+	}
+
+	#[test]
+	fn test_bind_to_nodes() {
+		let mem = MmapMemory::new(0, 0x200000, 0, false, false);
+		assert!(bind_to_nodes(&mem, &[]).is_err());
+		if std::path::Path::new("/sys/devices/system/node/node0").is_dir() {
+			bind_to_nodes(&mem, &[0]).unwrap();
+		}
+	}
+
+	#[test]
+	fn test_prefault() {
+		let mem = SharedMemory::from_memfd(0, 0x203000, 0).unwrap();
+		unsafe { *(mem.host_address() as *mut u8).add(0x2000) = 0x42 };
+		prefault(&mem);
+
+		// every page is populated and the content is preserved
+		let mut file = unsafe { File::from_raw_fd(nix::unistd::dup(mem.fd().unwrap()).unwrap()) };
+		let mut buf = vec![0u8; mem.memory_size()];
+		file.read_exact(&mut buf).unwrap();
+		assert_eq!(buf[0x2000], 0x42);
+		let stat = nix::sys::stat::fstat(mem.fd().unwrap()).unwrap();
+		assert_eq!(stat.st_blocks as usize * 512, mem.memory_size());
+	}
+
+	#[test]
+	fn test_dirty_log() {
+		let mut log = DirtyLog::new();
+		log.add_slot(0, vec![0b101, 1 << 63]);
+		log.add_slot(0x1_0000_0000, vec![0b10]);
+		assert_eq!(log.count(), 4);
+		assert_eq!(
+			log.pages().collect::<Vec<_>>(),
+			vec![0, 0x2000, 127 * 0x1000, 0x1_0000_1000]
+		);
+	}
+
+	#[test]
+	fn test_private_file_mapping() {
+		let shared = SharedMemory::from_memfd(0, 0x3000, 0).unwrap();
+		unsafe { *(shared.host_address() as *mut u8).add(0x1000) = 0x42 };
+
+		let file = unsafe { File::from_raw_fd(nix::unistd::dup(shared.fd().unwrap()).unwrap()) };
+		let mem = MmapMemory::from_file(0, 0x2000, 0, &file, 0x1000).unwrap();
+		let ptr = mem.host_address() as *mut u8;
+		assert_eq!(unsafe { *ptr }, 0x42);
+
+		// writes remain private
+		unsafe { *ptr = 0x43 };
+		assert_eq!(
+			unsafe { *(shared.host_address() as *const u8).add(0x1000) },
+			0x42
+		);
+	}
+
+	#[test]
+	fn test_mem_path_directory() {
+		let dir = std::env::temp_dir();
+		let mem = SharedMemory::from_path(0, 0x200000, 0, &dir).unwrap();
+		assert_eq!(mem.memory_size(), 0x200000);
+		assert!(mem.fd().is_some());
+	}
+}
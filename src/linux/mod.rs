@@ -1,21 +1,37 @@
+pub mod cpuid;
 pub mod gdb;
+pub mod kick;
+pub mod kvm_run;
+pub mod memory;
+pub mod msr;
+pub mod profiler;
+pub mod rtc;
+pub mod serial;
+pub mod snapshot;
+pub mod tsc;
 pub mod uhyve;
 pub mod vcpu;
 pub mod virtio;
 pub mod virtqueue;
+pub mod watchdog;
 
 use kvm_ioctls::Kvm;
 use lazy_static::lazy_static;
+use std::os::unix::io::RawFd;
 
 lazy_static! {
 	static ref KVM: Kvm = Kvm::new().unwrap();
 }
 
-trait MemoryRegion {
+pub trait MemoryRegion {
 	fn flags(&self) -> u32;
 	fn memory_size(&self) -> usize;
 	fn guest_address(&self) -> usize;
 	fn host_address(&self) -> usize;
+	/// Returns the file descriptor of the file, which backs the memory region.
+	fn fd(&self) -> Option<RawFd> {
+		None
+	}
 }
 
 #[cfg(test)]
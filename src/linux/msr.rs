@@ -0,0 +1,384 @@
+//! Machine specific registers of the guest.
+//!
+//! uhyve initializes the MSRs with explicit values and allows the guest only
+//! to access the MSRs, which RustyHermit requires (`KVM_X86_SET_MSR_FILTER`).
+//! All other accesses exit to uhyve (`KVM_CAP_X86_USER_SPACE_MSR`), which logs
+//! them and injects a #GP into the guest.
+
+use crate::error::*;
+use crate::linux::kvm_run::MsrExit;
+use crate::linux::KVM;
+use kvm_bindings::{kvm_enable_cap, kvm_msr_entry, Msrs};
+use kvm_ioctls::VmFd;
+use log::{debug, warn};
+use std::os::unix::io::AsRawFd;
+
+const MSR_IA32_TSC: u32 = 0x0000_0010;
+const MSR_IA32_APIC_BASE: u32 = 0x0000_001b;
+const MSR_IA32_TSC_ADJUST: u32 = 0x0000_003b;
+const MSR_IA32_SPEC_CTRL: u32 = 0x0000_0048;
+const MSR_IA32_UCODE_REV: u32 = 0x0000_008b;
+const MSR_IA32_MPERF: u32 = 0x0000_00e7;
+const MSR_MTRR_CAP: u32 = 0x0000_00fe;
+const MSR_IA32_ARCH_CAPABILITIES: u32 = 0x0000_010a;
+const MSR_IA32_SYSENTER_CS: u32 = 0x0000_0174;
+const MSR_IA32_SYSENTER_ESP: u32 = 0x0000_0175;
+const MSR_IA32_SYSENTER_EIP: u32 = 0x0000_0176;
+const MSR_IA32_MCG_CAP: u32 = 0x0000_0179;
+const MSR_IA32_MISC_ENABLE: u32 = 0x0000_01a0;
+const MSR_MTRR_PHYS_BASE0: u32 = 0x0000_0200;
+const MSR_MTRR_FIX64K_00000: u32 = 0x0000_0250;
+const MSR_MTRR_FIX16K_80000: u32 = 0x0000_0258;
+const MSR_MTRR_FIX4K_C0000: u32 = 0x0000_0268;
+const MSR_IA32_CR_PAT: u32 = 0x0000_0277;
+const MSR_MTRR_DEF_TYPE: u32 = 0x0000_02ff;
+const MSR_IA32_TSC_DEADLINE: u32 = 0x0000_06e0;
+const MSR_X2APIC_BASE: u32 = 0x0000_0800;
+const MSR_IA32_XSS: u32 = 0x0000_0da0;
+const MSR_KVM_WALL_CLOCK: u32 = 0x0000_0011;
+const MSR_KVM_BASE: u32 = 0x4b56_4d00;
+const MSR_EFER: u32 = 0xc000_0080;
+const MSR_STAR: u32 = 0xc000_0081;
+const MSR_LSTAR: u32 = 0xc000_0082;
+const MSR_CSTAR: u32 = 0xc000_0083;
+const MSR_SYSCALL_MASK: u32 = 0xc000_0084;
+const MSR_FS_BASE: u32 = 0xc000_0100;
+const MSR_KERNEL_GS_BASE: u32 = 0xc000_0102;
+const MSR_TSC_AUX: u32 = 0xc000_0103;
+
+/// Initial values of the MSRs. All other MSRs keep the reset values of KVM.
+const MSR_DEFAULTS: [(u32, u64); 15] = [
+	(MSR_IA32_TSC, 0),
+	(MSR_IA32_SYSENTER_CS, 0),
+	(MSR_IA32_SYSENTER_ESP, 0),
+	(MSR_IA32_SYSENTER_EIP, 0),
+	// enable fast string operations
+	(MSR_IA32_MISC_ENABLE, 1),
+	// power-on value: WB, WT, UC-, UC in both halves
+	(MSR_IA32_CR_PAT, 0x0007_0406_0007_0406),
+	// enable the MTRRs, default memory type write-back
+	(MSR_MTRR_DEF_TYPE, 0x806),
+	(MSR_IA32_TSC_DEADLINE, 0),
+	(MSR_STAR, 0),
+	(MSR_LSTAR, 0),
+	(MSR_CSTAR, 0),
+	(MSR_SYSCALL_MASK, 0),
+	(MSR_KERNEL_GS_BASE, 0),
+	(MSR_TSC_AUX, 0),
+	(MSR_IA32_TSC_ADJUST, 0),
+];
+
+/// MSRs (first index, number), which the guest may access
+const ALLOWED_MSRS: [(u32, u32); 24] = [
+	(MSR_KVM_WALL_CLOCK, 2),
+	(MSR_IA32_TSC, 1),
+	(MSR_IA32_APIC_BASE, 1),
+	(MSR_IA32_TSC_ADJUST, 1),
+	(MSR_IA32_SPEC_CTRL, 2),
+	(MSR_IA32_UCODE_REV, 1),
+	(MSR_IA32_MPERF, 2),
+	(MSR_MTRR_CAP, 1),
+	(MSR_IA32_ARCH_CAPABILITIES, 1),
+	(MSR_IA32_SYSENTER_CS, 3),
+	(MSR_IA32_MCG_CAP, 2),
+	(MSR_IA32_MISC_ENABLE, 1),
+	(MSR_MTRR_PHYS_BASE0, 16),
+	(MSR_MTRR_FIX64K_00000, 1),
+	(MSR_MTRR_FIX16K_80000, 2),
+	(MSR_MTRR_FIX4K_C0000, 8),
+	(MSR_IA32_CR_PAT, 1),
+	(MSR_MTRR_DEF_TYPE, 1),
+	(MSR_IA32_TSC_DEADLINE, 1),
+	(MSR_X2APIC_BASE, 0x100),
+	(MSR_IA32_XSS, 1),
+	(MSR_KVM_BASE, 8),
+	(MSR_EFER, 5),
+	(MSR_FS_BASE, 4),
+];
+
+const KVM_CAP_X86_USER_SPACE_MSR: u32 = 188;
+const KVM_CAP_X86_MSR_FILTER: u32 = 189;
+
+/// Reasons of `KVM_CAP_X86_USER_SPACE_MSR`, which exit to uhyve
+const KVM_MSR_EXIT_REASON_INVAL: u32 = 1 << 0;
+const KVM_MSR_EXIT_REASON_UNKNOWN: u32 = 1 << 1;
+const KVM_MSR_EXIT_REASON_FILTER: u32 = 1 << 2;
+
+const KVM_MSR_FILTER_READ: u32 = 1 << 0;
+const KVM_MSR_FILTER_WRITE: u32 = 1 << 1;
+const KVM_MSR_FILTER_DEFAULT_DENY: u32 = 1 << 0;
+const KVM_MSR_FILTER_MAX_RANGES: usize = 16;
+/// Maximal number of MSRs in one range of the filter
+const KVM_MSR_FILTER_MAX_MSRS: u32 = 0x600 * 8;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct KvmMsrFilterRange {
+	flags: u32,
+	nmsrs: u32,
+	base: u32,
+	bitmap: *const u8,
+}
+
+#[repr(C)]
+struct KvmMsrFilter {
+	flags: u32,
+	ranges: [KvmMsrFilterRange; KVM_MSR_FILTER_MAX_RANGES],
+}
+
+/// Returns the initial values of the MSRs, which KVM supports.
+pub fn initial_msrs() -> Result<Msrs> {
+	let msr_list = KVM.get_msr_index_list().or_else(to_error)?;
+	let entries = MSR_DEFAULTS
+		.iter()
+		.filter(|(index, _)| msr_list.as_slice().contains(index))
+		.map(|&(index, data)| kvm_msr_entry {
+			index,
+			data,
+			..Default::default()
+		})
+		.collect::<Vec<_>>();
+
+	Ok(Msrs::from_entries(&entries)
+		.expect("Unable to create initial values for the machine specific registers"))
+}
+
+/// Filter of the MSR accesses. Each range consists of the first index and a
+/// bitmap, in which a set bit allows the access.
+#[derive(Debug, Default)]
+pub struct MsrFilter {
+	ranges: Vec<(u32, Vec<u8>)>,
+}
+
+impl MsrFilter {
+	/// Creates a filter, which allows the MSRs `allowed` (first index, number).
+	pub fn new(allowed: &[(u32, u32)]) -> MsrFilter {
+		let mut indices = allowed
+			.iter()
+			.flat_map(|&(base, count)| base..base + count)
+			.collect::<Vec<_>>();
+		indices.sort_unstable();
+
+		let mut filter = MsrFilter::default();
+		for index in indices {
+			match filter.ranges.last() {
+				Some((base, _)) if index - base < KVM_MSR_FILTER_MAX_MSRS => {}
+				_ => filter.ranges.push((index, Vec::new())),
+			}
+			let (base, bitmap) = filter.ranges.last_mut().unwrap();
+			let bit = (index - *base) as usize;
+			if bitmap.len() <= bit / 8 {
+				bitmap.resize(bit / 8 + 1, 0);
+			}
+			bitmap[bit / 8] |= 1 << (bit % 8);
+		}
+
+		filter
+	}
+
+	pub fn is_allowed(&self, index: u32) -> bool {
+		self.ranges.iter().any(|(base, bitmap)| {
+			let bit = index.wrapping_sub(*base) as usize;
+			bit / 8 < bitmap.len() && bitmap[bit / 8] & (1 << (bit % 8)) != 0
+		})
+	}
+
+	/// Installs the filter at `vm`. Accesses to all other MSRs are denied.
+	pub fn install(&self, vm: &VmFd) -> Result<()> {
+		assert!(self.ranges.len() <= KVM_MSR_FILTER_MAX_RANGES);
+
+		let mut filter = KvmMsrFilter {
+			flags: KVM_MSR_FILTER_DEFAULT_DENY,
+			ranges: [KvmMsrFilterRange {
+				flags: 0,
+				nmsrs: 0,
+				base: 0,
+				bitmap: std::ptr::null(),
+			}; KVM_MSR_FILTER_MAX_RANGES],
+		};
+		for (range, (base, bitmap)) in filter.ranges.iter_mut().zip(self.ranges.iter()) {
+			range.flags = KVM_MSR_FILTER_READ | KVM_MSR_FILTER_WRITE;
+			range.nmsrs = bitmap.len() as u32 * 8;
+			range.base = *base;
+			range.bitmap = bitmap.as_ptr();
+		}
+
+		let ret = unsafe {
+			libc::ioctl(
+				vm.as_raw_fd(),
+				0x4188aec6, /* KVM_X86_SET_MSR_FILTER */
+				&filter,
+			)
+		};
+		if ret < 0 {
+			return Err(Error::OsError(unsafe { *libc::__errno_location() }));
+		}
+
+		Ok(())
+	}
+}
+
+fn check_extension(vm: &VmFd, cap: u32) -> bool {
+	unsafe {
+		libc::ioctl(vm.as_raw_fd(), 0xae03 /* KVM_CHECK_EXTENSION */, cap) > 0
+	}
+}
+
+/// Forwards unknown, invalid and filtered MSR accesses of the guest to uhyve.
+/// Without the support of KVM, the guest gets a #GP from KVM instead and the
+/// accesses aren't logged.
+pub fn setup_vm(vm: &VmFd) -> Result<()> {
+	if !check_extension(vm, KVM_CAP_X86_USER_SPACE_MSR)
+		|| !check_extension(vm, KVM_CAP_X86_MSR_FILTER)
+	{
+		debug!("KVM doesn't support the filtering of MSR accesses");
+		return Ok(());
+	}
+
+	let mut cap = kvm_enable_cap {
+		cap: KVM_CAP_X86_USER_SPACE_MSR,
+		..Default::default()
+	};
+	cap.args[0] = (KVM_MSR_EXIT_REASON_INVAL
+		| KVM_MSR_EXIT_REASON_UNKNOWN
+		| KVM_MSR_EXIT_REASON_FILTER) as u64;
+	vm.enable_cap(&cap).or_else(to_error)?;
+
+	MsrFilter::new(&ALLOWED_MSRS).install(vm)
+}
+
+fn reason_name(reason: u32) -> &'static str {
+	match reason {
+		KVM_MSR_EXIT_REASON_INVAL => "invalid access",
+		KVM_MSR_EXIT_REASON_UNKNOWN => "unknown MSR",
+		KVM_MSR_EXIT_REASON_FILTER => "filtered MSR",
+		_ => "unknown reason",
+	}
+}
+
+/// Handles a read of the guest, which KVM has forwarded to uhyve => #GP
+pub fn emulate_rdmsr(cpu: u32, msr: &mut MsrExit) {
+	warn!(
+		"CPU {} reads MSR 0x{:x} ({}) => #GP",
+		cpu,
+		msr.index,
+		reason_name(msr.reason)
+	);
+	msr.error = 1;
+}
+
+/// Handles a write of the guest, which KVM has forwarded to uhyve => #GP
+pub fn emulate_wrmsr(cpu: u32, msr: &mut MsrExit) {
+	warn!(
+		"CPU {} writes 0x{:x} into MSR 0x{:x} ({}) => #GP",
+		cpu,
+		msr.data,
+		msr.index,
+		reason_name(msr.reason)
+	);
+	msr.error = 1;
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::linux::kvm_run::{KvmExit, KvmRun};
+	use crate::linux::memory::MmapMemory;
+	use crate::linux::MemoryRegion;
+	use kvm_bindings::kvm_userspace_memory_region;
+	use kvm_ioctls::VcpuExit;
+	use std::ptr;
+
+	#[test]
+	fn test_filter() {
+		let filter = MsrFilter::new(&ALLOWED_MSRS);
+		// low MSRs, KVM MSRs and AMD64 MSRs
+		assert_eq!(filter.ranges.len(), 3);
+
+		for &(base, count) in ALLOWED_MSRS.iter() {
+			assert!(filter.is_allowed(base));
+			assert!(filter.is_allowed(base + count - 1));
+		}
+		assert!(filter.is_allowed(0x8ff));
+		assert!(!filter.is_allowed(0x0));
+		assert!(!filter.is_allowed(0xce));
+		assert!(!filter.is_allowed(0x900));
+		assert!(!filter.is_allowed(0xc000_0085));
+		assert!(!filter.is_allowed(0xc001_0015));
+	}
+
+	#[test]
+	fn test_initial_msrs() {
+		if !crate::linux::tests::has_vm_support() {
+			return;
+		}
+
+		let msrs = initial_msrs().unwrap();
+		assert!(msrs
+			.as_slice()
+			.iter()
+			.any(|msr| msr.index == MSR_IA32_MISC_ENABLE && msr.data == 1));
+
+		let vm = KVM.create_vm().unwrap();
+		let vcpu = vm.create_vcpu(0).unwrap();
+		assert_eq!(vcpu.set_msrs(&msrs).unwrap(), msrs.as_slice().len());
+	}
+
+	#[test]
+	fn test_filtered_rdmsr() {
+		if !crate::linux::tests::has_vm_support() {
+			return;
+		}
+
+		let vm = KVM.create_vm().unwrap();
+		if !check_extension(&vm, KVM_CAP_X86_USER_SPACE_MSR)
+			|| !check_extension(&vm, KVM_CAP_X86_MSR_FILTER)
+		{
+			return;
+		}
+		setup_vm(&vm).unwrap();
+
+		// real mode: read EFER (allowed), read MSR_PLATFORM_INFO (filtered), hlt
+		let code = [
+			0x66, 0xb9, 0x80, 0x00, 0x00, 0xc0, 0x0f, 0x32, 0x66, 0xb9, 0xce, 0x00, 0x00, 0x00,
+			0x0f, 0x32, 0xf4,
+		];
+		let mem = MmapMemory::new(0, 0x1000, 0, false, false);
+		unsafe {
+			ptr::copy_nonoverlapping(code.as_ptr(), mem.host_address() as *mut u8, code.len())
+		};
+		let region = kvm_userspace_memory_region {
+			slot: 0,
+			flags: 0,
+			guest_phys_addr: 0,
+			memory_size: mem.memory_size() as u64,
+			userspace_addr: mem.host_address() as u64,
+		};
+		unsafe { vm.set_user_memory_region(region) }.unwrap();
+
+		let vcpu = vm.create_vcpu(0).unwrap();
+		let mut sregs = vcpu.get_sregs().unwrap();
+		sregs.cs.base = 0;
+		sregs.cs.selector = 0;
+		vcpu.set_sregs(&sregs).unwrap();
+		let mut regs = vcpu.get_regs().unwrap();
+		regs.rip = 0;
+		regs.rflags = 2;
+		vcpu.set_regs(&regs).unwrap();
+
+		let kvm_run = KvmRun::new(&vcpu).unwrap();
+		match kvm_run.run(&vcpu).unwrap() {
+			KvmExit::RdMsr(msr) => {
+				assert_eq!(msr.index, 0xce);
+				assert_eq!(msr.reason, KVM_MSR_EXIT_REASON_FILTER);
+				msr.data = 0x1234;
+			}
+			_ => panic!("Expected an exit of RDMSR"),
+		}
+		match kvm_run.run(&vcpu).unwrap() {
+			KvmExit::Vcpu(VcpuExit::Hlt) => {}
+			_ => panic!("Expected an exit of HLT"),
+		}
+		assert_eq!(vcpu.get_regs().unwrap().rax, 0x1234);
+	}
+}
@@ -0,0 +1,269 @@
+//! Sampling profiler of the guest.
+//!
+//! The profiler thread periodically kicks all vCPUs. A kicked vCPU records
+//! its stack, which it walks along the frame pointers through the page
+//! tables of the guest, as a sample. After the VM has stopped, the samples
+//! are symbolized by the kernel and written as folded stacks (e.g. for
+//! `flamegraph.pl`) and as the text of `perf script`.
+
+use crate::linux::kick;
+use crate::linux::uhyve::Uhyve;
+use crate::symbols::Symbols;
+use crate::vm::Vm;
+use log::{debug, error, info, warn};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Samples per second and vCPU. Like `perf record`, 99 Hz avoids sampling
+/// in lockstep with periodic activities of the guest.
+pub const SAMPLE_FREQUENCY: u32 = 99;
+
+/// Extension of the file with the text of `perf script`
+const PERF_SCRIPT_EXTENSION: &str = "perf";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Sample {
+	cpu: u32,
+	/// Time since the start of the profiler
+	time: Duration,
+	/// Return addresses, beginning with the RIP
+	frames: Vec<u64>,
+}
+
+/// Samples of all vCPUs of a VM
+#[derive(Debug)]
+pub struct Profiler {
+	path: PathBuf,
+	start: Instant,
+	requested: Vec<AtomicBool>,
+	samples: Mutex<Vec<Sample>>,
+}
+
+impl Profiler {
+	/// Creates a profiler, which writes the folded stacks to `path` and the
+	/// text of `perf script` to `path` with the extension `.perf`.
+	pub fn new(num_cpus: u32, path: PathBuf) -> Self {
+		Profiler {
+			path,
+			start: Instant::now(),
+			requested: (0..num_cpus).map(|_| AtomicBool::new(false)).collect(),
+			samples: Mutex::new(Vec::new()),
+		}
+	}
+
+	/// Returns true if vCPU `id` has to record a sample after a kick.
+	pub fn sample_requested(&self, id: u32) -> bool {
+		self.requested[id as usize].swap(false, Ordering::SeqCst)
+	}
+
+	/// Records the stack `frames` of vCPU `id`, beginning with the RIP.
+	pub fn record(&self, id: u32, frames: Vec<u64>) {
+		let sample = Sample {
+			cpu: id,
+			time: self.start.elapsed(),
+			frames,
+		};
+		self.samples.lock().unwrap().push(sample);
+	}
+
+	fn request_samples(&self) {
+		for requested in self.requested.iter() {
+			requested.store(true, Ordering::SeqCst);
+		}
+	}
+
+	/// Returns the name of the function, which contains the `index`th frame
+	/// `addr` of a stack.
+	fn function(symbols: &Symbols, index: usize, addr: u64) -> Option<&str> {
+		// return addresses point behind the call
+		let addr = if index == 0 { addr } else { addr - 1 };
+		symbols.lookup(addr).map(|(name, _)| name)
+	}
+
+	/// Writes the samples as folded stacks: the functions of a stack from the
+	/// outermost to the innermost, separated by `;`, and the number of
+	/// samples with this stack.
+	fn write_folded(&self, symbols: &Symbols, out: &mut dyn Write) -> io::Result<()> {
+		let mut stacks = BTreeMap::new();
+		for sample in self.samples.lock().unwrap().iter() {
+			let stack = sample
+				.frames
+				.iter()
+				.enumerate()
+				.rev()
+				.map(
+					|(index, addr)| match Profiler::function(symbols, index, *addr) {
+						Some(name) => name.to_string(),
+						None => format!("0x{:x}", addr),
+					},
+				)
+				.collect::<Vec<_>>()
+				.join(";");
+			*stacks.entry(stack).or_insert(0u64) += 1;
+		}
+
+		for (stack, count) in stacks.iter() {
+			writeln!(out, "{} {}", stack, count)?;
+		}
+		Ok(())
+	}
+
+	/// Writes the samples like `perf script`, e.g. for `stackcollapse-perf.pl`
+	/// or the Firefox Profiler.
+	fn write_perf_script(
+		&self,
+		symbols: &Symbols,
+		kernel: &Path,
+		out: &mut dyn Write,
+	) -> io::Result<()> {
+		let pid = std::process::id();
+		let period = 1_000_000_000 / SAMPLE_FREQUENCY;
+		for sample in self.samples.lock().unwrap().iter() {
+			writeln!(
+				out,
+				"uhyve {}/{} [{:03}] {}.{:06}: {} cpu-clock:",
+				pid,
+				sample.cpu,
+				sample.cpu,
+				sample.time.as_secs(),
+				sample.time.subsec_micros(),
+				period
+			)?;
+			for (index, addr) in sample.frames.iter().enumerate() {
+				let lookup = if index == 0 { *addr } else { addr - 1 };
+				match symbols.lookup(lookup) {
+					Some((name, offset)) => writeln!(
+						out,
+						"\t{:16x} {}+0x{:x} ({})",
+						addr,
+						name,
+						offset + addr - lookup,
+						kernel.display()
+					)?,
+					None => writeln!(out, "\t{:16x} [unknown] ({})", addr, kernel.display())?,
+				}
+			}
+			writeln!(out)?;
+		}
+		Ok(())
+	}
+
+	/// Writes the profile of the kernel `kernel`, whose functions are
+	/// `symbols`.
+	pub fn write(&self, symbols: &Symbols, kernel: &Path) -> io::Result<()> {
+		let mut folded = BufWriter::new(File::create(&self.path)?);
+		self.write_folded(symbols, &mut folded)?;
+		folded.flush()?;
+
+		let perf_path = self.path.with_extension(PERF_SCRIPT_EXTENSION);
+		let mut perf_script = BufWriter::new(File::create(&perf_path)?);
+		self.write_perf_script(symbols, kernel, &mut perf_script)?;
+		perf_script.flush()?;
+
+		info!(
+			"Wrote {} samples to {} and {}",
+			self.samples.lock().unwrap().len(),
+			self.path.display(),
+			perf_path.display()
+		);
+		Ok(())
+	}
+}
+
+/// Starts the profiler thread of `vm`, if profiling is enabled. The thread
+/// writes the profile after the VM has stopped and finishes.
+pub fn spawn_profiler_thread(vm: Arc<Uhyve>) -> Option<JoinHandle<()>> {
+	let profiler = vm.profiler()?;
+
+	Some(thread::spawn(move || {
+		let period = Duration::from_secs(1) / SAMPLE_FREQUENCY;
+		kick::sample_periodically(&*vm, period, || profiler.request_samples(), || true);
+
+		debug!("Write the profile to {}", profiler.path.display());
+		let kernel = vm.kernel_path();
+		let symbols = Symbols::load(&kernel, vm.kernel_base()).unwrap_or_else(|err| {
+			warn!("Unable to read the symbols of the kernel: {}", err);
+			Symbols::default()
+		});
+		if let Err(err) = profiler.write(&symbols, &kernel) {
+			error!(
+				"Unable to write the profile to {}: {}",
+				profiler.path.display(),
+				err
+			);
+		}
+	}))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn create_profiler() -> (Profiler, Symbols) {
+		let symbols = vec![
+			(0x1000, 0x1000, "main".to_string()),
+			(0x2000, 0x100, "compute".to_string()),
+		]
+		.into_iter()
+		.collect::<Symbols>();
+
+		let profiler = Profiler::new(2, PathBuf::from("profile.folded"));
+		profiler.record(0, vec![0x2010, 0x1020]);
+		profiler.record(1, vec![0x2010, 0x1020]);
+		// the return address 0x2000 belongs to the call at the end of main
+		profiler.record(0, vec![0x3000, 0x2000]);
+		(profiler, symbols)
+	}
+
+	#[test]
+	fn test_sample_requested() {
+		let profiler = Profiler::new(2, PathBuf::from("profile.folded"));
+		assert!(!profiler.sample_requested(0));
+		profiler.request_samples();
+		assert!(profiler.sample_requested(0));
+		assert!(!profiler.sample_requested(0));
+		assert!(profiler.sample_requested(1));
+	}
+
+	#[test]
+	fn test_folded() {
+		let (profiler, symbols) = create_profiler();
+		let mut out = Vec::new();
+		profiler.write_folded(&symbols, &mut out).unwrap();
+		assert_eq!(
+			String::from_utf8(out).unwrap(),
+			"main;0x3000 1\nmain;compute 2\n"
+		);
+	}
+
+	#[test]
+	fn test_perf_script() {
+		let (profiler, symbols) = create_profiler();
+		let mut out = Vec::new();
+		profiler
+			.write_perf_script(&symbols, Path::new("/kernel"), &mut out)
+			.unwrap();
+		let out = String::from_utf8(out).unwrap();
+		let samples = out.split("\n\n").collect::<Vec<_>>();
+		assert_eq!(samples.len(), 4);
+		assert!(samples[0].starts_with("uhyve "));
+		assert!(samples[0].contains(" [000] "));
+		assert!(samples[0].ends_with(&format!(
+			"{} cpu-clock:\n\t{:16x} compute+0x10 (/kernel)\n\t{:16x} main+0x20 (/kernel)",
+			1_000_000_000 / SAMPLE_FREQUENCY,
+			0x2010,
+			0x1020
+		)));
+		assert!(samples[1].contains(" [001] "));
+		assert!(samples[2].ends_with(&format!(
+			"\t{:16x} [unknown] (/kernel)\n\t{:16x} main+0x1000 (/kernel)",
+			0x3000, 0x2000
+		)));
+	}
+}
@@ -0,0 +1,643 @@
+//! Emulation of the MC146818 real-time clock of the CMOS.
+//!
+//! The clock of the guest advances with the host clock, but starts at the
+//! host time or at a configured time. Writes of the guest to the time
+//! registers move the clock of the guest. A separate thread raises the
+//! periodic, alarm and update-ended interrupts, while the guest has enabled
+//! one of them.
+
+use crate::consts::*;
+use crate::error::*;
+use crate::utils::{civil_from_days, days_from_civil};
+use log::warn;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Once};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use vmm_sys_util::eventfd::EventFd;
+
+/// Registers of the RTC
+const REG_SECONDS: u8 = 0x00;
+const REG_ALARM_SECONDS: u8 = 0x01;
+const REG_MINUTES: u8 = 0x02;
+const REG_ALARM_MINUTES: u8 = 0x03;
+const REG_HOURS: u8 = 0x04;
+const REG_ALARM_HOURS: u8 = 0x05;
+const REG_WEEKDAY: u8 = 0x06;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_A: u8 = 0x0a;
+const REG_B: u8 = 0x0b;
+const REG_C: u8 = 0x0c;
+const REG_D: u8 = 0x0d;
+const REG_CENTURY: u8 = CMOS_CENTURY_REG;
+
+/// Update in progress
+const A_UIP: u8 = 0x80;
+/// 32.768 kHz time base, periodic interrupt with 1024 Hz
+const A_DEFAULT: u8 = 0x26;
+const A_RATE: u8 = 0x0f;
+
+/// The guest sets the time, updates are inhibited
+const B_SET: u8 = 0x80;
+const B_PIE: u8 = 0x40;
+const B_AIE: u8 = 0x20;
+const B_UIE: u8 = 0x10;
+const B_BINARY: u8 = 0x04;
+const B_24H: u8 = 0x02;
+const B_INTERRUPTS: u8 = B_PIE | B_AIE | B_UIE;
+
+const C_IRQF: u8 = 0x80;
+const C_PF: u8 = 0x40;
+const C_AF: u8 = 0x20;
+const C_UF: u8 = 0x10;
+
+/// Valid RAM and time
+const D_VRT: u8 = 0x80;
+
+/// Alarm values of this size match any time
+const ALARM_DONT_CARE: u8 = 0xc0;
+
+/// UIP is set for this time before each update of the time registers.
+const UPDATE_CYCLE: Duration = Duration::from_micros(244);
+
+/// Broken-down time of the RTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DateTime {
+	year: i64,
+	month: u32,
+	day: u32,
+	hour: u32,
+	minute: u32,
+	second: u32,
+}
+
+impl DateTime {
+	fn from_secs(secs: u64) -> Self {
+		let (year, month, day) = civil_from_days((secs / 86400) as i64);
+		let secs_of_day = (secs % 86400) as u32;
+		DateTime {
+			year,
+			month,
+			day,
+			hour: secs_of_day / 3600,
+			minute: secs_of_day / 60 % 60,
+			second: secs_of_day % 60,
+		}
+	}
+
+	/// Returns the seconds since the epoch. Times before the epoch are
+	/// clamped to the epoch.
+	fn to_secs(self) -> u64 {
+		let days = days_from_civil(self.year, self.month, self.day);
+		let secs = days * 86400
+			+ i64::from(self.hour) * 3600
+			+ i64::from(self.minute) * 60
+			+ i64::from(self.second);
+		secs.max(0) as u64
+	}
+
+	/// Day of the week, Sunday is 1
+	fn weekday(self) -> u32 {
+		// 1970-01-01 was a Thursday
+		((days_from_civil(self.year, self.month, self.day) + 4).rem_euclid(7) + 1) as u32
+	}
+}
+
+/// State of the RTC, which is stored in a snapshot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RtcState {
+	pub index: u8,
+	pub cmos: Vec<u8>,
+	/// Time of the guest since the epoch
+	pub time: Duration,
+	/// Time of the host since the epoch, when the state was saved
+	pub host_time: Duration,
+	/// Time in seconds since the epoch, which the guest has written while
+	/// updates are inhibited
+	pub latched: Option<u64>,
+}
+
+struct Cmos {
+	/// Selected register
+	index: u8,
+	cmos: [u8; 128],
+	/// Time of the guest (since the epoch) at `origin`
+	origin_time: Duration,
+	origin: Instant,
+	/// Time, which the guest writes while `B_SET` is set
+	latched: Option<DateTime>,
+	/// Second of the last update of the time registers
+	last_update: u64,
+	next_periodic: Option<Instant>,
+	/// Set when the VM shuts down to terminate the timer thread
+	stopped: bool,
+}
+
+impl Cmos {
+	fn now(&self) -> Duration {
+		self.origin_time + self.origin.elapsed()
+	}
+
+	fn date_time(&self) -> DateTime {
+		self.latched
+			.unwrap_or_else(|| DateTime::from_secs(self.now().as_secs()))
+	}
+
+	fn set_date_time(&mut self, date_time: DateTime) {
+		let now = self.now();
+		self.origin = Instant::now();
+		self.origin_time = Duration::new(date_time.to_secs(), now.subsec_nanos());
+		self.last_update = self.origin_time.as_secs();
+	}
+
+	fn encode(&self, value: u32) -> u8 {
+		if self.cmos[REG_B as usize] & B_BINARY != 0 {
+			value as u8
+		} else {
+			(((value / 10) << 4) | (value % 10)) as u8
+		}
+	}
+
+	fn decode(&self, value: u8) -> u32 {
+		if self.cmos[REG_B as usize] & B_BINARY != 0 {
+			u32::from(value)
+		} else {
+			u32::from(value >> 4) * 10 + u32::from(value & 0x0f)
+		}
+	}
+
+	fn encode_hour(&self, hour: u32) -> u8 {
+		if self.cmos[REG_B as usize] & B_24H != 0 {
+			self.encode(hour)
+		} else {
+			let pm = if hour >= 12 { 0x80 } else { 0 };
+			let hour = if hour % 12 == 0 { 12 } else { hour % 12 };
+			self.encode(hour) | pm
+		}
+	}
+
+	fn decode_hour(&self, value: u8) -> u32 {
+		if self.cmos[REG_B as usize] & B_24H != 0 {
+			self.decode(value)
+		} else {
+			let hour = self.decode(value & 0x7f) % 12;
+			if value & 0x80 != 0 {
+				hour + 12
+			} else {
+				hour
+			}
+		}
+	}
+
+	/// Period of the periodic interrupt
+	fn period(&self) -> Option<Duration> {
+		match self.cmos[REG_A as usize] & A_RATE {
+			0 => None,
+			1 => Some(Duration::from_nanos(1_000_000_000 / 256)),
+			2 => Some(Duration::from_nanos(1_000_000_000 / 128)),
+			rate => Some(Duration::from_nanos(1_000_000_000 / (32768 >> (rate - 1)))),
+		}
+	}
+
+	fn alarm_matches(&self, date_time: DateTime) -> bool {
+		[
+			(REG_ALARM_SECONDS, self.encode(date_time.second)),
+			(REG_ALARM_MINUTES, self.encode(date_time.minute)),
+			(REG_ALARM_HOURS, self.encode_hour(date_time.hour)),
+		]
+		.iter()
+		.all(|(reg, value)| {
+			let alarm = self.cmos[*reg as usize];
+			alarm >= ALARM_DONT_CARE || alarm == *value
+		})
+	}
+
+	fn read(&mut self, reg: u8) -> u8 {
+		let date_time = self.date_time();
+		match reg {
+			REG_SECONDS => self.encode(date_time.second),
+			REG_MINUTES => self.encode(date_time.minute),
+			REG_HOURS => self.encode_hour(date_time.hour),
+			REG_WEEKDAY => self.encode(date_time.weekday()),
+			REG_DAY => self.encode(date_time.day),
+			REG_MONTH => self.encode(date_time.month),
+			REG_YEAR => self.encode(date_time.year.rem_euclid(100) as u32),
+			REG_CENTURY => self.encode(date_time.year.div_euclid(100) as u32),
+			REG_A => {
+				let uip = self.cmos[REG_B as usize] & B_SET == 0
+					&& self.now().subsec_nanos()
+						>= (Duration::from_secs(1) - UPDATE_CYCLE).subsec_nanos();
+				self.cmos[REG_A as usize] | if uip { A_UIP } else { 0 }
+			}
+			REG_C => {
+				let flags = self.cmos[REG_C as usize];
+				self.cmos[REG_C as usize] = 0;
+				flags
+			}
+			_ => self.cmos[reg as usize],
+		}
+	}
+
+	/// Writes `value` into `reg`. Returns true if the interrupts have changed.
+	fn write(&mut self, reg: u8, value: u8) -> bool {
+		let mut date_time = self.date_time();
+		match reg {
+			REG_SECONDS => date_time.second = self.decode(value),
+			REG_MINUTES => date_time.minute = self.decode(value),
+			REG_HOURS => date_time.hour = self.decode_hour(value),
+			REG_DAY => date_time.day = self.decode(value),
+			REG_MONTH => date_time.month = self.decode(value),
+			REG_YEAR => {
+				date_time.year =
+					date_time.year.div_euclid(100) * 100 + i64::from(self.decode(value))
+			}
+			REG_CENTURY => {
+				date_time.year =
+					i64::from(self.decode(value)) * 100 + date_time.year.rem_euclid(100)
+			}
+			// the weekday is derived from the date
+			REG_WEEKDAY => return false,
+			REG_A => {
+				self.cmos[REG_A as usize] = value & !A_UIP;
+				self.next_periodic = None;
+				return true;
+			}
+			REG_B => {
+				if value & B_SET != 0 && self.latched.is_none() {
+					self.latched = Some(date_time);
+				} else if value & B_SET == 0 {
+					if let Some(latched) = self.latched.take() {
+						self.set_date_time(latched);
+					}
+				}
+				self.cmos[REG_B as usize] = value;
+				return true;
+			}
+			REG_C | REG_D => return false,
+			_ => {
+				self.cmos[reg as usize] = value;
+				return false;
+			}
+		}
+
+		if self.latched.is_some() {
+			self.latched = Some(date_time);
+		} else {
+			self.set_date_time(date_time);
+		}
+		false
+	}
+
+	/// Updates the flags of the interrupts, which have occurred until `now`.
+	/// Returns true if an enabled interrupt has occurred.
+	fn update(&mut self, now: Instant) -> bool {
+		let b = self.cmos[REG_B as usize];
+		let mut flags = 0;
+
+		match (b & B_PIE != 0, self.period()) {
+			(true, Some(period)) => {
+				let next = self.next_periodic.get_or_insert(now + period);
+				if *next <= now {
+					flags |= C_PF;
+					*next = (*next + period).max(now);
+				}
+			}
+			_ => self.next_periodic = None,
+		}
+
+		let secs = self.now().as_secs();
+		if secs != self.last_update {
+			self.last_update = secs;
+			if b & B_SET == 0 {
+				flags |= C_UF;
+				if self.alarm_matches(DateTime::from_secs(secs)) {
+					flags |= C_AF;
+				}
+			}
+		}
+
+		self.cmos[REG_C as usize] |= flags;
+		if flags & b & B_INTERRUPTS != 0 {
+			self.cmos[REG_C as usize] |= C_IRQF;
+			true
+		} else {
+			false
+		}
+	}
+
+	/// Returns the time until the next interrupt.
+	fn timeout(&self, now: Instant) -> Duration {
+		let next_second =
+			Duration::from_secs(1) - Duration::from_nanos(self.now().subsec_nanos().into());
+		match self.next_periodic {
+			Some(next) => next_second.min(next.saturating_duration_since(now)),
+			None => next_second,
+		}
+	}
+}
+
+pub struct Rtc {
+	state: Mutex<Cmos>,
+	cond: Condvar,
+	interrupt: EventFd,
+	timer_started: Once,
+	/// Thread, which raises the interrupts, until it is joined by `stop`
+	timer: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Rtc {
+	/// Creates an RTC, which starts at `start` and raises its interrupt by
+	/// writing to `interrupt`.
+	pub fn new(start: SystemTime, interrupt: EventFd) -> Self {
+		let origin_time = start.duration_since(UNIX_EPOCH).unwrap_or_default();
+		let mut cmos = [0u8; 128];
+		cmos[REG_A as usize] = A_DEFAULT;
+		cmos[REG_B as usize] = B_24H;
+		cmos[REG_D as usize] = D_VRT;
+
+		Rtc {
+			state: Mutex::new(Cmos {
+				index: 0,
+				cmos,
+				origin_time,
+				origin: Instant::now(),
+				latched: None,
+				last_update: origin_time.as_secs(),
+				next_periodic: None,
+				stopped: false,
+			}),
+			cond: Condvar::new(),
+			interrupt,
+			timer_started: Once::new(),
+			timer: Mutex::new(None),
+		}
+	}
+
+	/// Returns the state of the RTC, which is stored in a snapshot.
+	pub fn save_state(&self) -> RtcState {
+		let state = self.state.lock().unwrap();
+		RtcState {
+			index: state.index,
+			cmos: state.cmos.to_vec(),
+			time: state.now(),
+			host_time: SystemTime::now()
+				.duration_since(UNIX_EPOCH)
+				.unwrap_or_default(),
+			latched: state.latched.map(DateTime::to_secs),
+		}
+	}
+
+	/// Restores the state of the RTC from a snapshot. The clock of the guest
+	/// keeps its offset to the clock of the host.
+	pub fn restore_state(self: &Arc<Self>, saved: &RtcState) -> Result<()> {
+		if saved.cmos.len() != 128 {
+			return Err(Error::Snapshot(format!(
+				"invalid RTC state with {} bytes of CMOS",
+				saved.cmos.len()
+			)));
+		}
+
+		let elapsed = SystemTime::now()
+			.duration_since(UNIX_EPOCH + saved.host_time)
+			.unwrap_or_default();
+		let mut state = self.state.lock().unwrap();
+		state.index = saved.index;
+		state.cmos.copy_from_slice(&saved.cmos);
+		state.origin = Instant::now();
+		state.origin_time = saved.time + elapsed;
+		state.latched = saved.latched.map(DateTime::from_secs);
+		state.last_update = state.origin_time.as_secs();
+		state.next_periodic = None;
+
+		if state.cmos[REG_B as usize] & B_INTERRUPTS != 0 {
+			self.start_timer();
+		}
+		self.cond.notify_all();
+
+		Ok(())
+	}
+
+	/// Reads from the index (`CMOS_INDEX_PORT`) or data port.
+	pub fn read(&self, port: u16) -> u8 {
+		let mut state = self.state.lock().unwrap();
+		if port == CMOS_INDEX_PORT {
+			state.index
+		} else {
+			let index = state.index;
+			state.read(index)
+		}
+	}
+
+	/// Writes to the index (`CMOS_INDEX_PORT`) or data port.
+	pub fn write(self: &Arc<Self>, port: u16, value: u8) {
+		let mut state = self.state.lock().unwrap();
+		if port == CMOS_INDEX_PORT {
+			// bit 7 disables the NMI
+			state.index = value & 0x7f;
+			return;
+		}
+
+		let index = state.index;
+		if state.write(index, value) {
+			if state.cmos[REG_B as usize] & B_INTERRUPTS != 0 {
+				self.start_timer();
+			}
+			self.cond.notify_all();
+		}
+	}
+
+	/// Stops the thread, which raises the interrupts, and waits until it has
+	/// finished. The RTC doesn't raise any interrupts afterwards.
+	pub fn stop(&self) {
+		self.state.lock().unwrap().stopped = true;
+		self.cond.notify_all();
+
+		let timer = self.timer.lock().unwrap().take();
+		if let Some(timer) = timer {
+			if timer.join().is_err() {
+				warn!("The timer thread of the RTC has panicked");
+			}
+		}
+	}
+
+	fn raise_interrupt(&self) {
+		if let Err(err) = self.interrupt.write(1) {
+			warn!("Unable to raise the RTC interrupt: {}", err);
+		}
+	}
+
+	/// Starts the thread, which raises the interrupts.
+	fn start_timer(self: &Arc<Self>) {
+		self.timer_started.call_once(|| {
+			let rtc = self.clone();
+			let timer = thread::spawn(move || {
+				let mut state = rtc.state.lock().unwrap();
+				loop {
+					state = rtc.wait_for_interrupts(state);
+					if state.stopped {
+						break;
+					}
+					let now = Instant::now();
+					if state.update(now) {
+						rtc.raise_interrupt();
+					}
+					let timeout = state.timeout(now);
+					state = rtc.cond.wait_timeout(state, timeout).unwrap().0;
+				}
+			});
+			*self.timer.lock().unwrap() = Some(timer);
+		});
+	}
+
+	/// Blocks while the guest has disabled all interrupts and the RTC isn't
+	/// stopped.
+	fn wait_for_interrupts<'a>(&self, mut state: MutexGuard<'a, Cmos>) -> MutexGuard<'a, Cmos> {
+		while state.cmos[REG_B as usize] & B_INTERRUPTS == 0 && !state.stopped {
+			state.next_periodic = None;
+			state = self.cond.wait(state).unwrap();
+		}
+		state
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn create_rtc(start: u64) -> Arc<Rtc> {
+		let interrupt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+		Arc::new(Rtc::new(UNIX_EPOCH + Duration::from_secs(start), interrupt))
+	}
+
+	fn read_reg(rtc: &Arc<Rtc>, reg: u8) -> u8 {
+		rtc.write(CMOS_INDEX_PORT, reg);
+		rtc.read(CMOS_DATA_PORT)
+	}
+
+	fn write_reg(rtc: &Arc<Rtc>, reg: u8, value: u8) {
+		rtc.write(CMOS_INDEX_PORT, reg);
+		rtc.write(CMOS_DATA_PORT, value);
+	}
+
+	#[test]
+	fn test_date_time() {
+		// 2021-06-15 12:30:45, a Tuesday
+		let date_time = DateTime::from_secs(1_623_760_245);
+		assert_eq!(
+			date_time,
+			DateTime {
+				year: 2021,
+				month: 6,
+				day: 15,
+				hour: 12,
+				minute: 30,
+				second: 45
+			}
+		);
+		assert_eq!(date_time.weekday(), 3);
+		assert_eq!(date_time.to_secs(), 1_623_760_245);
+	}
+
+	#[test]
+	fn test_read_time() {
+		// 2021-06-15 23:59:58
+		let rtc = create_rtc(1_623_801_598);
+		assert_eq!(read_reg(&rtc, REG_D), D_VRT);
+
+		// BCD and 24 hours
+		assert_eq!(read_reg(&rtc, REG_HOURS), 0x23);
+		assert_eq!(read_reg(&rtc, REG_MINUTES), 0x59);
+		assert_eq!(read_reg(&rtc, REG_DAY), 0x15);
+		assert_eq!(read_reg(&rtc, REG_MONTH), 0x06);
+		assert_eq!(read_reg(&rtc, REG_YEAR), 0x21);
+		assert_eq!(read_reg(&rtc, REG_CENTURY), 0x20);
+		assert_eq!(read_reg(&rtc, REG_WEEKDAY), 0x03);
+
+		// binary and 12 hours
+		write_reg(&rtc, REG_B, B_BINARY);
+		assert_eq!(read_reg(&rtc, REG_HOURS), 0x80 | 11);
+		assert_eq!(read_reg(&rtc, REG_MINUTES), 59);
+	}
+
+	#[test]
+	fn test_set_time() {
+		let rtc = create_rtc(1_623_801_598);
+
+		// set 2020-02-29 08:00:00 with inhibited updates
+		write_reg(&rtc, REG_B, B_SET | B_24H);
+		write_reg(&rtc, REG_SECONDS, 0x00);
+		write_reg(&rtc, REG_MINUTES, 0x00);
+		write_reg(&rtc, REG_HOURS, 0x08);
+		write_reg(&rtc, REG_DAY, 0x29);
+		write_reg(&rtc, REG_MONTH, 0x02);
+		write_reg(&rtc, REG_YEAR, 0x20);
+		write_reg(&rtc, REG_CENTURY, 0x20);
+		write_reg(&rtc, REG_B, B_24H);
+
+		assert_eq!(read_reg(&rtc, REG_DAY), 0x29);
+		assert_eq!(read_reg(&rtc, REG_MONTH), 0x02);
+		assert_eq!(read_reg(&rtc, REG_YEAR), 0x20);
+		assert_eq!(read_reg(&rtc, REG_HOURS), 0x08);
+		let secs = rtc.state.lock().unwrap().now().as_secs();
+		assert!((1_582_963_200..1_582_963_210).contains(&secs));
+	}
+
+	#[test]
+	fn test_interrupts() {
+		let rtc = create_rtc(0);
+		let now = Instant::now();
+		{
+			let mut state = rtc.state.lock().unwrap();
+			state.write(REG_B, B_24H | B_UIE | B_AIE);
+			state.write(REG_ALARM_SECONDS, ALARM_DONT_CARE);
+			state.write(REG_ALARM_MINUTES, ALARM_DONT_CARE);
+			state.write(REG_ALARM_HOURS, ALARM_DONT_CARE);
+
+			// no update within the same second
+			assert!(!state.update(now));
+			state.origin_time += Duration::from_secs(1);
+			assert!(state.update(now));
+			assert_eq!(state.read(REG_C), C_IRQF | C_UF | C_AF);
+			assert_eq!(state.read(REG_C), 0);
+
+			// periodic interrupt with 2 Hz
+			state.write(REG_B, B_24H | B_PIE);
+			state.write(REG_A, 0x20 | 0x0f);
+			assert_eq!(state.period(), Some(Duration::from_millis(500)));
+			assert!(!state.update(now));
+			assert!(state.update(now + Duration::from_millis(500)));
+			assert_eq!(state.read(REG_C), C_IRQF | C_PF);
+		}
+
+		// the timer raises the periodic interrupt
+		write_reg(&rtc, REG_A, 0x20 | 0x03);
+		thread::sleep(Duration::from_millis(50));
+		assert!(rtc.interrupt.read().unwrap() > 0);
+		write_reg(&rtc, REG_B, B_24H);
+
+		// the timer thread has released the RTC, after it is stopped
+		rtc.stop();
+		assert_eq!(Arc::strong_count(&rtc), 1);
+	}
+
+	#[test]
+	fn test_state() {
+		let rtc = create_rtc(1_623_801_598);
+		write_reg(&rtc, REG_B, B_BINARY | B_24H);
+		write_reg(&rtc, 0x40, 0x42);
+		let mut state = rtc.save_state();
+		// the snapshot was taken 10 seconds ago
+		state.host_time -= Duration::from_secs(10);
+
+		let restored = create_rtc(0);
+		restored.restore_state(&state).unwrap();
+		assert_eq!(read_reg(&restored, 0x40), 0x42);
+		assert_eq!(read_reg(&restored, REG_YEAR), 21);
+		let secs = restored.state.lock().unwrap().now().as_secs();
+		assert!((1_623_801_608..1_623_801_618).contains(&secs));
+
+		state.cmos.pop();
+		assert!(restored.restore_state(&state).is_err());
+	}
+}
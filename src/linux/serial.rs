@@ -0,0 +1,332 @@
+//! Emulation of a 16550 UART, which is connected to the console.
+//!
+//! The transmitter is always ready, because the output is written
+//! synchronously to the console. The UART raises its interrupt by an irqfd
+//! whenever received data or an empty transmitter holding register becomes
+//! pending while the corresponding interrupt is enabled.
+
+use crate::console::Console;
+use log::warn;
+use std::sync::Arc;
+use vmm_sys_util::eventfd::EventFd;
+
+/// Register offsets
+const DATA: u16 = 0;
+const IER: u16 = 1;
+const IIR_FCR: u16 = 2;
+const LCR: u16 = 3;
+const MCR: u16 = 4;
+const LSR: u16 = 5;
+const MSR: u16 = 6;
+const SCR: u16 = 7;
+
+const IER_RX_DATA: u8 = 0x01;
+const IER_THR_EMPTY: u8 = 0x02;
+const IER_MASK: u8 = 0x0f;
+
+const IIR_NO_INTERRUPT: u8 = 0x01;
+const IIR_THR_EMPTY: u8 = 0x02;
+const IIR_RX_DATA: u8 = 0x04;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+const FCR_FIFO_ENABLE: u8 = 0x01;
+
+const LCR_DLAB: u8 = 0x80;
+
+const MCR_LOOPBACK: u8 = 0x10;
+
+const LSR_DATA_READY: u8 = 0x01;
+const LSR_THR_EMPTY: u8 = 0x20;
+const LSR_TRANSMITTER_EMPTY: u8 = 0x40;
+
+/// Carrier detect, data set ready and clear to send
+const MSR_CONNECTED: u8 = 0xb0;
+
+/// State of the UART, which is stored in a snapshot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialState {
+	pub ier: u8,
+	pub lcr: u8,
+	pub mcr: u8,
+	pub fcr: u8,
+	pub scr: u8,
+	pub divisor: u16,
+	pub thr_empty_pending: bool,
+	pub loopback: Option<u8>,
+}
+
+pub struct Serial {
+	console: Arc<Console>,
+	interrupt: EventFd,
+	ier: u8,
+	lcr: u8,
+	mcr: u8,
+	fcr: u8,
+	scr: u8,
+	divisor: u16,
+	/// The guest hasn't yet read the THR empty interrupt from IIR.
+	thr_empty_pending: bool,
+	/// Byte, which the guest has sent in loopback mode
+	loopback: Option<u8>,
+}
+
+impl Serial {
+	/// Creates a UART, which raises its interrupt by writing to `interrupt`.
+	pub fn new(console: Arc<Console>, interrupt: EventFd) -> Self {
+		Serial {
+			console,
+			interrupt,
+			ier: 0,
+			lcr: 0x03,
+			mcr: 0x08,
+			fcr: 0,
+			scr: 0,
+			divisor: 12,
+			thr_empty_pending: false,
+			loopback: None,
+		}
+	}
+
+	/// Returns the state of the UART, which is stored in a snapshot.
+	pub fn save_state(&self) -> SerialState {
+		SerialState {
+			ier: self.ier,
+			lcr: self.lcr,
+			mcr: self.mcr,
+			fcr: self.fcr,
+			scr: self.scr,
+			divisor: self.divisor,
+			thr_empty_pending: self.thr_empty_pending,
+			loopback: self.loopback,
+		}
+	}
+
+	/// Restores the state of the UART from a snapshot.
+	pub fn restore_state(&mut self, state: &SerialState) {
+		self.ier = state.ier;
+		self.lcr = state.lcr;
+		self.mcr = state.mcr;
+		self.fcr = state.fcr;
+		self.scr = state.scr;
+		self.divisor = state.divisor;
+		self.thr_empty_pending = state.thr_empty_pending;
+		self.loopback = state.loopback;
+	}
+
+	fn dlab(&self) -> bool {
+		self.lcr & LCR_DLAB != 0
+	}
+
+	fn has_rx_data(&self) -> bool {
+		if self.mcr & MCR_LOOPBACK != 0 {
+			self.loopback.is_some()
+		} else {
+			self.console.has_input()
+		}
+	}
+
+	fn iir(&self) -> u8 {
+		let fifo = if self.fcr & FCR_FIFO_ENABLE != 0 {
+			IIR_FIFO_ENABLED
+		} else {
+			0
+		};
+
+		if self.ier & IER_RX_DATA != 0 && self.has_rx_data() {
+			fifo | IIR_RX_DATA
+		} else if self.ier & IER_THR_EMPTY != 0 && self.thr_empty_pending {
+			fifo | IIR_THR_EMPTY
+		} else {
+			fifo | IIR_NO_INTERRUPT
+		}
+	}
+
+	fn raise_interrupt(&self) {
+		if let Err(err) = self.interrupt.write(1) {
+			warn!("Unable to raise the UART interrupt: {}", err);
+		}
+	}
+
+	/// Reads the register at `offset` from the base port.
+	pub fn read(&mut self, offset: u16) -> u8 {
+		self.console.start_input();
+
+		match offset {
+			DATA if self.dlab() => self.divisor as u8,
+			DATA => {
+				let byte = if self.mcr & MCR_LOOPBACK != 0 {
+					self.loopback.take()
+				} else {
+					self.console.read_byte()
+				};
+				if self.ier & IER_RX_DATA != 0 && self.has_rx_data() {
+					self.raise_interrupt();
+				}
+				byte.unwrap_or(0)
+			}
+			IER if self.dlab() => (self.divisor >> 8) as u8,
+			IER => self.ier,
+			IIR_FCR => {
+				let iir = self.iir();
+				if iir & 0x0f == IIR_THR_EMPTY {
+					self.thr_empty_pending = false;
+				}
+				iir
+			}
+			LCR => self.lcr,
+			MCR => self.mcr,
+			LSR => {
+				let mut lsr = LSR_THR_EMPTY | LSR_TRANSMITTER_EMPTY;
+				if self.has_rx_data() {
+					lsr |= LSR_DATA_READY;
+				}
+				lsr
+			}
+			MSR if self.mcr & MCR_LOOPBACK != 0 => {
+				// DTR, RTS, OUT1 and OUT2 are connected to DSR, CTS, RI and DCD
+				((self.mcr & 0x02) << 3)
+					| ((self.mcr & 0x01) << 5)
+					| ((self.mcr & 0x04) << 4)
+					| ((self.mcr & 0x08) << 4)
+			}
+			MSR => MSR_CONNECTED,
+			SCR => self.scr,
+			_ => 0xff,
+		}
+	}
+
+	/// Writes `value` into the register at `offset` from the base port.
+	pub fn write(&mut self, offset: u16, value: u8) {
+		self.console.start_input();
+
+		match offset {
+			DATA if self.dlab() => self.divisor = (self.divisor & 0xff00) | u16::from(value),
+			DATA => {
+				if self.mcr & MCR_LOOPBACK != 0 {
+					self.loopback = Some(value);
+				} else if let Err(err) = self.console.write(&[value]) {
+					warn!("Unable to write to the console: {}", err);
+				}
+				self.thr_empty_pending = true;
+				if self.ier != 0 && self.iir() & IIR_NO_INTERRUPT == 0 {
+					self.raise_interrupt();
+				}
+			}
+			IER if self.dlab() => self.divisor = (self.divisor & 0x00ff) | (u16::from(value) << 8),
+			IER => {
+				let enabled = value & !self.ier;
+				self.ier = value & IER_MASK;
+				if enabled & IER_THR_EMPTY != 0 {
+					self.thr_empty_pending = true;
+				}
+				if enabled != 0 && self.iir() & IIR_NO_INTERRUPT == 0 {
+					self.raise_interrupt();
+				}
+			}
+			IIR_FCR => self.fcr = value,
+			LCR => self.lcr = value,
+			MCR => self.mcr = value & 0x1f,
+			SCR => self.scr = value,
+			_ => {}
+		}
+	}
+
+	/// Raises the interrupt, if the guest waits for received data. Called
+	/// by the console when it has received input.
+	pub fn input_available(&mut self) {
+		if self.ier & IER_RX_DATA != 0 {
+			self.raise_interrupt();
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::console::ConsoleKind;
+	use std::fs;
+
+	fn create_serial(name: &str) -> (Serial, std::path::PathBuf) {
+		let path = std::env::temp_dir().join(format!("uhyve-{}-{}.log", name, std::process::id()));
+		let console = Arc::new(Console::new(&ConsoleKind::File(path.clone())).unwrap());
+		let interrupt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+		(Serial::new(console, interrupt), path)
+	}
+
+	fn interrupts(serial: &Serial) -> u64 {
+		serial.interrupt.read().unwrap_or(0)
+	}
+
+	#[test]
+	fn test_output() {
+		let (mut serial, path) = create_serial("serial-output");
+		assert_eq!(serial.read(LSR) & LSR_THR_EMPTY, LSR_THR_EMPTY);
+		for byte in b"hi" {
+			serial.write(DATA, *byte);
+		}
+		assert_eq!(fs::read(&path).unwrap(), b"hi");
+		assert_eq!(interrupts(&serial), 0);
+
+		// enabling the THR empty interrupt raises it immediately
+		serial.write(IER, IER_THR_EMPTY);
+		assert_eq!(interrupts(&serial), 1);
+		assert_eq!(serial.read(IIR_FCR), IIR_THR_EMPTY);
+		assert_eq!(serial.read(IIR_FCR), IIR_NO_INTERRUPT);
+		serial.write(DATA, b'!');
+		assert_eq!(interrupts(&serial), 1);
+		assert_eq!(fs::read(&path).unwrap(), b"hi!");
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn test_divisor() {
+		let (mut serial, path) = create_serial("serial-divisor");
+		serial.write(LCR, LCR_DLAB | 0x03);
+		serial.write(DATA, 0x01);
+		serial.write(IER, 0x02);
+		assert_eq!(serial.read(DATA), 0x01);
+		assert_eq!(serial.read(IER), 0x02);
+		serial.write(LCR, 0x03);
+		assert_eq!(serial.divisor, 0x0201);
+		assert_eq!(serial.read(IER), 0);
+		assert!(fs::read(&path).unwrap().is_empty());
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn test_loopback() {
+		let (mut serial, path) = create_serial("serial-loopback");
+		serial.write(IER, IER_RX_DATA);
+		serial.write(MCR, MCR_LOOPBACK | 0x0b);
+		assert_eq!(serial.read(MSR), 0xb0);
+		assert_eq!(serial.read(LSR) & LSR_DATA_READY, 0);
+
+		serial.write(DATA, 0x5a);
+		assert_eq!(interrupts(&serial), 1);
+		assert_eq!(serial.read(LSR) & LSR_DATA_READY, LSR_DATA_READY);
+		assert_eq!(serial.read(IIR_FCR), IIR_RX_DATA);
+		assert_eq!(serial.read(DATA), 0x5a);
+		assert_eq!(serial.read(LSR) & LSR_DATA_READY, 0);
+		assert!(fs::read(&path).unwrap().is_empty());
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn test_state() {
+		let (mut serial, path) = create_serial("serial-state");
+		serial.write(LCR, LCR_DLAB | 0x03);
+		serial.write(DATA, 0x01);
+		serial.write(LCR, 0x03);
+		serial.write(MCR, MCR_LOOPBACK);
+		serial.write(DATA, b'x');
+		let state = serial.save_state();
+
+		let (mut restored, restored_path) = create_serial("serial-state-restored");
+		restored.restore_state(&state);
+		assert_eq!(restored.save_state(), state);
+		assert_eq!(restored.divisor, 0x0001);
+		assert_eq!(restored.read(DATA), b'x');
+		fs::remove_file(&path).unwrap();
+		fs::remove_file(&restored_path).unwrap();
+	}
+}
@@ -0,0 +1,1143 @@
+//! Snapshots of a running VM, which can be restored by a new uhyve process on
+//! the same host.
+//!
+//! A snapshot file starts with a header and the serialized state of the VM,
+//! its vCPUs, devices and opened files. The guest memory follows page-aligned
+//! at the end of the file, where zero pages are left as holes.
+
+use crate::error::*;
+use crate::linux::kick::VcpuThreads;
+use crate::linux::memory::PAGE_SIZE;
+use crate::linux::rtc::RtcState;
+use crate::linux::serial::SerialState;
+use crate::linux::uhyve::Uhyve;
+use crate::linux::virtio::VirtioNetState;
+use crate::linux::KVM;
+use crate::vm::{CpuStartup, GuestFile, GuestFiles, Shutdown, SnapshotTrigger, Vm};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use kvm_bindings::*;
+use kvm_ioctls::{VcpuFd, VmFd};
+use log::{debug, error, info, warn};
+use nix::sys::signal::{pthread_sigmask, SigSet, SigmaskHow, Signal};
+use std::ffi::{CString, OsStr};
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use std::{mem, ptr, slice};
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"UHYVESNP";
+const SNAPSHOT_VERSION: u32 = 1;
+/// Size of magic, version and memory offset at the beginning of the file
+const SNAPSHOT_HEADER_SIZE: usize = 8 + 4 + 8;
+
+/// Signal, which triggers a snapshot with `--snapshot-on signal`
+pub const SNAPSHOT_SIGNAL: Signal = Signal::SIGUSR1;
+/// Interval, in which vCPUs are kicked until they are paused
+const KICK_INTERVAL: Duration = Duration::from_millis(10);
+/// Interval, in which the snapshot threads check whether the VM has stopped
+const STOP_INTERVAL: Duration = Duration::from_millis(100);
+
+fn io_error(err: io::Error) -> Error {
+	Error::OsError(err.raw_os_error().unwrap_or(libc::EIO))
+}
+
+fn invalid_snapshot(err: io::Error) -> Error {
+	Error::Snapshot(format!("invalid snapshot file: {}", err))
+}
+
+fn write_pod<T: Copy, W: Write>(w: &mut W, value: &T) -> io::Result<()> {
+	let bytes =
+		unsafe { slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) };
+	w.write_all(bytes)
+}
+
+/// Reads a plain data structure of KVM. `T` has to be valid for every bit pattern.
+unsafe fn read_pod<T: Copy, R: Read>(r: &mut R) -> io::Result<T> {
+	let mut value: T = mem::zeroed();
+	let bytes = slice::from_raw_parts_mut(&mut value as *mut T as *mut u8, mem::size_of::<T>());
+	r.read_exact(bytes)?;
+	Ok(value)
+}
+
+fn write_bytes<W: Write>(w: &mut W, bytes: &[u8]) -> io::Result<()> {
+	w.write_u32::<LittleEndian>(bytes.len() as u32)?;
+	w.write_all(bytes)
+}
+
+fn read_bytes<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+	let len = r.read_u32::<LittleEndian>()? as usize;
+	let mut bytes = vec![0; len];
+	r.read_exact(&mut bytes)?;
+	Ok(bytes)
+}
+
+fn write_duration<W: Write>(w: &mut W, duration: Duration) -> io::Result<()> {
+	w.write_u64::<LittleEndian>(duration.as_secs())?;
+	w.write_u32::<LittleEndian>(duration.subsec_nanos())
+}
+
+fn read_duration<R: Read>(r: &mut R) -> io::Result<Duration> {
+	let secs = r.read_u64::<LittleEndian>()?;
+	let nanos = r.read_u32::<LittleEndian>()?;
+	if nanos >= 1_000_000_000 {
+		return Err(io::ErrorKind::InvalidData.into());
+	}
+	Ok(Duration::new(secs, nanos))
+}
+
+/// Architectural state of a vCPU
+#[derive(Clone)]
+pub struct VcpuState {
+	pub regs: kvm_regs,
+	pub sregs: kvm_sregs,
+	pub fpu: kvm_fpu,
+	pub xsave: kvm_xsave,
+	pub xcrs: kvm_xcrs,
+	pub debug_regs: kvm_debugregs,
+	pub lapic: kvm_lapic_state,
+	pub mp_state: kvm_mp_state,
+	pub events: kvm_vcpu_events,
+	pub msrs: Vec<kvm_msr_entry>,
+}
+
+impl VcpuState {
+	/// Reads the state of `vcpu`. The vCPU must not run in the meantime and
+	/// its pending I/O has to be completed before (see
+	/// `UhyveCPU::save_state`).
+	pub fn save(vcpu: &VcpuFd) -> Result<VcpuState> {
+		// KVM stops at the first MSR, which cannot be read => skip it and continue
+		let msr_list = KVM.get_msr_index_list().or_else(to_error)?;
+		let mut indices = msr_list.as_slice();
+		let mut msrs = Vec::new();
+		while !indices.is_empty() {
+			let entries = indices
+				.iter()
+				.map(|index| kvm_msr_entry {
+					index: *index,
+					..Default::default()
+				})
+				.collect::<Vec<_>>();
+			let mut buffer = Msrs::from_entries(&entries)
+				.map_err(|_| Error::Snapshot("too many MSRs".to_string()))?;
+			let count = vcpu.get_msrs(&mut buffer).or_else(to_error)?;
+			msrs.extend_from_slice(&buffer.as_slice()[..count]);
+			if count < indices.len() {
+				debug!("Unable to save MSR 0x{:x}", indices[count]);
+			}
+			indices = &indices[(count + 1).min(indices.len())..];
+		}
+
+		Ok(VcpuState {
+			regs: vcpu.get_regs().or_else(to_error)?,
+			sregs: vcpu.get_sregs().or_else(to_error)?,
+			fpu: vcpu.get_fpu().or_else(to_error)?,
+			xsave: vcpu.get_xsave().or_else(to_error)?,
+			xcrs: vcpu.get_xcrs().or_else(to_error)?,
+			debug_regs: vcpu.get_debug_regs().or_else(to_error)?,
+			lapic: vcpu.get_lapic().or_else(to_error)?,
+			mp_state: vcpu.get_mp_state().or_else(to_error)?,
+			events: vcpu.get_vcpu_events().or_else(to_error)?,
+			msrs,
+		})
+	}
+
+	/// Writes the state to `vcpu`. The CPUID of the vCPU has to be set up before.
+	pub fn restore(&self, vcpu: &VcpuFd) -> Result<()> {
+		vcpu.set_mp_state(self.mp_state).or_else(to_error)?;
+		vcpu.set_regs(&self.regs).or_else(to_error)?;
+		vcpu.set_sregs(&self.sregs).or_else(to_error)?;
+		vcpu.set_fpu(&self.fpu).or_else(to_error)?;
+		vcpu.set_xsave(&self.xsave).or_else(to_error)?;
+		vcpu.set_xcrs(&self.xcrs).or_else(to_error)?;
+		vcpu.set_debug_regs(&self.debug_regs).or_else(to_error)?;
+		vcpu.set_lapic(&self.lapic).or_else(to_error)?;
+
+		let mut entries = &self.msrs[..];
+		while !entries.is_empty() {
+			let msrs = Msrs::from_entries(entries)
+				.map_err(|_| Error::Snapshot("too many MSRs".to_string()))?;
+			let count = vcpu.set_msrs(&msrs).or_else(to_error)?;
+			if count < entries.len() {
+				warn!("Unable to restore MSR 0x{:x}", entries[count].index);
+			}
+			entries = &entries[(count + 1).min(entries.len())..];
+		}
+
+		vcpu.set_vcpu_events(&self.events).or_else(to_error)?;
+
+		Ok(())
+	}
+
+	fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+		write_pod(w, &self.regs)?;
+		write_pod(w, &self.sregs)?;
+		write_pod(w, &self.fpu)?;
+		write_pod(w, &self.xsave)?;
+		write_pod(w, &self.xcrs)?;
+		write_pod(w, &self.debug_regs)?;
+		write_pod(w, &self.lapic)?;
+		write_pod(w, &self.mp_state)?;
+		write_pod(w, &self.events)?;
+		w.write_u32::<LittleEndian>(self.msrs.len() as u32)?;
+		for msr in self.msrs.iter() {
+			write_pod(w, msr)?;
+		}
+
+		Ok(())
+	}
+
+	fn read_from<R: Read>(r: &mut R) -> io::Result<VcpuState> {
+		unsafe {
+			let regs = read_pod(r)?;
+			let sregs = read_pod(r)?;
+			let fpu = read_pod(r)?;
+			let xsave = read_pod(r)?;
+			let xcrs = read_pod(r)?;
+			let debug_regs = read_pod(r)?;
+			let lapic = read_pod(r)?;
+			let mp_state = read_pod(r)?;
+			let events = read_pod(r)?;
+			let count = r.read_u32::<LittleEndian>()?;
+			let msrs = (0..count)
+				.map(|_| read_pod(r))
+				.collect::<io::Result<Vec<kvm_msr_entry>>>()?;
+
+			Ok(VcpuState {
+				regs,
+				sregs,
+				fpu,
+				xsave,
+				xcrs,
+				debug_regs,
+				lapic,
+				mp_state,
+				events,
+				msrs,
+			})
+		}
+	}
+}
+
+/// Host file, which the guest has opened
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileState {
+	/// File descriptor of the guest
+	pub fd: i32,
+	pub path: PathBuf,
+	pub flags: i32,
+	pub mode: i32,
+	pub offset: i64,
+}
+
+impl FileState {
+	/// Returns the state of all `files`, which are opened by the guest.
+	pub fn save_all(files: &GuestFiles) -> Vec<FileState> {
+		files
+			.to_vec()
+			.into_iter()
+			.map(|(fd, file)| FileState {
+				fd,
+				path: file.path.clone(),
+				flags: file.flags,
+				mode: file.mode,
+				offset: unsafe { libc::lseek(file.fd, 0, libc::SEEK_CUR) },
+			})
+			.collect()
+	}
+
+	/// Reopens the file at the same offset and adds it to `files`. The guest
+	/// keeps its file descriptor, which is translated to the new host file
+	/// descriptor.
+	pub fn reopen(&self, files: &GuestFiles) -> Result<()> {
+		let name = CString::new(self.path.as_os_str().as_bytes())
+			.map_err(|_| Error::InvalidFile(self.path.clone()))?;
+		// the file must neither be truncated nor created again
+		let flags = self.flags & !(libc::O_CREAT | libc::O_EXCL | libc::O_TRUNC);
+
+		let fd = unsafe { libc::open(name.as_ptr(), flags, self.mode) };
+		if fd < 0 {
+			return Err(Error::InvalidFile(self.path.clone()));
+		}
+		if self.offset >= 0 {
+			unsafe { libc::lseek(fd, self.offset, libc::SEEK_SET) };
+		}
+
+		files.insert_at(
+			self.fd,
+			GuestFile {
+				fd,
+				path: self.path.clone(),
+				flags: self.flags,
+				mode: self.mode,
+			},
+		);
+
+		Ok(())
+	}
+
+	fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+		w.write_i32::<LittleEndian>(self.fd)?;
+		write_bytes(w, self.path.as_os_str().as_bytes())?;
+		w.write_i32::<LittleEndian>(self.flags)?;
+		w.write_i32::<LittleEndian>(self.mode)?;
+		w.write_i64::<LittleEndian>(self.offset)
+	}
+
+	fn read_from<R: Read>(r: &mut R) -> io::Result<FileState> {
+		Ok(FileState {
+			fd: r.read_i32::<LittleEndian>()?,
+			path: PathBuf::from(OsStr::from_bytes(&read_bytes(r)?)),
+			flags: r.read_i32::<LittleEndian>()?,
+			mode: r.read_i32::<LittleEndian>()?,
+			offset: r.read_i64::<LittleEndian>()?,
+		})
+	}
+}
+
+/// State of the in-kernel devices of KVM
+#[derive(Clone)]
+pub struct IrqchipState {
+	/// PIC master, PIC slave and IOAPIC
+	pub irqchips: Vec<kvm_irqchip>,
+	pub clock: kvm_clock_data,
+}
+
+impl IrqchipState {
+	pub fn save(vm: &VmFd) -> Result<IrqchipState> {
+		let irqchips = [
+			KVM_IRQCHIP_PIC_MASTER,
+			KVM_IRQCHIP_PIC_SLAVE,
+			KVM_IRQCHIP_IOAPIC,
+		]
+		.iter()
+		.map(|chip_id| {
+			let mut irqchip = kvm_irqchip {
+				chip_id: *chip_id,
+				..Default::default()
+			};
+			vm.get_irqchip(&mut irqchip).or_else(to_error)?;
+			Ok(irqchip)
+		})
+		.collect::<Result<Vec<_>>>()?;
+
+		Ok(IrqchipState {
+			irqchips,
+			clock: vm.get_clock().or_else(to_error)?,
+		})
+	}
+
+	pub fn restore(&self, vm: &VmFd) -> Result<()> {
+		for irqchip in self.irqchips.iter() {
+			vm.set_irqchip(irqchip).or_else(to_error)?;
+		}
+
+		// KVM_SET_CLOCK doesn't accept the flags, which are reported by KVM_GET_CLOCK
+		let clock = kvm_clock_data {
+			flags: 0,
+			..self.clock
+		};
+		vm.set_clock(&clock).or_else(to_error)
+	}
+}
+
+/// Complete state of a VM, apart from the guest memory
+pub struct Snapshot {
+	pub kernel_path: PathBuf,
+	pub mem_size: usize,
+	pub num_cpus: u32,
+	/// CPU model of the guest (see `--cpu-model`)
+	pub cpu_model: String,
+	/// TSC frequency of the guest in kHz, if it is known
+	pub tsc_khz: Option<u32>,
+	pub entry_point: u64,
+	/// Guest-physical address of the boot information
+	pub boot_info: u64,
+	pub irqchip: IrqchipState,
+	/// States of the vCPUs, which the guest has started
+	pub vcpus: Vec<Option<VcpuState>>,
+	/// The guest starts the vCPUs explicitly
+	pub explicit_startup: bool,
+	pub virtio: VirtioNetState,
+	pub serial: SerialState,
+	pub rtc: RtcState,
+	pub files: Vec<FileState>,
+	/// Guest-physical address of the flag, which tells the guest that it
+	/// runs in a restored VM (see `SnapshotControl::request_and_pause`)
+	pub restored_flag: Option<u64>,
+}
+
+impl Snapshot {
+	/// Writes the snapshot together with the guest memory `guest_mem` to the
+	/// file at `path`.
+	pub fn write(&self, path: &Path, guest_mem: &[u8]) -> Result<()> {
+		let mut state = Vec::new();
+		self.write_state(&mut state).map_err(io_error)?;
+		let mem_offset = align_up!(SNAPSHOT_HEADER_SIZE + state.len(), PAGE_SIZE);
+
+		// write to a temporary file first, because a running VM may still map
+		// the guest memory of an older snapshot at `path` copy-on-write
+		let mut tmp_path = path.as_os_str().to_owned();
+		tmp_path.push(".tmp");
+		let tmp_path = PathBuf::from(tmp_path);
+		let mut file = File::create(&tmp_path).map_err(|_| Error::InvalidFile(tmp_path.clone()))?;
+		file.write_all(SNAPSHOT_MAGIC).map_err(io_error)?;
+		file.write_u32::<LittleEndian>(SNAPSHOT_VERSION)
+			.map_err(io_error)?;
+		file.write_u64::<LittleEndian>(mem_offset as u64)
+			.map_err(io_error)?;
+		file.write_all(&state).map_err(io_error)?;
+
+		// write consecutive non-zero pages at once, zero pages remain holes in the file
+		let mut start = 0;
+		while start < guest_mem.len() {
+			let is_zero = |offset: usize| {
+				guest_mem[offset..(offset + PAGE_SIZE).min(guest_mem.len())]
+					.iter()
+					.all(|byte| *byte == 0)
+			};
+			if is_zero(start) {
+				start += PAGE_SIZE;
+				continue;
+			}
+
+			let mut end = start + PAGE_SIZE;
+			while end < guest_mem.len() && !is_zero(end) {
+				end += PAGE_SIZE;
+			}
+			let end = end.min(guest_mem.len());
+			file.write_all_at(&guest_mem[start..end], (mem_offset + start) as u64)
+				.map_err(io_error)?;
+			start = end;
+		}
+		file.set_len((mem_offset + guest_mem.len()) as u64)
+			.map_err(io_error)?;
+		fs::rename(&tmp_path, path).map_err(io_error)?;
+
+		Ok(())
+	}
+
+	/// Reads the snapshot at `path`. Returns the snapshot together with the
+	/// opened file and the offset of the guest memory in this file.
+	pub fn read(path: &Path) -> Result<(Snapshot, File, u64)> {
+		let file = File::open(path).map_err(|_| Error::InvalidFile(path.to_path_buf()))?;
+		let mut reader = BufReader::new(&file);
+
+		let mut magic = [0u8; 8];
+		reader.read_exact(&mut magic).map_err(invalid_snapshot)?;
+		if &magic != SNAPSHOT_MAGIC {
+			return Err(Error::InvalidFile(path.to_path_buf()));
+		}
+		let version = reader
+			.read_u32::<LittleEndian>()
+			.map_err(invalid_snapshot)?;
+		if version != SNAPSHOT_VERSION {
+			return Err(Error::Snapshot(format!(
+				"unsupported snapshot version {}",
+				version
+			)));
+		}
+		let mem_offset = reader
+			.read_u64::<LittleEndian>()
+			.map_err(invalid_snapshot)?;
+		let snapshot = Snapshot::read_state(&mut reader).map_err(invalid_snapshot)?;
+		drop(reader);
+
+		// a truncated file would be mapped anyway and the guest would crash
+		// with SIGBUS on the first access to the missing pages
+		let len = file.metadata().map_err(invalid_snapshot)?.len();
+		match mem_offset.checked_add(snapshot.mem_size as u64) {
+			Some(end) if end <= len => {}
+			_ => {
+				return Err(Error::Snapshot(format!(
+					"truncated snapshot file with {} instead of {} bytes",
+					len,
+					mem_offset.saturating_add(snapshot.mem_size as u64)
+				)))
+			}
+		}
+
+		Ok((snapshot, file, mem_offset))
+	}
+
+	fn write_state<W: Write>(&self, w: &mut W) -> io::Result<()> {
+		write_bytes(w, self.kernel_path.as_os_str().as_bytes())?;
+		w.write_u64::<LittleEndian>(self.mem_size as u64)?;
+		w.write_u32::<LittleEndian>(self.num_cpus)?;
+		write_bytes(w, self.cpu_model.as_bytes())?;
+		w.write_u8(self.tsc_khz.is_some() as u8)?;
+		w.write_u32::<LittleEndian>(self.tsc_khz.unwrap_or(0))?;
+		w.write_u64::<LittleEndian>(self.entry_point)?;
+		w.write_u64::<LittleEndian>(self.boot_info)?;
+
+		w.write_u32::<LittleEndian>(self.irqchip.irqchips.len() as u32)?;
+		for irqchip in self.irqchip.irqchips.iter() {
+			write_pod(w, irqchip)?;
+		}
+		write_pod(w, &self.irqchip.clock)?;
+
+		w.write_u32::<LittleEndian>(self.vcpus.len() as u32)?;
+		for vcpu in self.vcpus.iter() {
+			w.write_u8(vcpu.is_some() as u8)?;
+			if let Some(vcpu) = vcpu {
+				vcpu.write_to(w)?;
+			}
+		}
+		w.write_u8(self.explicit_startup as u8)?;
+
+		write_bytes(w, &self.virtio.registers)?;
+		w.write_u32::<LittleEndian>(self.virtio.requested_features)?;
+		w.write_u16::<LittleEndian>(self.virtio.selected_queue_num)?;
+		w.write_u32::<LittleEndian>(self.virtio.queues.len() as u32)?;
+		for (gpa, last_seen_available, last_seen_used) in self.virtio.queues.iter() {
+			w.write_u64::<LittleEndian>(*gpa)?;
+			w.write_u16::<LittleEndian>(*last_seen_available)?;
+			w.write_u16::<LittleEndian>(*last_seen_used)?;
+		}
+
+		w.write_all(&[
+			self.serial.ier,
+			self.serial.lcr,
+			self.serial.mcr,
+			self.serial.fcr,
+			self.serial.scr,
+		])?;
+		w.write_u16::<LittleEndian>(self.serial.divisor)?;
+		w.write_u8(self.serial.thr_empty_pending as u8)?;
+		w.write_u8(self.serial.loopback.is_some() as u8)?;
+		w.write_u8(self.serial.loopback.unwrap_or(0))?;
+
+		w.write_u8(self.rtc.index)?;
+		write_bytes(w, &self.rtc.cmos)?;
+		write_duration(w, self.rtc.time)?;
+		write_duration(w, self.rtc.host_time)?;
+		w.write_u8(self.rtc.latched.is_some() as u8)?;
+		w.write_u64::<LittleEndian>(self.rtc.latched.unwrap_or(0))?;
+
+		w.write_u32::<LittleEndian>(self.files.len() as u32)?;
+		for file in self.files.iter() {
+			file.write_to(w)?;
+		}
+
+		w.write_u8(self.restored_flag.is_some() as u8)?;
+		w.write_u64::<LittleEndian>(self.restored_flag.unwrap_or(0))?;
+
+		Ok(())
+	}
+
+	fn read_state<R: Read>(r: &mut R) -> io::Result<Snapshot> {
+		let kernel_path = PathBuf::from(OsStr::from_bytes(&read_bytes(r)?));
+		let mem_size = r.read_u64::<LittleEndian>()? as usize;
+		let num_cpus = r.read_u32::<LittleEndian>()?;
+		let cpu_model = String::from_utf8(read_bytes(r)?)
+			.map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+		let has_tsc_khz = r.read_u8()? != 0;
+		let tsc_khz = r.read_u32::<LittleEndian>()?;
+		let entry_point = r.read_u64::<LittleEndian>()?;
+		let boot_info = r.read_u64::<LittleEndian>()?;
+
+		let count = r.read_u32::<LittleEndian>()?;
+		let irqchips = (0..count)
+			.map(|_| unsafe { read_pod(r) })
+			.collect::<io::Result<Vec<kvm_irqchip>>>()?;
+		let clock = unsafe { read_pod(r)? };
+
+		let count = r.read_u32::<LittleEndian>()?;
+		let vcpus = (0..count)
+			.map(|_| match r.read_u8()? {
+				0 => Ok(None),
+				_ => VcpuState::read_from(r).map(Some),
+			})
+			.collect::<io::Result<Vec<_>>>()?;
+		let explicit_startup = r.read_u8()? != 0;
+
+		let registers = read_bytes(r)?;
+		let requested_features = r.read_u32::<LittleEndian>()?;
+		let selected_queue_num = r.read_u16::<LittleEndian>()?;
+		let count = r.read_u32::<LittleEndian>()?;
+		let queues = (0..count)
+			.map(|_| {
+				Ok((
+					r.read_u64::<LittleEndian>()?,
+					r.read_u16::<LittleEndian>()?,
+					r.read_u16::<LittleEndian>()?,
+				))
+			})
+			.collect::<io::Result<Vec<_>>>()?;
+
+		let mut serial_registers = [0u8; 5];
+		r.read_exact(&mut serial_registers)?;
+		let divisor = r.read_u16::<LittleEndian>()?;
+		let thr_empty_pending = r.read_u8()? != 0;
+		let has_loopback = r.read_u8()? != 0;
+		let loopback = r.read_u8()?;
+		let serial = SerialState {
+			ier: serial_registers[0],
+			lcr: serial_registers[1],
+			mcr: serial_registers[2],
+			fcr: serial_registers[3],
+			scr: serial_registers[4],
+			divisor,
+			thr_empty_pending,
+			loopback: if has_loopback { Some(loopback) } else { None },
+		};
+
+		let index = r.read_u8()?;
+		let cmos = read_bytes(r)?;
+		let time = read_duration(r)?;
+		let host_time = read_duration(r)?;
+		let has_latched = r.read_u8()? != 0;
+		let latched = r.read_u64::<LittleEndian>()?;
+		let rtc = RtcState {
+			index,
+			cmos,
+			time,
+			host_time,
+			latched: if has_latched { Some(latched) } else { None },
+		};
+
+		let count = r.read_u32::<LittleEndian>()?;
+		let files = (0..count)
+			.map(|_| FileState::read_from(r))
+			.collect::<io::Result<Vec<_>>>()?;
+
+		let has_restored_flag = r.read_u8()? != 0;
+		let restored_flag = r.read_u64::<LittleEndian>()?;
+
+		if vcpus.len() != num_cpus as usize {
+			return Err(io::ErrorKind::InvalidData.into());
+		}
+
+		Ok(Snapshot {
+			kernel_path,
+			mem_size,
+			num_cpus,
+			cpu_model,
+			tsc_khz: if has_tsc_khz { Some(tsc_khz) } else { None },
+			entry_point,
+			boot_info,
+			irqchip: IrqchipState { irqchips, clock },
+			vcpus,
+			explicit_startup,
+			virtio: VirtioNetState {
+				registers,
+				requested_features,
+				selected_queue_num,
+				queues,
+			},
+			serial,
+			rtc,
+			files,
+			restored_flag: if has_restored_flag {
+				Some(restored_flag)
+			} else {
+				None
+			},
+		})
+	}
+}
+
+struct ControlState {
+	/// A snapshot is requested, but not yet started
+	triggered: bool,
+	/// The vCPUs have to pause
+	paused: bool,
+	/// States of the paused vCPUs
+	states: Vec<Option<VcpuState>>,
+	/// Flag of the guest, which has requested the current snapshot
+	restored_flag: Option<u64>,
+}
+
+/// Coordinates the vCPU threads with the snapshot thread. During a snapshot,
+/// each vCPU thread stores the state of its vCPU and waits until the snapshot
+/// is written.
+pub struct SnapshotControl {
+	trigger: SnapshotTrigger,
+	path: PathBuf,
+	state: Mutex<ControlState>,
+	cond: Condvar,
+}
+
+impl SnapshotControl {
+	pub fn new(trigger: SnapshotTrigger, path: PathBuf, num_cpus: u32) -> Self {
+		SnapshotControl {
+			trigger,
+			path,
+			state: Mutex::new(ControlState {
+				triggered: false,
+				paused: false,
+				states: vec![None; num_cpus as usize],
+				restored_flag: None,
+			}),
+			cond: Condvar::new(),
+		}
+	}
+
+	pub fn trigger(&self) -> SnapshotTrigger {
+		self.trigger
+	}
+
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+
+	/// Returns true if the vCPUs have to pause for a snapshot.
+	pub fn is_paused(&self) -> bool {
+		self.state.lock().unwrap().paused
+	}
+
+	/// Requests a snapshot, which is taken asynchronously by the snapshot thread.
+	pub fn request(&self) {
+		self.state.lock().unwrap().triggered = true;
+		self.cond.notify_all();
+	}
+
+	/// Blocks until a snapshot is requested and returns true. Returns false
+	/// instead, if the VM stops. The request is consumed by `pause_all`.
+	pub fn wait_for_request(&self, shutdown: &Shutdown) -> bool {
+		let mut state = self.state.lock().unwrap();
+		while !shutdown.is_requested() {
+			if state.triggered {
+				return true;
+			}
+			state = self.cond.wait_timeout(state, STOP_INTERVAL).unwrap().0;
+		}
+		false
+	}
+
+	/// Called by the thread of vCPU `id`, whose guest requests a snapshot and
+	/// waits for it. Returns after the snapshot is written. The guest-physical
+	/// address `restored_flag` is stored in the snapshot, so that a restored
+	/// VM is able to tell the guest that it is a clone.
+	pub fn request_and_pause<F: FnOnce() -> Result<VcpuState>>(
+		&self,
+		id: u32,
+		restored_flag: u64,
+		save: F,
+	) -> Result<()> {
+		let mut state = self.state.lock().unwrap();
+		if state.restored_flag.is_none() {
+			state.restored_flag = Some(restored_flag);
+		}
+		if !state.paused {
+			state.triggered = true;
+			self.cond.notify_all();
+			while state.triggered {
+				state = self.cond.wait(state).unwrap();
+			}
+		}
+		drop(state);
+
+		self.pause(id, save)
+	}
+
+	/// Returns the flag of the guest, which has requested the current snapshot.
+	pub fn restored_flag(&self) -> Option<u64> {
+		self.state.lock().unwrap().restored_flag
+	}
+
+	/// Called by the thread of vCPU `id`, if the vCPUs have to pause. Stores
+	/// the vCPU state, which is returned by `save`, and blocks until the
+	/// snapshot is written. Returns immediately if the pause is already over.
+	pub fn pause<F: FnOnce() -> Result<VcpuState>>(&self, id: u32, save: F) -> Result<()> {
+		let vcpu_state = save()?;
+
+		let mut state = self.state.lock().unwrap();
+		if !state.paused {
+			return Ok(());
+		}
+		state.states[id as usize] = Some(vcpu_state);
+		self.cond.notify_all();
+		while state.paused {
+			state = self.cond.wait(state).unwrap();
+		}
+
+		Ok(())
+	}
+
+	/// Pauses all vCPUs and returns their states. The vCPUs, which are
+	/// registered in `threads`, are kicked out of the guest until all of them
+	/// are paused. vCPUs, which the guest hasn't started according to
+	/// `startup`, have no state. Fails if the VM stops in the meantime.
+	pub fn pause_all(
+		&self,
+		threads: &VcpuThreads,
+		startup: &CpuStartup,
+		shutdown: &Shutdown,
+	) -> Result<Vec<Option<VcpuState>>> {
+		let mut state = self.state.lock().unwrap();
+		state.triggered = false;
+		state.paused = true;
+		self.cond.notify_all();
+
+		loop {
+			if shutdown.is_requested() {
+				drop(state);
+				self.resume_all();
+				return Err(Error::Snapshot("the VM is stopping".to_string()));
+			}
+
+			let mut is_complete = true;
+			for (id, vcpu_state) in state.states.iter().enumerate() {
+				if vcpu_state.is_none() {
+					if threads.kick(id as u32) {
+						is_complete = false;
+					} else {
+						// a started vCPU pauses as soon as its thread runs
+						is_complete &= !startup.is_started(id as u32);
+					}
+				}
+			}
+			if is_complete {
+				break;
+			}
+
+			state = self.cond.wait_timeout(state, KICK_INTERVAL).unwrap().0;
+		}
+
+		Ok(state
+			.states
+			.iter_mut()
+			.map(|vcpu_state| vcpu_state.take())
+			.collect())
+	}
+
+	/// Continues the execution of all paused vCPUs.
+	pub fn resume_all(&self) {
+		let mut state = self.state.lock().unwrap();
+		state.paused = false;
+		state.restored_flag = None;
+		state
+			.states
+			.iter_mut()
+			.for_each(|vcpu_state| *vcpu_state = None);
+		self.cond.notify_all();
+	}
+}
+
+fn snapshot_sigset() -> SigSet {
+	let mut sigset = SigSet::empty();
+	sigset.add(SNAPSHOT_SIGNAL);
+	sigset
+}
+
+/// Blocks the snapshot signal in the calling thread and all threads, which
+/// are created afterwards. It has to be called before the first thread of
+/// the VM is created, otherwise the signal may terminate the process.
+pub fn block_snapshot_signal() {
+	pthread_sigmask(SigmaskHow::SIG_BLOCK, Some(&snapshot_sigset()), None)
+		.expect("Unable to block the snapshot signal");
+}
+
+/// Starts the threads, which write the snapshots of `vm`. With
+/// `--snapshot-on signal`, a thread waits for the snapshot signal, which
+/// `block_snapshot_signal` has blocked. The threads finish after the VM
+/// has stopped.
+pub fn spawn_snapshot_threads(vm: Arc<Uhyve>) -> Vec<JoinHandle<()>> {
+	let control = match vm.snapshot_control() {
+		Some(control) => control,
+		None => return Vec::new(),
+	};
+	let mut threads = Vec::new();
+
+	if control.trigger() == SnapshotTrigger::Signal {
+		let vm = vm.clone();
+		let control = control.clone();
+		threads.push(thread::spawn(move || {
+			let sigset = snapshot_sigset();
+			let timeout = libc::timespec {
+				tv_sec: 0,
+				tv_nsec: STOP_INTERVAL.as_nanos() as libc::c_long,
+			};
+			while !vm.shutdown().is_requested() {
+				let signal =
+					unsafe { libc::sigtimedwait(sigset.as_ref(), ptr::null_mut(), &timeout) };
+				if signal == SNAPSHOT_SIGNAL as libc::c_int {
+					debug!("Received {:?}", SNAPSHOT_SIGNAL);
+					control.request();
+				}
+			}
+		}));
+	}
+
+	threads.push(thread::spawn(move || {
+		while control.wait_for_request(vm.shutdown()) {
+			match vm.snapshot(control.path()) {
+				Ok(()) => info!("Snapshot written to {}", control.path().display()),
+				Err(err) => error!("Unable to write snapshot: {}", err),
+			}
+		}
+	}));
+
+	threads
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_file_state() {
+		let file = FileState {
+			fd: 3,
+			path: PathBuf::from("/tmp/hello.txt"),
+			flags: libc::O_RDWR | libc::O_CREAT,
+			mode: 0o644,
+			offset: 42,
+		};
+		let mut buf = Vec::new();
+		file.write_to(&mut buf).unwrap();
+		assert_eq!(FileState::read_from(&mut &buf[..]).unwrap(), file);
+	}
+
+	#[test]
+	fn test_reopen_file() {
+		let path = std::env::temp_dir().join(format!("uhyve-{}-reopen.txt", std::process::id()));
+		fs::write(&path, b"hello").unwrap();
+
+		// the guest file descriptor is unused on the host
+		let file = FileState {
+			fd: 1000,
+			path: path.clone(),
+			flags: libc::O_RDONLY,
+			mode: 0,
+			offset: 2,
+		};
+		let files = GuestFiles::new();
+		file.reopen(&files).unwrap();
+		let fd = files.host_fd(file.fd);
+		assert_ne!(fd, file.fd);
+		assert_eq!(FileState::save_all(&files), vec![file.clone()]);
+
+		let mut buf = [0u8; 8];
+		let len = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+		assert_eq!(&buf[..len as usize], b"llo");
+		files.remove(file.fd);
+		unsafe { libc::close(fd) };
+		fs::remove_file(&path).unwrap();
+	}
+
+	fn dummy_vcpu_state() -> VcpuState {
+		unsafe {
+			VcpuState {
+				regs: mem::zeroed(),
+				sregs: mem::zeroed(),
+				fpu: mem::zeroed(),
+				xsave: mem::zeroed(),
+				xcrs: mem::zeroed(),
+				debug_regs: mem::zeroed(),
+				lapic: mem::zeroed(),
+				mp_state: mem::zeroed(),
+				events: mem::zeroed(),
+				msrs: Vec::new(),
+			}
+		}
+	}
+
+	/// Runs `f` on a thread, which is registered as the thread of vCPU 0.
+	fn spawn_vcpu_thread<F: FnOnce() + Send + 'static>(
+		threads: Arc<VcpuThreads>,
+		f: F,
+	) -> thread::JoinHandle<()> {
+		thread::spawn(move || {
+			let vm = KVM.create_vm().unwrap();
+			let vcpu = vm.create_vcpu(0).unwrap();
+			threads.register(0, &vcpu);
+			f();
+			threads.unregister(0);
+		})
+	}
+
+	#[test]
+	fn test_pause_unstarted_vcpus() {
+		if !crate::linux::tests::has_vm_support() {
+			return;
+		}
+
+		let control = Arc::new(SnapshotControl::new(
+			SnapshotTrigger::Hypercall,
+			PathBuf::from("snapshot"),
+			2,
+		));
+		let threads = Arc::new(VcpuThreads::new(2));
+		let startup = CpuStartup::new(2);
+		let shutdown = Shutdown::new();
+
+		// only the boot processor runs, the application processor isn't started
+		let vcpu = {
+			let control = control.clone();
+			spawn_vcpu_thread(threads.clone(), move || {
+				while !control.is_paused() {
+					thread::sleep(Duration::from_millis(1));
+				}
+				control.pause(0, || Ok(dummy_vcpu_state())).unwrap();
+			})
+		};
+
+		let states = control.pause_all(&threads, &startup, &shutdown).unwrap();
+		assert!(states[0].is_some());
+		assert!(states[1].is_none());
+		control.resume_all();
+		vcpu.join().unwrap();
+
+		// the started, but not yet running vCPU 1 blocks the snapshot until the VM stops
+		startup.restore(&[true, true], false);
+		shutdown.request(crate::vm::VmOutcome::Exit(0));
+		assert!(control.pause_all(&threads, &startup, &shutdown).is_err());
+		assert!(!control.is_paused());
+	}
+
+	#[test]
+	fn test_request_and_pause() {
+		if !crate::linux::tests::has_vm_support() {
+			return;
+		}
+
+		let control = Arc::new(SnapshotControl::new(
+			SnapshotTrigger::Hypercall,
+			PathBuf::from("snapshot"),
+			1,
+		));
+		let threads = Arc::new(VcpuThreads::new(1));
+		let startup = CpuStartup::new(1);
+		let shutdown = Shutdown::new();
+
+		// the guest on vCPU 0 waits until its snapshot is written
+		let vcpu = {
+			let control = control.clone();
+			spawn_vcpu_thread(threads.clone(), move || {
+				control
+					.request_and_pause(0, 0x5000, || Ok(dummy_vcpu_state()))
+					.unwrap();
+			})
+		};
+
+		assert!(control.wait_for_request(&shutdown));
+		let states = control.pause_all(&threads, &startup, &shutdown).unwrap();
+		assert!(states[0].is_some());
+		assert_eq!(control.restored_flag(), Some(0x5000));
+		control.resume_all();
+		vcpu.join().unwrap();
+		assert_eq!(control.restored_flag(), None);
+
+		// the snapshot thread finishes after the VM has stopped
+		shutdown.request(crate::vm::VmOutcome::Exit(0));
+		assert!(!control.wait_for_request(&shutdown));
+	}
+
+	#[test]
+	fn test_vcpu_state() {
+		if !crate::linux::tests::has_vm_support() {
+			return;
+		}
+
+		let vm = KVM.create_vm().unwrap();
+		vm.create_irq_chip().unwrap();
+		let cpuid = KVM.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES).unwrap();
+		let vcpu = vm.create_vcpu(0).unwrap();
+		vcpu.set_cpuid2(&cpuid).unwrap();
+		let mut regs = vcpu.get_regs().unwrap();
+		regs.rax = 0x42;
+		regs.rip = 0x1000;
+		vcpu.set_regs(&regs).unwrap();
+
+		let state = VcpuState::save(&vcpu).unwrap();
+		assert!(!state.msrs.is_empty());
+		let mut buf = Vec::new();
+		state.write_to(&mut buf).unwrap();
+		let restored = VcpuState::read_from(&mut &buf[..]).unwrap();
+
+		let vcpu = vm.create_vcpu(1).unwrap();
+		vcpu.set_cpuid2(&cpuid).unwrap();
+		restored.restore(&vcpu).unwrap();
+		let regs = vcpu.get_regs().unwrap();
+		assert_eq!(regs.rax, 0x42);
+		assert_eq!(regs.rip, 0x1000);
+	}
+
+	#[test]
+	fn test_snapshot_file() {
+		let snapshot = Snapshot {
+			kernel_path: PathBuf::from("hello_world"),
+			mem_size: 4 * PAGE_SIZE,
+			num_cpus: 0,
+			cpu_model: "x86-64-v2".to_string(),
+			tsc_khz: Some(2_500_000),
+			entry_point: 0x400000,
+			boot_info: 0x9000,
+			irqchip: IrqchipState {
+				irqchips: Vec::new(),
+				clock: kvm_clock_data {
+					clock: 1234,
+					..Default::default()
+				},
+			},
+			vcpus: Vec::new(),
+			explicit_startup: true,
+			virtio: VirtioNetState {
+				registers: vec![1; 0x40],
+				requested_features: 0x20,
+				selected_queue_num: 1,
+				queues: vec![(0x10000, 1, 2), (0x20000, 3, 4)],
+			},
+			serial: SerialState {
+				ier: 1,
+				lcr: 3,
+				mcr: 8,
+				fcr: 0,
+				scr: 0x42,
+				divisor: 12,
+				thr_empty_pending: true,
+				loopback: Some(b'x'),
+			},
+			rtc: RtcState {
+				index: 0x0b,
+				cmos: vec![2; 128],
+				time: Duration::new(1_623_801_598, 500),
+				host_time: Duration::new(1_623_801_600, 0),
+				latched: None,
+			},
+			files: Vec::new(),
+			restored_flag: Some(0x12340),
+		};
+		let mut guest_mem = vec![0u8; snapshot.mem_size];
+		guest_mem[PAGE_SIZE + 3] = 0x42;
+		guest_mem[3 * PAGE_SIZE] = 0x43;
+
+		let path = std::env::temp_dir().join(format!("uhyve-{}.snapshot", std::process::id()));
+		snapshot.write(&path, &guest_mem).unwrap();
+		let (restored, file, mem_offset) = Snapshot::read(&path).unwrap();
+
+		assert_eq!(restored.kernel_path, snapshot.kernel_path);
+		assert_eq!(restored.mem_size, snapshot.mem_size);
+		assert_eq!(restored.cpu_model, snapshot.cpu_model);
+		assert_eq!(restored.tsc_khz, snapshot.tsc_khz);
+		assert_eq!(restored.entry_point, snapshot.entry_point);
+		assert_eq!(restored.boot_info, snapshot.boot_info);
+		assert_eq!(restored.irqchip.clock.clock, 1234);
+		assert!(restored.explicit_startup);
+		assert_eq!(restored.virtio, snapshot.virtio);
+		assert_eq!(restored.serial, snapshot.serial);
+		assert_eq!(restored.rtc, snapshot.rtc);
+		assert_eq!(restored.restored_flag, Some(0x12340));
+		assert_eq!(mem_offset as usize % PAGE_SIZE, 0);
+
+		let mut restored_mem = vec![0xffu8; snapshot.mem_size];
+		file.read_exact_at(&mut restored_mem, mem_offset).unwrap();
+		assert_eq!(restored_mem, guest_mem);
+
+		// the last page of the guest memory is missing
+		fs::OpenOptions::new()
+			.write(true)
+			.open(&path)
+			.unwrap()
+			.set_len(mem_offset + (snapshot.mem_size - PAGE_SIZE) as u64)
+			.unwrap();
+		assert!(matches!(Snapshot::read(&path), Err(Error::Snapshot(_))));
+		fs::remove_file(&path).unwrap();
+	}
+}
@@ -0,0 +1,78 @@
+//! TSC frequency of the guest.
+//!
+//! KVM runs the TSC of a guest with the frequency, which the host kernel has
+//! calibrated, unless `KVM_SET_TSC_KHZ` scales it. uhyve sets the frequency
+//! explicitly on each vCPU and announces it to the guest by the boot
+//! information and the CPUID leaves 0x15 and 0x40000010.
+
+use crate::error::*;
+use crate::linux::KVM;
+use kvm_ioctls::{Cap, VcpuFd};
+use log::{debug, warn};
+
+/// Returns the TSC frequency of the host in kHz, which KVM reports for a
+/// temporary vCPU.
+fn host_tsc_khz() -> Result<u32> {
+	if !KVM.check_extension(Cap::GetTscKhz) {
+		return Err(Error::OsError(libc::ENOTSUP));
+	}
+	let vm = KVM.create_vm().or_else(to_error)?;
+	let vcpu = vm.create_vcpu(0).or_else(to_error)?;
+	vcpu.get_tsc_khz().or_else(to_error)
+}
+
+/// Returns the TSC frequency of the guest in kHz, which is `requested` or
+/// the frequency of the host. Returns `None` if KVM doesn't report the
+/// frequency of the host. Fails if the host is unable to scale the TSC to
+/// `requested`.
+pub fn guest_tsc_khz(requested: Option<u32>) -> Result<Option<u32>> {
+	let host = host_tsc_khz()
+		.map_err(|err| warn!("Unable to get the TSC frequency from KVM: {}", err))
+		.ok();
+	debug!("TSC frequency of the host: {:?} kHz", host);
+
+	match requested {
+		Some(0) => Err(Error::InvalidArgument("TSC frequency 0 kHz".to_string())),
+		Some(khz) if Some(khz) != host && !KVM.check_extension(Cap::TscControl) => {
+			Err(Error::UnsupportedTscFrequency(khz))
+		}
+		Some(khz) => Ok(Some(khz)),
+		None => Ok(host),
+	}
+}
+
+/// Sets the TSC frequency of `vcpu` to `tsc_khz`.
+pub fn set_tsc_khz(vcpu: &VcpuFd, tsc_khz: u32) -> Result<()> {
+	vcpu.set_tsc_khz(tsc_khz).or_else(|err| {
+		warn!(
+			"Unable to set the TSC frequency to {} kHz: {}",
+			tsc_khz, err
+		);
+		to_error(err)
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_guest_tsc_khz() {
+		if !crate::linux::tests::has_vm_support() {
+			return;
+		}
+
+		let host = match guest_tsc_khz(None).unwrap() {
+			Some(khz) => khz,
+			None => return,
+		};
+		assert!(host > 0);
+		assert_eq!(guest_tsc_khz(Some(host)).unwrap(), Some(host));
+		assert!(guest_tsc_khz(Some(0)).is_err());
+
+		let vm = KVM.create_vm().unwrap();
+		let vcpu = vm.create_vcpu(0).unwrap();
+		set_tsc_khz(&vcpu, host).unwrap();
+		assert_eq!(vcpu.get_tsc_khz().unwrap(), host);
+	}
+}
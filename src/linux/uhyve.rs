@@ -1,37 +1,52 @@
 //! This file contains the entry point to the Hypervisor. The Uhyve utilizes KVM to
 //! create a Virtual Machine and load the kernel.
 
+use crate::console::{Console, ConsoleKind};
 use crate::consts::*;
 use crate::debug_manager::DebugManager;
 use crate::error::*;
+use crate::linux::cpuid::{guest_cpuid, has_1gib_pages, HOST_CPU_MODEL};
+use crate::linux::kick::VcpuThreads;
+use crate::linux::kvm_run::KvmRun;
+use crate::linux::memory::*;
+use crate::linux::msr;
+use crate::linux::profiler::Profiler;
+use crate::linux::rtc::Rtc;
+use crate::linux::serial::Serial;
+use crate::linux::snapshot::*;
+use crate::linux::tsc;
 use crate::linux::vcpu::*;
 use crate::linux::virtio::*;
+use crate::linux::watchdog::Watchdog;
 use crate::linux::{MemoryRegion, KVM};
 use crate::shared_queue::*;
-use crate::vm::{BootInfo, Parameter, VirtualCPU, Vm};
+use crate::stats::VmStats;
+use crate::vm::{
+	guest_mem_regions, guest_phys_to_offset, BootInfo, CpuStartup, GuestCommand, GuestFiles,
+	Parameter, Shutdown, SnapshotTrigger, UnknownAccessPolicy, VirtualCPU, Vm,
+};
 use kvm_bindings::*;
 use kvm_ioctls::VmFd;
-use log::debug;
-use nix::sys::mman::*;
+use log::{debug, info};
 use std::convert::TryInto;
 use std::hint;
 use std::mem;
 use std::net::Ipv4Addr;
-use std::os::raw::c_void;
-use std::path::PathBuf;
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
 use std::ptr;
 use std::ptr::{read_volatile, write_volatile};
+use std::slice;
 use std::str::FromStr;
 use std::sync::mpsc::sync_channel;
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::SystemTime;
 use tun_tap::{Iface, Mode};
 use vmm_sys_util::eventfd::EventFd;
 
-const KVM_32BIT_MAX_MEM_SIZE: usize = 1 << 32;
-const KVM_32BIT_GAP_SIZE: usize = 768 << 20;
-const KVM_32BIT_GAP_START: usize = KVM_32BIT_MAX_MEM_SIZE - KVM_32BIT_GAP_SIZE;
-
 struct UhyveNetwork {
 	#[allow(dead_code)]
 	reader: std::thread::JoinHandle<()>,
@@ -123,8 +138,9 @@ impl Drop for UhyveNetwork {
 pub struct Uhyve {
 	vm: VmFd,
 	entry_point: u64,
-	mem: MmapMemory,
+	mem: Box<dyn MemoryRegion>,
 	num_cpus: u32,
+	cpu_model: String,
 	path: PathBuf,
 	boot_info: *const BootInfo,
 	verbose: bool,
@@ -134,6 +150,21 @@ pub struct Uhyve {
 	uhyve_device: Option<UhyveNetwork>,
 	virtio_device: Arc<Mutex<VirtioNetPciDevice>>,
 	dbg: Option<Arc<Mutex<DebugManager>>>,
+	snapshot: Option<Arc<SnapshotControl>>,
+	cpu_startup: Arc<CpuStartup>,
+	shutdown: Arc<Shutdown>,
+	vcpu_threads: Arc<VcpuThreads>,
+	cpuid: Arc<CpuId>,
+	stats: Option<Arc<VmStats>>,
+	watchdog: Option<Arc<Watchdog>>,
+	unknown_access: UnknownAccessPolicy,
+	console: Arc<Console>,
+	serial: Arc<Mutex<Serial>>,
+	rtc: Arc<Rtc>,
+	tsc_khz: Option<u32>,
+	profiler: Option<Arc<Profiler>>,
+	command: GuestCommand,
+	files: Arc<GuestFiles>,
 }
 
 impl Uhyve {
@@ -142,6 +173,72 @@ impl Uhyve {
 		specs: &Parameter<'_>,
 		dbg: Option<DebugManager>,
 	) -> Result<Uhyve> {
+		let mem = Uhyve::create_memory(specs)?;
+		let hyve = Uhyve::with_memory(kernel_path, specs, dbg, mem)?;
+		hyve.init_guest_mem();
+
+		Ok(hyve)
+	}
+
+	/// Allocates the guest memory with the backing, which is selected by `specs`.
+	fn create_memory(specs: &Parameter<'_>) -> Result<Box<dyn MemoryRegion>> {
+		if specs.mergeable && (specs.mem_path.is_some() || specs.memfd) {
+			return Err(Error::InvalidArgument(
+				"shared guest memory can't be mergeable".to_string(),
+			));
+		}
+
+		let mem: Box<dyn MemoryRegion> = if let Some(path) = specs.mem_path {
+			debug!("Use {} as guest memory", path);
+			Box::new(SharedMemory::from_path(
+				0,
+				specs.mem_size,
+				0,
+				Path::new(path),
+			)?)
+		} else if let Some(page_size) = specs.hugetlb {
+			debug!(
+				"Use huge pages of size {} KiB as guest memory",
+				page_size >> 10
+			);
+			// the memory above the PCI gap has to start at a huge page of the
+			// host, otherwise KVM can't map the guest memory by huge pages
+			if specs.mem_size > KVM_32BIT_GAP_START && KVM_32BIT_GAP_START % page_size != 0 {
+				return Err(Error::InvalidArgument(format!(
+					"huge pages of size {} KiB require at most {} MiB guest memory",
+					page_size >> 10,
+					KVM_32BIT_GAP_START >> 20
+				)));
+			}
+			Box::new(MmapMemory::hugetlb(0, specs.mem_size, 0, page_size)?)
+		} else if specs.memfd {
+			debug!("Use memfd as guest memory");
+			Box::new(SharedMemory::from_memfd(0, specs.mem_size, 0)?)
+		} else {
+			Box::new(MmapMemory::new(
+				0,
+				specs.mem_size,
+				0,
+				specs.hugepage,
+				specs.mergeable,
+			))
+		};
+
+		Ok(mem)
+	}
+
+	/// Creates a VM, which uses `mem` as guest memory. The guest memory isn't initialized.
+	fn with_memory(
+		kernel_path: PathBuf,
+		specs: &Parameter<'_>,
+		dbg: Option<DebugManager>,
+		mem: Box<dyn MemoryRegion>,
+	) -> Result<Uhyve> {
+		// the threads of the network interfaces inherit the signal mask
+		if specs.snapshot_on == Some(SnapshotTrigger::Signal) {
+			block_snapshot_signal();
+		}
+
 		// parse string to get IP address
 		let ip_addr = specs
 			.ip
@@ -160,42 +257,40 @@ impl Uhyve {
 			.as_ref()
 			.map(|addr_str| Ipv4Addr::from_str(addr_str).expect("Unable to parse network parse"));
 
+		// refuse to start, if the host doesn't support the CPU model
+		let tsc_khz = tsc::guest_tsc_khz(specs.tsc_khz)?;
+		let cpu_model = specs.cpu_model.unwrap_or(HOST_CPU_MODEL);
+		let cpuid = guest_cpuid(cpu_model, specs.kvm_pv, tsc_khz)?;
+
 		let vm = KVM.create_vm().or_else(to_error)?;
 
-		let mem = MmapMemory::new(0, specs.mem_size, 0, specs.hugepage, specs.mergeable);
+		if let Some(nodes) = specs.numa_nodes {
+			debug!("Bind guest memory to NUMA nodes {:?}", nodes);
+			bind_to_nodes(mem.as_ref(), nodes)?;
+		}
 
-		let sz = if specs.mem_size < KVM_32BIT_GAP_START {
-			specs.mem_size
-		} else {
-			KVM_32BIT_GAP_START
-		};
+		if specs.prefault {
+			let duration = prefault(mem.as_ref());
+			info!(
+				"Prefaulted {} MiB of guest memory in {:?}",
+				mem.memory_size() >> 20,
+				duration
+			);
+		}
+
+		if specs.mlock {
+			let duration = lock(mem.as_ref())?;
+			info!(
+				"Locked {} MiB of guest memory in {:?}",
+				mem.memory_size() >> 20,
+				duration
+			);
+		}
 
 		// create virtio interface
 		let virtio_device = Arc::new(Mutex::new(VirtioNetPciDevice::new()));
 
-		let kvm_mem = kvm_userspace_memory_region {
-			slot: 0,
-			flags: mem.flags(),
-			memory_size: sz as u64,
-			guest_phys_addr: mem.guest_address() as u64,
-			userspace_addr: mem.host_address() as u64,
-		};
-
-		unsafe { vm.set_user_memory_region(kvm_mem) }.or_else(to_error)?;
-
-		if specs.mem_size > KVM_32BIT_GAP_START + KVM_32BIT_GAP_SIZE {
-			let kvm_mem = kvm_userspace_memory_region {
-				slot: 1,
-				flags: mem.flags(),
-				memory_size: (specs.mem_size - KVM_32BIT_GAP_START - KVM_32BIT_GAP_SIZE) as u64,
-				guest_phys_addr: (mem.guest_address() + KVM_32BIT_GAP_START + KVM_32BIT_GAP_SIZE)
-					as u64,
-				userspace_addr: (mem.host_address() + KVM_32BIT_GAP_START + KVM_32BIT_GAP_SIZE)
-					as u64,
-			};
-
-			unsafe { vm.set_user_memory_region(kvm_mem) }.or_else(to_error)?;
-		}
+		set_memory_slots(&vm, mem.as_ref(), mem.flags())?;
 
 		debug!("Initialize interrupt controller");
 
@@ -243,8 +338,35 @@ impl Uhyve {
 		vm.enable_cap(&cap)
 			.expect("Unable to disable exists due pause instructions");
 
+		msr::setup_vm(&vm)?;
+
 		let evtfd = EventFd::new(0).unwrap();
 		vm.register_irqfd(&evtfd, UHYVE_IRQ_NET).or_else(to_error)?;
+
+		// create the UART on COM1, which is connected to the console
+		let console_kind = match specs.console {
+			Some(console) => console.parse::<ConsoleKind>()?,
+			None => ConsoleKind::Stdio,
+		};
+		let console = Arc::new(Console::new(&console_kind)?);
+		let serial_evtfd = EventFd::new(0).unwrap();
+		vm.register_irqfd(&serial_evtfd, COM1_IRQ)
+			.or_else(to_error)?;
+		let serial = Arc::new(Mutex::new(Serial::new(console.clone(), serial_evtfd)));
+		let weak_serial = Arc::downgrade(&serial);
+		console.set_input_handler(Box::new(move || {
+			if let Some(serial) = weak_serial.upgrade() {
+				serial.lock().unwrap().input_available();
+			}
+		}));
+
+		let rtc_evtfd = EventFd::new(0).unwrap();
+		vm.register_irqfd(&rtc_evtfd, RTC_IRQ).or_else(to_error)?;
+		let rtc = Arc::new(Rtc::new(
+			specs.rtc_start.unwrap_or_else(SystemTime::now),
+			rtc_evtfd,
+		));
+
 		// create TUN/TAP device
 		let uhyve_device = match &specs.nic {
 			Some(nic) => {
@@ -263,6 +385,7 @@ impl Uhyve {
 			entry_point: 0,
 			mem,
 			num_cpus: specs.num_cpus,
+			cpu_model: cpu_model.to_string(),
 			path: kernel_path,
 			boot_info: ptr::null(),
 			verbose: specs.verbose,
@@ -272,12 +395,419 @@ impl Uhyve {
 			uhyve_device,
 			virtio_device,
 			dbg: dbg.map(|g| Arc::new(Mutex::new(g))),
+			snapshot: specs.snapshot_on.map(|trigger| {
+				Arc::new(SnapshotControl::new(
+					trigger,
+					PathBuf::from(specs.snapshot_path.unwrap_or(DEFAULT_SNAPSHOT_PATH)),
+					specs.num_cpus,
+				))
+			}),
+			cpu_startup: Arc::new(CpuStartup::new(specs.num_cpus)),
+			shutdown: Arc::new(Shutdown::new()),
+			vcpu_threads: Arc::new(VcpuThreads::new(specs.num_cpus)),
+			cpuid: Arc::new(cpuid),
+			stats: specs.stats.cloned(),
+			watchdog: specs
+				.watchdog
+				.map(|timeout| Arc::new(Watchdog::new(specs.num_cpus, timeout))),
+			unknown_access: specs.unknown_access.unwrap_or_default(),
+			console,
+			serial,
+			rtc,
+			tsc_khz,
+			profiler: specs
+				.profile
+				.map(|path| Arc::new(Profiler::new(specs.num_cpus, PathBuf::from(path)))),
+			command: GuestCommand::from_process(),
+			files: Arc::new(GuestFiles::new()),
 		};
 
-		hyve.init_guest_mem();
-
 		Ok(hyve)
 	}
+
+	/// Enables or disables the dirty page logging of KVM for the guest memory
+	/// in the guest-physical address range `region`. KVM logs whole memory
+	/// slots, so the logging covers all slots, which overlap `region`.
+	pub fn set_dirty_logging(&self, region: Range<usize>, enable: bool) -> Result<()> {
+		set_dirty_logging(&self.vm, self.mem.as_ref(), region, enable)
+	}
+
+	/// Returns the pages in the memory slots overlapping `region`, which the
+	/// guest has written since the dirty page logging was enabled or since the
+	/// last call, and clears the dirty bitmap.
+	pub fn get_dirty_log(&self, region: Range<usize>) -> Result<DirtyLog> {
+		get_dirty_log(&self.vm, self.mem.as_ref(), region)
+	}
+
+	/// Creates a VM from the snapshot at `path`. The vCPUs have to be created
+	/// by `restore_cpu` with the returned states. vCPUs without a state
+	/// aren't started by the guest yet and are created like in a new VM.
+	///
+	/// Without an explicit memory backing in `specs`, the guest memory is
+	/// mapped copy-on-write from the snapshot file. Then, pages are loaded
+	/// lazily and many VMs are able to share the unmodified pages of one
+	/// snapshot.
+	pub fn restore(
+		path: &Path,
+		specs: &Parameter<'_>,
+		dbg: Option<DebugManager>,
+	) -> Result<(Uhyve, Vec<Option<VcpuState>>)> {
+		let (snapshot, file, mem_offset) = Snapshot::read(path)?;
+		// the vCPU states are only valid for the CPUID of the snapshot
+		if let Some(model) = specs.cpu_model {
+			if model != snapshot.cpu_model {
+				return Err(Error::Snapshot(format!(
+					"the snapshot requires the CPU model {}, not {}",
+					snapshot.cpu_model, model
+				)));
+			}
+		}
+		// the TSC of the vCPUs has to keep its frequency
+		if let (Some(khz), Some(saved)) = (specs.tsc_khz, snapshot.tsc_khz) {
+			if khz != saved {
+				return Err(Error::Snapshot(format!(
+					"the snapshot requires a TSC frequency of {} kHz, not {} kHz",
+					saved, khz
+				)));
+			}
+		}
+		let specs = Parameter {
+			mem_size: snapshot.mem_size,
+			num_cpus: snapshot.num_cpus,
+			cpu_model: Some(&snapshot.cpu_model),
+			tsc_khz: snapshot.tsc_khz.or(specs.tsc_khz),
+			..*specs
+		};
+
+		let mem: Box<dyn MemoryRegion> =
+			if specs.mem_path.is_none() && specs.hugetlb.is_none() && !specs.memfd {
+				debug!("Map guest memory copy-on-write from {}", path.display());
+				Box::new(MmapMemory::from_file(
+					0,
+					snapshot.mem_size,
+					0,
+					&file,
+					mem_offset,
+				)?)
+			} else {
+				let mem = Uhyve::create_memory(&specs)?;
+				let guest_mem = unsafe {
+					slice::from_raw_parts_mut(mem.host_address() as *mut u8, mem.memory_size())
+				};
+				file.read_exact_at(guest_mem, mem_offset)
+					.map_err(|_| Error::InvalidFile(path.to_path_buf()))?;
+				mem
+			};
+		let mut hyve = Uhyve::with_memory(snapshot.kernel_path.clone(), &specs, dbg, mem)?;
+
+		snapshot.irqchip.restore(&hyve.vm)?;
+		hyve.entry_point = snapshot.entry_point;
+		hyve.boot_info = (hyve.mem.host_address() + snapshot.boot_info as usize) as *const BootInfo;
+		let started = snapshot
+			.vcpus
+			.iter()
+			.map(|vcpu| vcpu.is_some())
+			.collect::<Vec<_>>();
+		hyve.cpu_startup
+			.restore(&started, snapshot.explicit_startup);
+		let vm_start = hyve.mem.host_address();
+		let regions = guest_mem_regions(hyve.mem.memory_size());
+		hyve.virtio_device
+			.lock()
+			.unwrap()
+			.restore_state(&snapshot.virtio, |addr, len| {
+				let is_mapped = regions.iter().any(|region| {
+					region.start <= addr
+						&& addr.checked_add(len).map_or(false, |end| end <= region.end)
+				});
+				if is_mapped {
+					Some(guest_phys_to_offset(addr) + vm_start)
+				} else {
+					None
+				}
+			})?;
+		hyve.serial.lock().unwrap().restore_state(&snapshot.serial);
+		hyve.rtc.restore_state(&snapshot.rtc)?;
+		for file in snapshot.files.iter() {
+			file.reopen(&hyve.files)?;
+		}
+		hyve.update_network_identity();
+		if let Some(flag) = snapshot.restored_flag {
+			hyve.write_guest_mem(flag as usize, &1i32.to_ne_bytes())?;
+		}
+
+		Ok((hyve, snapshot.vcpus))
+	}
+
+	/// Returns the console of the guest.
+	pub fn console(&self) -> &Console {
+		&self.console
+	}
+
+	/// Replaces the arguments and environment variables, which the guest
+	/// gets from now on. By default, these are the ones of the uhyve process.
+	pub fn set_command(&mut self, command: GuestCommand) {
+		self.command = command;
+	}
+
+	/// Replaces the network configuration of the guest, where `None` keeps
+	/// the current value. For a restored VM, the configuration is written to
+	/// the boot information.
+	pub fn set_network_identity(
+		&mut self,
+		ip: Option<Ipv4Addr>,
+		gateway: Option<Ipv4Addr>,
+		mask: Option<Ipv4Addr>,
+	) {
+		self.ip = ip.or(self.ip);
+		self.gateway = gateway.or(self.gateway);
+		self.mask = mask.or(self.mask);
+		self.update_network_identity();
+	}
+
+	/// Writes the network configuration of this instance (`--ip`, `--gateway`
+	/// and `--mask`) to the boot information of a restored VM. The guest
+	/// observes it, if it reads the boot information after the snapshot.
+	fn update_network_identity(&self) {
+		if self.boot_info.is_null() {
+			return;
+		}
+
+		let boot_info = self.boot_info as *mut BootInfo;
+		unsafe {
+			if let Some(ip) = self.ip {
+				ptr::write(&mut (*boot_info).hcip, ip.octets());
+			}
+			if let Some(gateway) = self.gateway {
+				ptr::write(&mut (*boot_info).hcgateway, gateway.octets());
+			}
+			if let Some(mask) = self.mask {
+				ptr::write(&mut (*boot_info).hcmask, mask.octets());
+			}
+		}
+	}
+
+	/// Copies `data` to the guest-physical address `addr`. Hooks use it to
+	/// customize a restored VM before its vCPUs are started.
+	pub fn write_guest_mem(&self, addr: usize, data: &[u8]) -> Result<()> {
+		let is_mapped = guest_mem_regions(self.mem.memory_size())
+			.iter()
+			.any(|region| region.start <= addr && addr + data.len() <= region.end);
+		if !is_mapped {
+			return Err(Error::InvalidArgument(format!(
+				"guest memory 0x{:x}..0x{:x} isn't mapped",
+				addr,
+				addr + data.len()
+			)));
+		}
+
+		unsafe {
+			ptr::copy_nonoverlapping(
+				data.as_ptr(),
+				(self.mem.host_address() + guest_phys_to_offset(addr)) as *mut u8,
+				data.len(),
+			);
+		}
+
+		Ok(())
+	}
+
+	/// Creates the vCPU `id` and restores its state from a snapshot.
+	pub fn restore_cpu(&self, id: u32, state: &VcpuState) -> Result<Box<dyn VirtualCPU>> {
+		let mut cpu = self.create_uhyve_cpu(id)?;
+		cpu.restore_state(state)?;
+
+		Ok(Box::new(cpu))
+	}
+
+	/// Returns the coordinator of the vCPUs for snapshots, if snapshots are enabled.
+	pub fn snapshot_control(&self) -> Option<Arc<SnapshotControl>> {
+		self.snapshot.clone()
+	}
+
+	/// Returns the watchdog of the vCPUs, if the watchdog is enabled.
+	pub fn watchdog(&self) -> Option<Arc<Watchdog>> {
+		self.watchdog.clone()
+	}
+
+	/// Returns the profiler of the guest, if profiling is enabled.
+	pub fn profiler(&self) -> Option<Arc<Profiler>> {
+		self.profiler.clone()
+	}
+
+	/// Returns the start address of the kernel image. Returns 0 before the
+	/// kernel is loaded.
+	pub fn kernel_base(&self) -> u64 {
+		if self.boot_info.is_null() {
+			0
+		} else {
+			unsafe { read_volatile(&(*self.boot_info).base) }
+		}
+	}
+
+	/// Pauses all vCPUs and writes a snapshot of the VM to `path`.
+	pub fn snapshot(&self, path: &Path) -> Result<()> {
+		let control = self
+			.snapshot
+			.as_ref()
+			.ok_or_else(|| Error::Snapshot("snapshots aren't enabled".to_string()))?;
+
+		let vcpus = control.pause_all(&self.vcpu_threads, &self.cpu_startup, &self.shutdown)?;
+		let result = self.write_snapshot(path, vcpus);
+		control.resume_all();
+
+		result
+	}
+
+	fn write_snapshot(&self, path: &Path, vcpus: Vec<Option<VcpuState>>) -> Result<()> {
+		// the boot information is located in the low memory => offset == guest-physical address
+		let boot_info = if self.boot_info.is_null() {
+			0
+		} else {
+			(self.boot_info as usize - self.mem.host_address()) as u64
+		};
+		let snapshot = Snapshot {
+			kernel_path: self.path.clone(),
+			mem_size: self.mem.memory_size(),
+			num_cpus: self.num_cpus,
+			cpu_model: self.cpu_model.clone(),
+			tsc_khz: self.tsc_khz,
+			entry_point: self.entry_point,
+			boot_info,
+			irqchip: IrqchipState::save(&self.vm)?,
+			vcpus,
+			explicit_startup: self.cpu_startup.is_explicit(),
+			virtio: self.virtio_device.lock().unwrap().save_state(),
+			serial: self.serial.lock().unwrap().save_state(),
+			rtc: self.rtc.save_state(),
+			files: FileState::save_all(&self.files),
+			restored_flag: self
+				.snapshot
+				.as_ref()
+				.and_then(|control| control.restored_flag()),
+		};
+		let guest_mem = unsafe {
+			slice::from_raw_parts(self.mem.host_address() as *const u8, self.mem.memory_size())
+		};
+
+		snapshot.write(path, guest_mem)
+	}
+
+	fn create_uhyve_cpu(&self, id: u32) -> Result<UhyveCPU> {
+		let vm_start = self.mem.host_address() as usize;
+		let tx = self.uhyve_device.as_ref().map(|dev| dev.tx.clone());
+		let vcpu = self
+			.vm
+			.create_vcpu(id.try_into().unwrap())
+			.or_else(to_error)?;
+		let kvm_run = KvmRun::new(&vcpu)?;
+
+		Ok(UhyveCPU::new(
+			id,
+			self.path.clone(),
+			vcpu,
+			kvm_run,
+			vm_start,
+			tx,
+			self.virtio_device.clone(),
+			self.dbg.as_ref().cloned(),
+			self.snapshot.clone(),
+			self.cpu_startup.clone(),
+			self.shutdown.clone(),
+			self.vcpu_threads.clone(),
+			self.cpuid.clone(),
+			self.stats.clone(),
+			self.watchdog.clone(),
+			self.unknown_access,
+			self.console.clone(),
+			self.serial.clone(),
+			self.rtc.clone(),
+			self.tsc_khz,
+			self.profiler.clone(),
+			self.command.clone(),
+			self.files.clone(),
+		))
+	}
+
+	/// Returns the file descriptor of the file, which backs the guest memory.
+	/// Returns `None` if the guest memory is anonymous memory.
+	pub fn guest_mem_fd(&self) -> Option<RawFd> {
+		self.mem.fd()
+	}
+}
+
+/// Registers the guest memory at KVM. The guest memory is split at the
+/// 32-bit gap => one memory slot per region.
+fn set_memory_slot(vm: &VmFd, mem: &dyn MemoryRegion, slot: usize, flags: u32) -> Result<()> {
+	let region = &guest_mem_regions(mem.memory_size())[slot];
+	let kvm_mem = kvm_userspace_memory_region {
+		slot: slot as u32,
+		flags,
+		memory_size: region.len() as u64,
+		guest_phys_addr: (mem.guest_address() + region.start) as u64,
+		userspace_addr: (mem.host_address() + guest_phys_to_offset(region.start)) as u64,
+	};
+
+	unsafe { vm.set_user_memory_region(kvm_mem) }.or_else(to_error)
+}
+
+fn set_memory_slots(vm: &VmFd, mem: &dyn MemoryRegion, flags: u32) -> Result<()> {
+	for slot in 0..guest_mem_regions(mem.memory_size()).len() {
+		set_memory_slot(vm, mem, slot, flags)?;
+	}
+
+	Ok(())
+}
+
+/// Returns the memory slots of `mem`, which overlap the guest-physical
+/// address range `region`.
+fn overlapping_slots(mem: &dyn MemoryRegion, region: &Range<usize>) -> Result<Vec<usize>> {
+	let slots = guest_mem_regions(mem.memory_size())
+		.iter()
+		.enumerate()
+		.filter(|(_, slot)| slot.start < region.end && region.start < slot.end)
+		.map(|(slot, _)| slot)
+		.collect::<Vec<_>>();
+	if slots.is_empty() {
+		return Err(Error::InvalidArgument(format!(
+			"{:#x}..{:#x} isn't guest memory",
+			region.start, region.end
+		)));
+	}
+
+	Ok(slots)
+}
+
+fn set_dirty_logging(
+	vm: &VmFd,
+	mem: &dyn MemoryRegion,
+	region: Range<usize>,
+	enable: bool,
+) -> Result<()> {
+	let flags = if enable {
+		mem.flags() | KVM_MEM_LOG_DIRTY_PAGES
+	} else {
+		mem.flags()
+	};
+
+	for slot in overlapping_slots(mem, &region)? {
+		set_memory_slot(vm, mem, slot, flags)?;
+	}
+
+	Ok(())
+}
+
+fn get_dirty_log(vm: &VmFd, mem: &dyn MemoryRegion, region: Range<usize>) -> Result<DirtyLog> {
+	let regions = guest_mem_regions(mem.memory_size());
+	let mut log = DirtyLog::new();
+	for slot in overlapping_slots(mem, &region)? {
+		let bitmap = vm
+			.get_dirty_log(slot as u32, regions[slot].len())
+			.or_else(to_error)?;
+		log.add_slot(mem.guest_address() + regions[slot].start, bitmap);
+	}
+
+	Ok(log)
 }
 
 impl Vm for Uhyve {
@@ -317,21 +847,16 @@ impl Vm for Uhyve {
 		self.path.clone()
 	}
 
-	fn create_cpu(&self, id: u32) -> Result<Box<dyn VirtualCPU>> {
-		let vm_start = self.mem.host_address() as usize;
-		let tx = self.uhyve_device.as_ref().map(|dev| dev.tx.clone());
+	fn gib_pages(&self) -> bool {
+		has_1gib_pages(&self.cpuid)
+	}
 
-		Ok(Box::new(UhyveCPU::new(
-			id,
-			self.path.clone(),
-			self.vm
-				.create_vcpu(id.try_into().unwrap())
-				.or_else(to_error)?,
-			vm_start,
-			tx,
-			self.virtio_device.clone(),
-			self.dbg.as_ref().cloned(),
-		)))
+	fn tsc_khz(&self) -> Option<u32> {
+		self.tsc_khz
+	}
+
+	fn create_cpu(&self, id: u32) -> Result<Box<dyn VirtualCPU>> {
+		Ok(Box::new(self.create_uhyve_cpu(id)?))
 	}
 
 	fn set_boot_info(&mut self, header: *const BootInfo) {
@@ -345,103 +870,82 @@ impl Vm for Uhyve {
 			unsafe { read_volatile(&(*self.boot_info).cpu_online) }
 		}
 	}
+
+	fn cpu_startup(&self) -> &CpuStartup {
+		&self.cpu_startup
+	}
+
+	fn shutdown(&self) -> &Shutdown {
+		&self.shutdown
+	}
+
+	fn kick_cpus(&self) {
+		self.vcpu_threads.kick_all();
+	}
 }
 
 impl Drop for Uhyve {
 	fn drop(&mut self) {
 		debug!("Drop virtual machine");
+		self.rtc.stop();
 	}
 }
 
 unsafe impl Send for Uhyve {}
 unsafe impl Sync for Uhyve {}
 
-#[derive(Debug)]
-struct MmapMemory {
-	flags: u32,
-	memory_size: usize,
-	guest_address: usize,
-	host_address: usize,
-}
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use kvm_ioctls::VcpuExit;
 
-impl MmapMemory {
-	pub fn new(
-		flags: u32,
-		memory_size: usize,
-		guest_address: u64,
-		huge_pages: bool,
-		mergeable: bool,
-	) -> MmapMemory {
-		let host_address = unsafe {
-			mmap(
-				std::ptr::null_mut(),
-				memory_size,
-				ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
-				MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS | MapFlags::MAP_NORESERVE,
-				-1,
-				0,
+	#[test]
+	fn test_dirty_logging() {
+		if !crate::linux::tests::has_vm_support() {
+			return;
+		}
+
+		let vm = KVM.create_vm().unwrap();
+		let mem = MmapMemory::new(0, 0x10000, 0, false, false);
+		set_memory_slots(&vm, &mem, mem.flags()).unwrap();
+		assert!(set_dirty_logging(&vm, &mem, 0x10000..0x20000, true).is_err());
+		set_dirty_logging(&vm, &mem, 0x4000..0x6000, true).unwrap();
+
+		// mov byte [0x5000], 0x42; hlt
+		let code = [0xc6, 0x06, 0x00, 0x50, 0x42, 0xf4];
+		unsafe {
+			std::ptr::copy_nonoverlapping(
+				code.as_ptr(),
+				(mem.host_address() + 0x1000) as *mut u8,
+				code.len(),
 			)
-			.expect("mmap failed")
 		};
 
-		if mergeable {
-			debug!("Enable kernel feature to merge same pages");
-			unsafe {
-				if madvise(host_address, memory_size, MmapAdvise::MADV_MERGEABLE).is_err() {
-					panic!("madvise failed");
-				}
-			}
-		}
-
-		if huge_pages {
-			debug!("Uhyve uses huge pages");
-			unsafe {
-				if madvise(host_address, memory_size, MmapAdvise::MADV_HUGEPAGE).is_err() {
-					panic!("madvise failed");
-				}
+		let vcpu = vm.create_vcpu(0).unwrap();
+		let mut sregs = vcpu.get_sregs().unwrap();
+		sregs.cs.base = 0;
+		sregs.cs.selector = 0;
+		vcpu.set_sregs(&sregs).unwrap();
+		vcpu.set_regs(&kvm_regs {
+			rip: 0x1000,
+			rflags: 2,
+			..Default::default()
+		})
+		.unwrap();
+		loop {
+			match vcpu.run().unwrap() {
+				VcpuExit::Hlt => break,
+				exit => panic!("unexpected exit {:?}", exit),
 			}
 		}
+		assert_eq!(
+			unsafe { *((mem.host_address() + 0x5000) as *const u8) },
+			0x42
+		);
 
-		MmapMemory {
-			flags,
-			memory_size,
-			guest_address: guest_address as usize,
-			host_address: host_address as usize,
-		}
-	}
-
-	#[allow(dead_code)]
-	fn as_slice_mut(&mut self) -> &mut [u8] {
-		unsafe { std::slice::from_raw_parts_mut(self.host_address as *mut u8, self.memory_size) }
-	}
-}
-
-impl MemoryRegion for MmapMemory {
-	fn flags(&self) -> u32 {
-		self.flags
-	}
-
-	fn memory_size(&self) -> usize {
-		self.memory_size
-	}
-
-	fn guest_address(&self) -> usize {
-		self.guest_address
-	}
-
-	fn host_address(&self) -> usize {
-		self.host_address
-	}
-}
-
-impl Drop for MmapMemory {
-	fn drop(&mut self) {
-		if self.memory_size() > 0 {
-			unsafe {
-				if munmap(self.host_address() as *mut c_void, self.memory_size()).is_err() {
-					panic!("munmap failed");
-				}
-			}
-		}
+		let log = get_dirty_log(&vm, &mem, 0..0x10000).unwrap();
+		assert_eq!(log.pages().collect::<Vec<_>>(), vec![0x5000]);
+		// reading the log clears it
+		assert_eq!(get_dirty_log(&vm, &mem, 0..0x10000).unwrap().count(), 0);
 	}
 }
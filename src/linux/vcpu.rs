@@ -1,160 +1,166 @@
+use crate::acpi;
+use crate::console::Console;
 use crate::consts::*;
 use crate::debug_manager::DebugManager;
 use crate::error::Error::*;
 use crate::error::*;
+use crate::linux::kick::VcpuThreads;
+use crate::linux::kvm_run::{KvmExit, KvmRun};
+use crate::linux::msr;
+use crate::linux::profiler::Profiler;
+use crate::linux::rtc::Rtc;
+use crate::linux::serial::Serial;
+use crate::linux::snapshot::{SnapshotControl, VcpuState};
+use crate::linux::tsc;
 use crate::linux::virtio::*;
-use crate::linux::KVM;
+use crate::linux::watchdog::Watchdog;
 use crate::paging::*;
-use crate::vm::VirtualCPU;
+use crate::stats::{CpuStats, VmStats};
+use crate::vm::{
+	debug_exit_code, guest_phys_to_offset, CpuStartup, GuestCommand, GuestFiles, Shutdown,
+	SnapshotTrigger, UnknownAccess, UnknownAccessPolicy, VirtualCPU,
+};
 use kvm_bindings::*;
 use kvm_ioctls::{VcpuExit, VcpuFd};
 use libc::ioctl;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use std::os::unix::io::AsRawFd;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use x86::controlregs::*;
 
-const CPUID_EXT_HYPERVISOR: u32 = 1 << 31;
-const CPUID_TSC_DEADLINE: u32 = 1 << 24;
-const CPUID_ENABLE_MSR: u32 = 1 << 5;
-const MSR_IA32_MISC_ENABLE: u32 = 0x000001a0;
 const PCI_CONFIG_DATA_PORT: u16 = 0xCFC;
 const PCI_CONFIG_ADDRESS_PORT: u16 = 0xCF8;
 
+/// Snapshot request of the guest, which waits until the snapshot is
+/// written. `restored` is set to 1 in the VMs, which are restored from it.
+#[repr(C, packed)]
+struct SysSnapshot {
+	restored: i32,
+}
+
 pub struct UhyveCPU {
 	id: u32,
 	vcpu: VcpuFd,
+	kvm_run: KvmRun,
 	vm_start: usize,
 	kernel_path: PathBuf,
 	tx: Option<std::sync::mpsc::SyncSender<usize>>,
 	virtio_device: Arc<Mutex<VirtioNetPciDevice>>,
 	pub dbg: Option<Arc<Mutex<DebugManager>>>,
+	snapshot: Option<Arc<SnapshotControl>>,
+	cpu_startup: Arc<CpuStartup>,
+	shutdown: Arc<Shutdown>,
+	vcpu_threads: Arc<VcpuThreads>,
+	cpuid: Arc<CpuId>,
+	stats: Option<Arc<VmStats>>,
+	watchdog: Option<Arc<Watchdog>>,
+	unknown_access: UnknownAccessPolicy,
+	console: Arc<Console>,
+	serial: Arc<Mutex<Serial>>,
+	rtc: Arc<Rtc>,
+	tsc_khz: Option<u32>,
+	profiler: Option<Arc<Profiler>>,
+	command: GuestCommand,
+	files: Arc<GuestFiles>,
 }
 
 impl UhyveCPU {
+	#[allow(clippy::too_many_arguments)]
 	pub fn new(
 		id: u32,
 		kernel_path: PathBuf,
 		vcpu: VcpuFd,
+		kvm_run: KvmRun,
 		vm_start: usize,
 		tx: Option<std::sync::mpsc::SyncSender<usize>>,
 		virtio_device: Arc<Mutex<VirtioNetPciDevice>>,
 		dbg: Option<Arc<Mutex<DebugManager>>>,
+		snapshot: Option<Arc<SnapshotControl>>,
+		cpu_startup: Arc<CpuStartup>,
+		shutdown: Arc<Shutdown>,
+		vcpu_threads: Arc<VcpuThreads>,
+		cpuid: Arc<CpuId>,
+		stats: Option<Arc<VmStats>>,
+		watchdog: Option<Arc<Watchdog>>,
+		unknown_access: UnknownAccessPolicy,
+		console: Arc<Console>,
+		serial: Arc<Mutex<Serial>>,
+		rtc: Arc<Rtc>,
+		tsc_khz: Option<u32>,
+		profiler: Option<Arc<Profiler>>,
+		command: GuestCommand,
+		files: Arc<GuestFiles>,
 	) -> UhyveCPU {
 		UhyveCPU {
 			id,
 			vcpu,
+			kvm_run,
 			vm_start,
 			kernel_path,
 			tx,
 			virtio_device,
 			dbg,
+			snapshot,
+			cpu_startup,
+			shutdown,
+			vcpu_threads,
+			cpuid,
+			stats,
+			watchdog,
+			unknown_access,
+			console,
+			serial,
+			rtc,
+			tsc_khz,
+			profiler,
+			command,
+			files,
 		}
 	}
 
-	fn setup_cpuid(&self) -> Result<()> {
-		//debug!("Setup cpuid");
-
-		let mut kvm_cpuid = KVM
-			.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)
-			.or_else(to_error)?;
-		let kvm_cpuid_entries = kvm_cpuid.as_mut_slice();
-		let i = kvm_cpuid_entries
-			.iter()
-			.position(|&r| r.function == 0x80000002)
-			.unwrap();
-
-		// create own processor string (first part)
-		let mut id_reg_values: [u32; 4] = [0; 4];
-		let id = b"uhyve - unikerne";
-		unsafe {
-			std::ptr::copy_nonoverlapping(
-				id.as_ptr(),
-				id_reg_values.as_mut_ptr() as *mut u8,
-				id.len(),
-			);
-		}
-		kvm_cpuid_entries[i].eax = id_reg_values[0];
-		kvm_cpuid_entries[i].ebx = id_reg_values[1];
-		kvm_cpuid_entries[i].ecx = id_reg_values[2];
-		kvm_cpuid_entries[i].edx = id_reg_values[3];
-
-		let i = kvm_cpuid_entries
-			.iter()
-			.position(|&r| r.function == 0x80000003)
-			.unwrap();
-
-		// create own processor string (second part)
-		let id = b"l hypervisor\0";
-		unsafe {
-			std::ptr::copy_nonoverlapping(
-				id.as_ptr(),
-				id_reg_values.as_mut_ptr() as *mut u8,
-				id.len(),
-			);
+	/// Returns the state of the vCPU, which is stored in a snapshot. The
+	/// I/O of the last exit is completed before, so that a restored vCPU
+	/// doesn't repeat it.
+	pub fn save_state(&self) -> Result<VcpuState> {
+		// KVM completes a pending I/O instruction not before the next KVM_RUN.
+		// Without it, the restored guest would repeat the instruction.
+		self.vcpu.set_kvm_immediate_exit(1);
+		let result = self.kvm_run.run(&self.vcpu).map(|_| ());
+		self.vcpu.set_kvm_immediate_exit(0);
+		match result {
+			Err(err) if err.errno() != libc::EINTR => return to_error(err),
+			_ => {}
 		}
-		kvm_cpuid_entries[i].eax = id_reg_values[0];
-		kvm_cpuid_entries[i].ebx = id_reg_values[1];
-		kvm_cpuid_entries[i].ecx = id_reg_values[2];
-		kvm_cpuid_entries[i].edx = id_reg_values[3];
-
-		let i = kvm_cpuid_entries
-			.iter()
-			.position(|&r| r.function == 0x80000004)
-			.unwrap();
-
-		// create own processor string (third part)
-		kvm_cpuid_entries[i].eax = 0;
-		kvm_cpuid_entries[i].ebx = 0;
-		kvm_cpuid_entries[i].ecx = 0;
-		kvm_cpuid_entries[i].edx = 0;
-
-		let i = kvm_cpuid_entries
-			.iter()
-			.position(|&r| r.function == 1)
-			.unwrap();
-
-		// CPUID to define basic cpu features
-		kvm_cpuid_entries[i].ecx |= CPUID_EXT_HYPERVISOR; // propagate that we are running on a hypervisor
-		kvm_cpuid_entries[i].ecx |= CPUID_TSC_DEADLINE; // enable TSC deadline feature
-		kvm_cpuid_entries[i].edx |= CPUID_ENABLE_MSR; // enable msr support
-
-		let i = kvm_cpuid_entries
-			.iter()
-			.position(|&r| r.function == 0x0A)
-			.unwrap();
-
-		// disable performance monitor
-		kvm_cpuid_entries[i].eax = 0x00;
-
-		self.vcpu.set_cpuid2(&kvm_cpuid).or_else(to_error)?;
 
-		Ok(())
+		VcpuState::save(&self.vcpu)
 	}
 
-	fn setup_msrs(&self) -> Result<()> {
-		//debug!("Setup MSR");
-
-		let msr_list = KVM.get_msr_index_list().or_else(to_error)?;
+	/// Restores the state of the vCPU from a snapshot instead of `init`.
+	pub fn restore_state(&mut self, state: &VcpuState) -> Result<()> {
+		self.setup_cpuid()?;
+		self.setup_tsc()?;
+		state.restore(&self.vcpu)
+	}
 
-		let mut msr_entries = msr_list
-			.as_slice()
-			.iter()
-			.map(|i| kvm_msr_entry {
-				index: *i,
-				data: 0,
-				..Default::default()
-			})
-			.collect::<Vec<_>>();
+	fn setup_cpuid(&self) -> Result<()> {
+		self.vcpu.set_cpuid2(&self.cpuid).or_else(to_error)
+	}
 
-		// enable fast string operations
-		msr_entries[0].index = MSR_IA32_MISC_ENABLE;
-		msr_entries[0].data = 1;
+	fn setup_tsc(&self) -> Result<()> {
+		match self.tsc_khz {
+			Some(tsc_khz) => tsc::set_tsc_khz(&self.vcpu, tsc_khz),
+			None => Ok(()),
+		}
+	}
 
-		let msrs = Msrs::from_entries(&msr_entries)
-			.expect("Unable to create initial values for the machine specific registers");
-		self.vcpu.set_msrs(&msrs).or_else(to_error)?;
+	fn setup_msrs(&self) -> Result<()> {
+		let msrs = msr::initial_msrs()?;
+		let count = self.vcpu.set_msrs(&msrs).or_else(to_error)?;
+		if let Some(msr) = msrs.as_slice().get(count) {
+			error!("Unable to initialize MSR 0x{:x}", msr.index);
+			return Err(OsError(libc::EINVAL));
+		}
 
 		Ok(())
 	}
@@ -215,90 +221,126 @@ impl UhyveCPU {
 		Ok(())
 	}
 
-	fn show_dtable(name: &str, dtable: &kvm_dtable) {
-		println!("{}                 {:?}", name, dtable);
-	}
-
-	fn show_segment(name: &str, seg: &kvm_segment) {
-		println!("{}       {:?}", name, seg);
-	}
-
-	pub fn get_vcpu(&self) -> &VcpuFd {
-		&self.vcpu
-	}
-
-	pub fn get_vcpu_mut(&mut self) -> &mut VcpuFd {
-		&mut self.vcpu
-	}
-}
-
-impl VirtualCPU for UhyveCPU {
-	fn init(&mut self, entry_point: u64) -> Result<()> {
-		self.setup_long_mode(entry_point)?;
-		self.setup_cpuid()?;
-
-		// be sure that the multiprocessor is runable
-		let mp_state = kvm_mp_state {
-			mp_state: KVM_MP_STATE_RUNNABLE,
-		};
-		let ret = unsafe {
-			ioctl(
-				self.vcpu.as_raw_fd(),
-				0x4004ae99, /* KVM_SET_MP_STATE */
-				&mp_state,
-			)
-		};
-		if ret < 0 {
-			return Err(OsError(unsafe { *libc::__errno_location() }));
+	/// Records a sample for the watchdog and dumps the state of the vCPU if
+	/// the watchdog has detected that it hangs.
+	fn check_watchdog(&self) -> Result<()> {
+		if let Some(watchdog) = &self.watchdog {
+			let regs = self.vcpu.get_regs().or_else(to_error)?;
+			let halted =
+				self.vcpu.get_mp_state().or_else(to_error)?.mp_state == KVM_MP_STATE_HALTED;
+			if watchdog.interrupted(self.id, regs.rip, halted) {
+				error!("CPU {} makes no progress", self.id);
+				self.print_registers();
+				self.print_backtrace(regs.rip, regs.rbp);
+				watchdog.dumped(self.id);
+			}
 		}
 
-		self.setup_msrs()?;
-
 		Ok(())
 	}
 
-	fn kernel_path(&self) -> PathBuf {
-		self.kernel_path.clone()
-	}
+	/// Records the stack of the guest as a sample, if the profiler has
+	/// requested it.
+	fn check_profiler(&self) -> Result<()> {
+		if let Some(profiler) = &self.profiler {
+			if profiler.sample_requested(self.id) {
+				let regs = self.vcpu.get_regs().or_else(to_error)?;
+				profiler.record(self.id, self.backtrace(regs.rip, regs.rbp));
+			}
+		}
 
-	fn host_address(&self, addr: usize) -> usize {
-		addr + self.vm_start
+		Ok(())
 	}
 
-	fn virt_to_phys(&self, addr: usize) -> usize {
-		let executable_disable_mask: usize = !PageTableEntryFlags::EXECUTE_DISABLE.bits();
-		let mut page_table = self.host_address(BOOT_PML4 as usize) as *const usize;
-		let mut page_bits = 39;
-		let mut entry: usize = 0;
-
-		for _i in 0..4 {
-			let index = (addr >> page_bits) & ((1 << PAGE_MAP_BITS) - 1);
-			entry = unsafe { *page_table.add(index) & executable_disable_mask };
+	/// Handles an access to an I/O port or MMIO address, which uhyve doesn't
+	/// emulate, by the policy of the VM. `data` contains the written data or
+	/// the result of a read. Returns true if the vCPU has to trap to the
+	/// debugger.
+	fn unknown_access(&self, addr: u64, mmio: bool, write: bool, data: &[u8]) -> Result<bool> {
+		let access = UnknownAccess {
+			addr,
+			mmio,
+			write,
+			size: data.len(),
+			data: if write { data.to_vec() } else { Vec::new() },
+			rip: self.vcpu.get_regs().or_else(to_error)?.rip,
+		};
 
-			// bit 7 is set if this entry references a 1 GiB (PDPT) or 2 MiB (PDT) page.
-			if entry & PageTableEntryFlags::HUGE_PAGE.bits() != 0 {
-				return (entry & ((!0usize) << page_bits)) | (addr & !((!0usize) << page_bits));
-			} else {
-				page_table = self.host_address(entry & !((1 << PAGE_BITS) - 1)) as *const usize;
-				page_bits -= PAGE_MAP_BITS;
+		match self.unknown_access {
+			UnknownAccessPolicy::Strict => {
+				error!("CPU {}: unhandled {}", self.id, access);
+				self.print_registers();
+				Err(Error::UnknownAccess(access.to_string()))
+			}
+			UnknownAccessPolicy::Ignore => {
+				warn!("CPU {}: ignore {}", self.id, access);
+				Ok(false)
+			}
+			UnknownAccessPolicy::Debug => {
+				warn!("CPU {}: trap on {}", self.id, access);
+				Ok(self.dbg.is_some())
 			}
 		}
-
-		(entry & ((!0usize) << PAGE_BITS)) | (addr & !((!0usize) << PAGE_BITS))
 	}
 
-	fn run(&mut self) -> Result<Option<i32>> {
-		//self.print_registers();
-
-		// Pause first CPU before first execution, so we have time to attach debugger
-		if self.id == 0 {
-			self.gdb_handle_exception(None);
+	/// Prints the crash report of a guest, which has reported a panic, and
+	/// returns the error, which stops the VM.
+	fn guest_panic(&self) -> Error {
+		error!("The guest has panicked on CPU {}", self.id);
+		self.print_registers();
+		match self.vcpu.get_regs() {
+			Ok(regs) => self.print_backtrace(regs.rip, regs.rbp),
+			Err(err) => error!("Unable to read the registers: {}", err),
 		}
 
+		GuestPanic
+	}
+
+	/// Runs the guest until the vCPU exits (`Ok(Some(exit_code))`), is stopped
+	/// by `Shutdown` or crashes. Counts the exits in `stats`.
+	fn run_loop(&mut self, mut stats: Option<&mut CpuStats>) -> Result<Option<i32>> {
 		let mut pci_addr: u32 = 0;
 		let mut pci_addr_set: bool = false;
 		loop {
-			let exitreason = self.vcpu.run().or_else(to_error)?;
+			if self.shutdown.is_requested() {
+				debug!("Stop CPU {}", self.id);
+				return Ok(None);
+			}
+
+			if let Some(snapshot) = self.snapshot.clone() {
+				if snapshot.is_paused() {
+					snapshot.pause(self.id, || self.save_state())?;
+				}
+			}
+
+			let exit = match self.kvm_run.run(&self.vcpu) {
+				Ok(exit) => exit,
+				// the vCPU was kicked out of the guest (e.g. to take a snapshot)
+				Err(err) if err.errno() == libc::EINTR => {
+					self.vcpu.set_kvm_immediate_exit(0);
+					self.check_watchdog()?;
+					self.check_profiler()?;
+					continue;
+				}
+				Err(err) => return to_error(err),
+			};
+			if let Some(watchdog) = &self.watchdog {
+				watchdog.exit(self.id);
+			}
+			let _timer = stats
+				.as_deref_mut()
+				.map(|stats| stats.start(exit.name(), exit.port()));
+			let exitreason = match exit {
+				KvmExit::Vcpu(exitreason) => exitreason,
+				KvmExit::RdMsr(msr) => {
+					msr::emulate_rdmsr(self.id, msr);
+					continue;
+				}
+				KvmExit::WrMsr(msr) => {
+					msr::emulate_wrmsr(self.id, msr);
+					continue;
+				}
+			};
 			match exitreason {
 				VcpuExit::Hlt => {
 					debug!("Halt Exit");
@@ -309,14 +351,16 @@ impl VirtualCPU for UhyveCPU {
 					debug!("Shutdown Exit");
 					break;
 				}
-				VcpuExit::MmioRead(addr, _) => {
-					debug!("KVM: read at 0x{:x}", addr);
-					break;
+				VcpuExit::MmioRead(addr, data) => {
+					data.fill(0xff);
+					if self.unknown_access(addr, true, false, data)? {
+						self.gdb_handle_exception(Some(VcpuExit::Debug));
+					}
 				}
-				VcpuExit::MmioWrite(addr, _) => {
-					debug!("KVM: write at 0x{:x}", addr);
-					self.print_registers();
-					break;
+				VcpuExit::MmioWrite(addr, data) => {
+					if self.unknown_access(addr, true, true, data)? {
+						self.gdb_handle_exception(Some(VcpuExit::Debug));
+					}
 				}
 				VcpuExit::IoIn(port, addr) => match port {
 					PCI_CONFIG_DATA_PORT => {
@@ -331,6 +375,21 @@ impl VirtualCPU for UhyveCPU {
 						}
 					}
 					PCI_CONFIG_ADDRESS_PORT => {}
+					PVPANIC_PORT => {
+						addr[0] = PVPANIC_PANICKED | PVPANIC_CRASH_LOADED;
+					}
+					// no ACPI events
+					PM1A_EVT_PORT..=PM1A_EVT_PORT_MAX => addr.fill(0),
+					PM1A_CNT_PORT => {
+						addr.fill(0);
+						addr[0] = acpi::SCI_EN as u8;
+					}
+					COM1_PORT..=COM1_PORT_MAX => {
+						addr[0] = self.serial.lock().unwrap().read(port - COM1_PORT);
+					}
+					CMOS_INDEX_PORT | CMOS_DATA_PORT => {
+						addr[0] = self.rtc.read(port);
+					}
 					VIRTIO_PCI_STATUS => {
 						let virtio_device = self.virtio_device.lock().unwrap();
 						virtio_device.read_status(addr);
@@ -356,17 +415,48 @@ impl VirtualCPU for UhyveCPU {
 						virtio_device.read_link_status(addr);
 					}
 					_ => {
-						info!("Unhanded IO Exit");
+						addr.fill(0xff);
+						if self.unknown_access(port.into(), false, false, addr)? {
+							self.gdb_handle_exception(Some(VcpuExit::Debug));
+						}
 					}
 				},
 				VcpuExit::IoOut(port, addr) => {
 					match port {
-						#[allow(clippy::cast_ptr_alignment)]
 						SHUTDOWN_PORT => {
-							return Ok(None);
+							let value = addr
+								.iter()
+								.rev()
+								.fold(0, |value, byte| (value << 8) | u32::from(*byte));
+							return Ok(Some(debug_exit_code(value)));
+						}
+						PM1A_EVT_PORT..=PM1A_EVT_PORT_MAX => {}
+						PM1A_CNT_PORT => {
+							let value = addr
+								.iter()
+								.rev()
+								.fold(0, |value, byte| (value << 8) | u16::from(*byte));
+							if acpi::is_power_off(value) {
+								debug!("ACPI power-off by CPU {}", self.id);
+								return Ok(Some(0));
+							}
+						}
+						PVPANIC_PORT => {
+							if addr[0] & PVPANIC_PANICKED != 0 {
+								return Err(self.guest_panic());
+							}
+							if addr[0] & PVPANIC_CRASH_LOADED != 0 {
+								info!("The guest has loaded a crash kernel");
+							}
 						}
 						UHYVE_UART_PORT => {
-							self.uart(addr).unwrap();
+							self.console.write(addr).unwrap();
+						}
+						COM1_PORT..=COM1_PORT_MAX => {
+							self.serial.lock().unwrap().write(port - COM1_PORT, addr[0]);
+						}
+						CMOS_INDEX_PORT | CMOS_DATA_PORT => {
+							self.rtc.write(port, addr[0]);
 						}
 						UHYVE_PORT_CMDSIZE => {
 							let data_addr: usize =
@@ -405,6 +495,31 @@ impl VirtualCPU for UhyveCPU {
 								unsafe { (*(addr.as_ptr() as *const u32)) as usize };
 							self.read(self.host_address(data_addr))?;
 						}
+						UHYVE_PORT_SNAPSHOT => match &self.snapshot {
+							Some(snapshot) if snapshot.trigger() == SnapshotTrigger::Hypercall => {
+								let data_addr: usize =
+									unsafe { (*(addr.as_ptr() as *const u32)) as usize };
+								if data_addr == 0 {
+									snapshot.request();
+								} else {
+									let syssnapshot = unsafe {
+										&mut *(self.host_address(data_addr) as *mut SysSnapshot)
+									};
+									syssnapshot.restored = 0;
+									snapshot.request_and_pause(
+										self.id,
+										data_addr as u64,
+										|| self.save_state(),
+									)?;
+								}
+							}
+							_ => debug!("Ignore snapshot request of the guest"),
+						},
+						UHYVE_PORT_CPU_START => {
+							let data_addr: usize =
+								unsafe { (*(addr.as_ptr() as *const u32)) as usize };
+							self.cpu_start(self.host_address(data_addr), &self.cpu_startup)?;
+						}
 						UHYVE_PORT_UNLINK => {
 							let data_addr: usize =
 								unsafe { (*(addr.as_ptr() as *const u32)) as usize };
@@ -451,9 +566,10 @@ impl VirtualCPU for UhyveCPU {
 							let mut virtio_device = self.virtio_device.lock().unwrap();
 							virtio_device.write_pfn(addr, self);
 						}
-
 						_ => {
-							panic!("Unhandled IO exit: 0x{:x}", port);
+							if self.unknown_access(port.into(), false, true, addr)? {
+								self.gdb_handle_exception(Some(VcpuExit::Debug));
+							}
 						}
 					}
 				}
@@ -479,6 +595,122 @@ impl VirtualCPU for UhyveCPU {
 		Ok(None)
 	}
 
+	fn show_dtable(name: &str, dtable: &kvm_dtable) {
+		println!("{}                 {:?}", name, dtable);
+	}
+
+	fn show_segment(name: &str, seg: &kvm_segment) {
+		println!("{}       {:?}", name, seg);
+	}
+
+	pub fn get_vcpu(&self) -> &VcpuFd {
+		&self.vcpu
+	}
+
+	pub fn get_vcpu_mut(&mut self) -> &mut VcpuFd {
+		&mut self.vcpu
+	}
+}
+
+impl VirtualCPU for UhyveCPU {
+	fn init(&mut self, entry_point: u64) -> Result<()> {
+		self.setup_long_mode(entry_point)?;
+		self.setup_cpuid()?;
+
+		// be sure that the multiprocessor is runable
+		let mp_state = kvm_mp_state {
+			mp_state: KVM_MP_STATE_RUNNABLE,
+		};
+		let ret = unsafe {
+			ioctl(
+				self.vcpu.as_raw_fd(),
+				0x4004ae99, /* KVM_SET_MP_STATE */
+				&mp_state,
+			)
+		};
+		if ret < 0 {
+			return Err(OsError(unsafe { *libc::__errno_location() }));
+		}
+
+		self.setup_tsc()?;
+		self.setup_msrs()?;
+
+		Ok(())
+	}
+
+	fn kernel_path(&self) -> PathBuf {
+		self.kernel_path.clone()
+	}
+
+	fn console(&self) -> Option<&Arc<Console>> {
+		Some(&self.console)
+	}
+
+	fn command(&self) -> GuestCommand {
+		self.command.clone()
+	}
+
+	fn guest_files(&self) -> &GuestFiles {
+		&self.files
+	}
+
+	fn is_interrupted(&self) -> bool {
+		self.shutdown.is_requested()
+			|| self.snapshot.as_ref().map(|snapshot| snapshot.is_paused()) == Some(true)
+	}
+
+	fn host_address(&self, addr: usize) -> usize {
+		guest_phys_to_offset(addr) + self.vm_start
+	}
+
+	fn virt_to_phys(&self, addr: usize) -> usize {
+		let executable_disable_mask: usize = !PageTableEntryFlags::EXECUTE_DISABLE.bits();
+		let mut page_table = self.host_address(BOOT_PML4 as usize) as *const usize;
+		let mut page_bits = 39;
+		let mut entry: usize = 0;
+
+		for _i in 0..4 {
+			let index = (addr >> page_bits) & ((1 << PAGE_MAP_BITS) - 1);
+			entry = unsafe { *page_table.add(index) & executable_disable_mask };
+
+			// bit 7 is set if this entry references a 1 GiB (PDPT) or 2 MiB (PDT) page.
+			if entry & PageTableEntryFlags::HUGE_PAGE.bits() != 0 {
+				return (entry & ((!0usize) << page_bits)) | (addr & !((!0usize) << page_bits));
+			} else {
+				page_table = self.host_address(entry & !((1 << PAGE_BITS) - 1)) as *const usize;
+				page_bits -= PAGE_MAP_BITS;
+			}
+		}
+
+		(entry & ((!0usize) << PAGE_BITS)) | (addr & !((!0usize) << PAGE_BITS))
+	}
+
+	fn page_table(&self) -> u64 {
+		self.vcpu
+			.get_sregs()
+			.map(|sregs| sregs.cr3 & 0x000f_ffff_ffff_f000)
+			.unwrap_or(BOOT_PML4)
+	}
+
+	fn run(&mut self) -> Result<Option<i32>> {
+		//self.print_registers();
+
+		// Pause first CPU before first execution, so we have time to attach debugger
+		if self.id == 0 {
+			self.gdb_handle_exception(None);
+		}
+
+		let mut stats = self.stats.as_ref().map(|_| CpuStats::default());
+		self.vcpu_threads.register(self.id, &self.vcpu);
+		let result = self.run_loop(stats.as_mut());
+		self.vcpu_threads.unregister(self.id);
+		if let (Some(vm_stats), Some(stats)) = (&self.stats, &stats) {
+			vm_stats.add_cpu(self.id, stats);
+		}
+
+		result
+	}
+
 	fn print_registers(&self) {
 		let regs = self.vcpu.get_regs().unwrap();
 		let sregs = self.vcpu.get_sregs().unwrap();
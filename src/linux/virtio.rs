@@ -1,3 +1,4 @@
+use crate::error::*;
 use crate::linux::virtqueue::*;
 use crate::vm::VirtualCPU;
 use log::info;
@@ -52,11 +53,24 @@ pub trait PciDevice {
 
 type PciRegisters = [u8; 0x40];
 
+/// State of a `VirtioNetPciDevice`, which is part of a VM snapshot
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VirtioNetState {
+	pub registers: Vec<u8>,
+	pub requested_features: u32,
+	pub selected_queue_num: u16,
+	/// Guest-physical address and the last seen available and used index of
+	/// each virtqueue
+	pub queues: Vec<(u64, u16, u16)>,
+}
+
 pub struct VirtioNetPciDevice {
 	registers: PciRegisters, //Add more
 	requested_features: u32,
 	selected_queue_num: u16,
 	virt_queues: Vec<Virtqueue>,
+	/// Guest-physical addresses of the virtqueues
+	queue_addresses: Vec<u64>,
 	iface: Option<Mutex<Iface>>,
 	mac_addr: [u8; 6],
 }
@@ -115,11 +129,76 @@ impl VirtioNetPciDevice {
 			requested_features: 0,
 			selected_queue_num: 0,
 			virt_queues,
+			queue_addresses: Vec::new(),
 			iface: None,
 			mac_addr: [0; 6],
 		}
 	}
 
+	/// Returns the state of the device, which is stored in a snapshot.
+	pub fn save_state(&self) -> VirtioNetState {
+		VirtioNetState {
+			registers: self.registers.to_vec(),
+			requested_features: self.requested_features,
+			selected_queue_num: self.selected_queue_num,
+			queues: self
+				.virt_queues
+				.iter()
+				.zip(self.queue_addresses.iter())
+				.map(|(queue, gpa)| (*gpa, queue.last_seen_available, queue.last_seen_used))
+				.collect(),
+		}
+	}
+
+	/// Restores the state of the device from a snapshot. `host_address`
+	/// translates a range of guest-physical addresses to a host address and
+	/// returns `None` if the range isn't guest memory. If the driver has
+	/// already finished the handshake, a new TAP device is created.
+	pub fn restore_state<F: Fn(usize, usize) -> Option<usize>>(
+		&mut self,
+		state: &VirtioNetState,
+		host_address: F,
+	) -> Result<()> {
+		if state.registers.len() != self.registers.len() {
+			return Err(Error::Snapshot(format!(
+				"invalid virtio state with {} registers",
+				state.registers.len()
+			)));
+		}
+
+		let hvas = state
+			.queues
+			.iter()
+			.map(|(gpa, _, _)| {
+				host_address(*gpa as usize, get_queue_size_in_bytes()).ok_or_else(|| {
+					Error::Snapshot(format!(
+						"virtqueue at 0x{:x} is outside of the guest memory",
+						gpa
+					))
+				})
+			})
+			.collect::<Result<Vec<_>>>()?;
+
+		self.registers.copy_from_slice(&state.registers);
+		self.requested_features = state.requested_features;
+		self.selected_queue_num = state.selected_queue_num;
+		self.virt_queues.clear();
+		self.queue_addresses.clear();
+		for ((gpa, last_seen_available, last_seen_used), hva) in state.queues.iter().zip(hvas) {
+			let mut queue = unsafe { Virtqueue::new(hva as *mut u8, QUEUE_LIMIT) };
+			queue.last_seen_available = *last_seen_available;
+			queue.last_seen_used = *last_seen_used;
+			self.virt_queues.push(queue);
+			self.queue_addresses.push(*gpa);
+		}
+
+		if self.read_status_reg() & STATUS_DRIVER_OK != 0 {
+			self.open_iface();
+		}
+
+		Ok(())
+	}
+
 	pub fn _poll_rx(_device: &mut VirtioNetPciDevice) {
 		//TODO: how to read packets without synchronization issues
 	}
@@ -173,6 +252,7 @@ impl VirtioNetPciDevice {
 			self.requested_features = 0;
 			self.selected_queue_num = 0;
 			self.virt_queues.clear();
+			self.queue_addresses.clear();
 			self.iface = None;
 		} else if status == STATUS_DRIVER_NEEDS_RESET || status == 0 {
 			self.write_status_reset(dest);
@@ -236,18 +316,22 @@ impl VirtioNetPciDevice {
 	fn write_status_ok(&mut self, dest: &[u8]) {
 		if dest[0] == STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK | STATUS_DRIVER_OK {
 			self.write_status_reg(dest[0]);
-			self.iface = match Iface::new("", Mode::Tap) {
-				Ok(tap) => Some(Mutex::new(tap)),
-				Err(err) => {
-					info!("Error creating TAP device: {}", err);
-					self.registers[STATUS_REGISTER as usize] |= STATUS_DRIVER_NEEDS_RESET;
-					None
-				}
-			};
-			self.get_mac_addr();
+			self.open_iface();
 		}
 	}
 
+	fn open_iface(&mut self) {
+		self.iface = match Iface::new("", Mode::Tap) {
+			Ok(tap) => Some(Mutex::new(tap)),
+			Err(err) => {
+				info!("Error creating TAP device: {}", err);
+				self.registers[STATUS_REGISTER as usize] |= STATUS_DRIVER_NEEDS_RESET;
+				None
+			}
+		};
+		self.get_mac_addr();
+	}
+
 	fn write_status_reg(&mut self, status: u8) {
 		self.registers[STATUS_REGISTER as usize] = status;
 	}
@@ -277,6 +361,7 @@ impl VirtioNetPciDevice {
 			let hva = (*vcpu).host_address(gpa) as *mut u8;
 			let queue = unsafe { Virtqueue::new(hva, QUEUE_LIMIT) };
 			self.virt_queues.push(queue);
+			self.queue_addresses.push(gpa as u64);
 		}
 	}
 
@@ -331,3 +416,19 @@ impl PciDevice for VirtioNetPciDevice {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_restore_queue_outside_of_guest_mem() {
+		let mut device = VirtioNetPciDevice::new();
+		let mut state = device.save_state();
+		state.queues.push((0xdead_0000, 0, 0));
+
+		let restored = device.restore_state(&state, |_, _| None);
+		assert!(matches!(restored, Err(Error::Snapshot(_))));
+		assert!(device.save_state().queues.is_empty());
+	}
+}
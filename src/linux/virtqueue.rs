@@ -108,6 +108,11 @@ fn get_used_ring_offset() -> usize {
 	)
 }
 
+/// Size of a virtqueue with `QUEUE_LIMIT` entries in the guest memory
+pub fn get_queue_size_in_bytes() -> usize {
+	get_used_ring_offset() + size_of::<u16>() * 3 + size_of::<VringUsedElement>() * QUEUE_LIMIT
+}
+
 impl Virtqueue {
 	pub unsafe fn new(mem: *mut u8, queue_size: usize) -> Self {
 		#[allow(clippy::cast_ptr_alignment)]
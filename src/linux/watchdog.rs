@@ -0,0 +1,225 @@
+//! Detects vCPUs, which make no progress.
+//!
+//! The watchdog thread periodically kicks all vCPUs. A kicked vCPU records
+//! its RIP as a sample. A vCPU hangs if neither its RIP nor its number of
+//! exits have changed for the timeout. A halted vCPU waits for an interrupt
+//! and doesn't hang. Then, the hanging vCPUs dump their
+//! state and the VM is stopped.
+
+use crate::linux::kick;
+use crate::linux::uhyve::Uhyve;
+use crate::vm::{Vm, VmOutcome};
+use log::{debug, error};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Maximal time, which the watchdog waits for the dumps of the vCPUs
+const DUMP_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Default)]
+struct CpuProgress {
+	exits: AtomicU64,
+	samples: AtomicU64,
+	rip: AtomicU64,
+	sample_requested: AtomicBool,
+	dump_requested: AtomicBool,
+	dumped: AtomicBool,
+}
+
+/// Progress of all vCPUs of a VM
+#[derive(Debug)]
+pub struct Watchdog {
+	timeout: Duration,
+	cpus: Vec<CpuProgress>,
+}
+
+impl Watchdog {
+	pub fn new(num_cpus: u32, timeout: Duration) -> Self {
+		Watchdog {
+			timeout,
+			cpus: (0..num_cpus).map(|_| CpuProgress::default()).collect(),
+		}
+	}
+
+	/// Counts an exit of vCPU `id`.
+	pub fn exit(&self, id: u32) {
+		self.cpus[id as usize].exits.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Called by vCPU `id` with its current `rip` after a kick. Records a
+	/// sample if the watchdog has requested it. A `halted` vCPU counts as
+	/// progress like an exit. Returns true if the vCPU has to dump its state.
+	pub fn interrupted(&self, id: u32, rip: u64, halted: bool) -> bool {
+		let cpu = &self.cpus[id as usize];
+		if halted {
+			cpu.exits.fetch_add(1, Ordering::Relaxed);
+		}
+		if cpu.sample_requested.swap(false, Ordering::SeqCst) {
+			cpu.rip.store(rip, Ordering::SeqCst);
+			cpu.samples.fetch_add(1, Ordering::SeqCst);
+		}
+		cpu.dump_requested.swap(false, Ordering::SeqCst)
+	}
+
+	/// Called by vCPU `id` after its state has been dumped.
+	pub fn dumped(&self, id: u32) {
+		self.cpus[id as usize].dumped.store(true, Ordering::SeqCst);
+	}
+
+	fn request_samples(&self) {
+		for cpu in self.cpus.iter() {
+			cpu.sample_requested.store(true, Ordering::SeqCst);
+		}
+	}
+
+	/// Returns (exits, samples, rip) of vCPU `id`.
+	fn progress(&self, id: usize) -> (u64, u64, u64) {
+		let cpu = &self.cpus[id];
+		(
+			cpu.exits.load(Ordering::SeqCst),
+			cpu.samples.load(Ordering::SeqCst),
+			cpu.rip.load(Ordering::SeqCst),
+		)
+	}
+}
+
+/// Tracks the progress of one vCPU between the checks of the watchdog
+#[derive(Debug, Default, Clone, Copy)]
+struct Stall {
+	last: (u64, u64, u64),
+	duration: Duration,
+}
+
+impl Stall {
+	/// Updates the stall by the progress since the last check, which was
+	/// `period` ago. Only a new sample without new exits and with the same
+	/// RIP extends the stall. Without a new sample, the vCPU isn't running
+	/// in the guest (e.g. it waits for its start or handles an exit).
+	fn update(&mut self, progress: (u64, u64, u64), period: Duration) {
+		let (exits, samples, rip) = progress;
+		let (last_exits, last_samples, last_rip) = self.last;
+		if samples != last_samples && exits == last_exits && rip == last_rip && last_samples != 0 {
+			self.duration += period;
+		} else if samples != last_samples || exits != last_exits {
+			self.duration = Duration::from_secs(0);
+		}
+		self.last = progress;
+	}
+}
+
+/// Starts the watchdog thread of `vm`, if the watchdog is enabled. The
+/// thread finishes after the VM has stopped.
+pub fn spawn_watchdog_thread(vm: Arc<Uhyve>) -> Option<JoinHandle<()>> {
+	let watchdog = vm.watchdog()?;
+
+	Some(thread::spawn(move || {
+		let period = (watchdog.timeout / 4).max(Duration::from_millis(10));
+		let mut stalls = vec![Stall::default(); watchdog.cpus.len()];
+
+		kick::sample_periodically(
+			&*vm,
+			period,
+			|| watchdog.request_samples(),
+			|| {
+				for (id, stall) in stalls.iter_mut().enumerate() {
+					stall.update(watchdog.progress(id), period);
+				}
+				let cpus = stalls
+					.iter()
+					.enumerate()
+					.filter(|(_, stall)| stall.duration >= watchdog.timeout)
+					.map(|(id, _)| id as u32)
+					.collect::<Vec<_>>();
+				if cpus.is_empty() {
+					return true;
+				}
+
+				error!(
+					"CPUs {:?} have made no progress for {:?}",
+					cpus, watchdog.timeout
+				);
+				for id in cpus.iter() {
+					watchdog.cpus[*id as usize]
+						.dump_requested
+						.store(true, Ordering::SeqCst);
+				}
+				vm.kick_cpus();
+
+				let start = Instant::now();
+				while start.elapsed() < DUMP_TIMEOUT
+					&& !cpus
+						.iter()
+						.all(|id| watchdog.cpus[*id as usize].dumped.load(Ordering::SeqCst))
+				{
+					thread::sleep(Duration::from_millis(10));
+				}
+				debug!("Stop the VM");
+				vm.stop(VmOutcome::Hang { cpus });
+				false
+			},
+		);
+	}))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_stall() {
+		let period = Duration::from_secs(1);
+		let mut stall = Stall::default();
+
+		// the vCPU hasn't started yet
+		stall.update((0, 0, 0), period);
+		assert_eq!(stall.duration, Duration::from_secs(0));
+
+		// first sample
+		stall.update((5, 1, 0x1000), period);
+		assert_eq!(stall.duration, Duration::from_secs(0));
+
+		// same RIP and no exits
+		stall.update((5, 2, 0x1000), period);
+		stall.update((5, 3, 0x1000), period);
+		assert_eq!(stall.duration, Duration::from_secs(2));
+
+		// no sample, the vCPU is outside of the guest
+		stall.update((5, 3, 0x1000), period);
+		assert_eq!(stall.duration, Duration::from_secs(2));
+
+		// progress
+		stall.update((5, 4, 0x1004), period);
+		assert_eq!(stall.duration, Duration::from_secs(0));
+		stall.update((5, 5, 0x1004), period);
+		stall.update((6, 6, 0x1004), period);
+		assert_eq!(stall.duration, Duration::from_secs(0));
+	}
+
+	#[test]
+	fn test_interrupted() {
+		let watchdog = Watchdog::new(2, Duration::from_secs(1));
+
+		// no sample without request
+		assert!(!watchdog.interrupted(0, 0x1000, false));
+		assert_eq!(watchdog.progress(0), (0, 0, 0));
+
+		watchdog.request_samples();
+		watchdog.exit(1);
+		assert!(!watchdog.interrupted(0, 0x1000, false));
+		assert_eq!(watchdog.progress(0), (0, 1, 0x1000));
+		assert_eq!(watchdog.progress(1), (1, 0, 0));
+
+		// a halted vCPU makes progress
+		watchdog.request_samples();
+		assert!(!watchdog.interrupted(0, 0x1000, true));
+		assert_eq!(watchdog.progress(0), (1, 2, 0x1000));
+
+		watchdog.cpus[1]
+			.dump_requested
+			.store(true, Ordering::SeqCst);
+		assert!(watchdog.interrupted(1, 0x2000, false));
+		assert!(!watchdog.interrupted(1, 0x2000, false));
+	}
+}
@@ -0,0 +1,51 @@
+//! Kicks vCPUs out of `hv_vcpu_run`.
+//!
+//! Hypervisor.framework interrupts a vCPU with `hv_vcpu_interrupt`. The vCPU
+//! leaves the guest with an external interrupt, even if it enters the guest
+//! shortly after the kick.
+
+use std::sync::Mutex;
+use xhypervisor::vCPU;
+
+#[link(name = "Hypervisor", kind = "framework")]
+extern "C" {
+	fn hv_vcpu_interrupt(vcpus: *const libc::c_uint, vcpu_count: libc::c_uint) -> libc::c_int;
+}
+
+/// Hypervisor ids of the running vCPUs
+pub struct VcpuThreads {
+	ids: Mutex<Vec<Option<libc::c_uint>>>,
+}
+
+impl VcpuThreads {
+	pub fn new(num_cpus: u32) -> Self {
+		VcpuThreads {
+			ids: Mutex::new(vec![None; num_cpus as usize]),
+		}
+	}
+
+	/// Registers `vcpu` as vCPU `id`, which runs on the calling thread.
+	pub fn register(&self, id: u32, vcpu: &vCPU) {
+		self.ids.lock().unwrap()[id as usize] = Some(vcpu.id as libc::c_uint);
+	}
+
+	/// Removes vCPU `id`, which doesn't run the guest anymore.
+	pub fn unregister(&self, id: u32) {
+		self.ids.lock().unwrap()[id as usize] = None;
+	}
+
+	/// Kicks all registered vCPUs out of the guest.
+	pub fn kick_all(&self) {
+		let ids = self
+			.ids
+			.lock()
+			.unwrap()
+			.iter()
+			.flatten()
+			.cloned()
+			.collect::<Vec<_>>();
+		if !ids.is_empty() {
+			unsafe { hv_vcpu_interrupt(ids.as_ptr(), ids.len() as libc::c_uint) };
+		}
+	}
+}
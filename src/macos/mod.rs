@@ -1,4 +1,5 @@
 pub mod gdb;
 mod ioapic;
+mod kick;
 pub mod uhyve;
 pub mod vcpu;
@@ -1,8 +1,12 @@
 use crate::debug_manager::DebugManager;
 use crate::error::*;
 use crate::macos::ioapic::IoApic;
+use crate::macos::kick::VcpuThreads;
 use crate::macos::vcpu::*;
-use crate::vm::{BootInfo, Parameter, VirtualCPU, Vm};
+use crate::vm::{
+	guest_mem_regions, guest_phys_to_offset, BootInfo, CpuStartup, GuestFiles, Parameter, Shutdown,
+	VirtualCPU, Vm,
+};
 use libc;
 use libc::c_void;
 use log::{debug, error};
@@ -23,6 +27,10 @@ pub struct Uhyve {
 	ioapic: Arc<Mutex<IoApic>>,
 	verbose: bool,
 	dbg: Option<Arc<Mutex<DebugManager>>>,
+	cpu_startup: CpuStartup,
+	shutdown: Arc<Shutdown>,
+	vcpu_threads: Arc<VcpuThreads>,
+	files: Arc<GuestFiles>,
 }
 
 impl Uhyve {
@@ -53,12 +61,17 @@ impl Uhyve {
 		create_vm()?;
 
 		debug!("Map guest memory...");
-		unsafe {
-			map_mem(
-				std::slice::from_raw_parts(mem as *mut u8, specs.mem_size),
-				0,
-				&MemPerm::ExecAndWrite,
-			)?;
+		for region in guest_mem_regions(specs.mem_size) {
+			unsafe {
+				map_mem(
+					std::slice::from_raw_parts(
+						(mem as *mut u8).add(guest_phys_to_offset(region.start)),
+						region.len(),
+					),
+					region.start as u64,
+					&MemPerm::ExecAndWrite,
+				)?;
+			}
 		}
 
 		let hyve = Uhyve {
@@ -71,6 +84,10 @@ impl Uhyve {
 			ioapic: Arc::new(Mutex::new(IoApic::new())),
 			verbose: specs.verbose,
 			dbg: dbg.map(|g| Arc::new(Mutex::new(g))),
+			cpu_startup: CpuStartup::new(specs.num_cpus),
+			shutdown: Arc::new(Shutdown::new()),
+			vcpu_threads: Arc::new(VcpuThreads::new(specs.num_cpus)),
+			files: Arc::new(GuestFiles::new()),
 		};
 
 		hyve.init_guest_mem();
@@ -111,6 +128,9 @@ impl Vm for Uhyve {
 			self.guest_mem as usize,
 			self.ioapic.clone(),
 			self.dbg.as_ref().cloned(),
+			self.shutdown.clone(),
+			self.vcpu_threads.clone(),
+			self.files.clone(),
 		)))
 	}
 
@@ -137,13 +157,27 @@ impl Vm for Uhyve {
 			unsafe { read_volatile(&(*self.boot_info).cpu_online) }
 		}
 	}
+
+	fn cpu_startup(&self) -> &CpuStartup {
+		&self.cpu_startup
+	}
+
+	fn shutdown(&self) -> &Shutdown {
+		&self.shutdown
+	}
+
+	fn kick_cpus(&self) {
+		self.vcpu_threads.kick_all();
+	}
 }
 
 impl Drop for Uhyve {
 	fn drop(&mut self) {
 		debug!("Drop virtual machine");
 
-		unmap_mem(0, self.mem_size).unwrap();
+		for region in guest_mem_regions(self.mem_size) {
+			unmap_mem(region.start as u64, region.len()).unwrap();
+		}
 
 		unsafe {
 			libc::munmap(self.guest_mem, self.mem_size);
@@ -4,8 +4,9 @@ use crate::consts::*;
 use crate::debug_manager::DebugManager;
 use crate::error::*;
 use crate::macos::ioapic::IoApic;
+use crate::macos::kick::VcpuThreads;
 use crate::paging::*;
-use crate::vm::VirtualCPU;
+use crate::vm::{debug_exit_code, guest_phys_to_offset, GuestFiles, Shutdown, VirtualCPU};
 use burst::x86::{disassemble_64, InstructionOperation, OperandType};
 use lazy_static::lazy_static;
 use log::{debug, error, trace};
@@ -69,6 +70,9 @@ pub struct UhyveCPU {
 	apic_base: u64,
 	ioapic: Arc<Mutex<IoApic>>,
 	pub dbg: Option<Arc<Mutex<DebugManager>>>,
+	shutdown: Arc<Shutdown>,
+	vcpu_threads: Arc<VcpuThreads>,
+	files: Arc<GuestFiles>,
 }
 
 impl UhyveCPU {
@@ -78,6 +82,9 @@ impl UhyveCPU {
 		vm_start: usize,
 		ioapic: Arc<Mutex<IoApic>>,
 		dbg: Option<Arc<Mutex<DebugManager>>>,
+		shutdown: Arc<Shutdown>,
+		vcpu_threads: Arc<VcpuThreads>,
+		files: Arc<GuestFiles>,
 	) -> UhyveCPU {
 		UhyveCPU {
 			id,
@@ -87,6 +94,9 @@ impl UhyveCPU {
 			apic_base: APIC_DEFAULT_BASE,
 			ioapic,
 			dbg,
+			shutdown,
+			vcpu_threads,
+			files,
 		}
 	}
 
@@ -510,88 +520,15 @@ impl UhyveCPU {
 		Ok(())
 	}
 
-	pub fn get_vcpu(&self) -> &vCPU {
-		&self.vcpu
-	}
-}
-
-impl VirtualCPU for UhyveCPU {
-	fn init(&mut self, entry_point: u64) -> Result<()> {
-		self.setup_capabilities()?;
-		self.setup_msr()?;
-
-		self.vcpu
-			.write_vmcs(VMCS_CTRL_EXC_BITMAP, (1 << 3) | (1 << 1))?;
-		self.vcpu.write_vmcs(VMCS_CTRL_TPR_THRESHOLD, 0)?;
-		self.vcpu.write_vmcs(VMCS_GUEST_SYSENTER_EIP, 0)?;
-		self.vcpu.write_vmcs(VMCS_GUEST_SYSENTER_ESP, 0)?;
-
-		debug!("Setup general purpose registers");
-		self.vcpu.write_register(&x86Reg::RIP, entry_point)?;
-		self.vcpu.write_register(&x86Reg::RFLAGS, 0x2)?;
-		// create temporary stack to boot the kernel
-		self.vcpu.write_register(&x86Reg::RSP, 0x200000 - 0x1000)?;
-		self.vcpu.write_register(&x86Reg::RBP, 0)?;
-		self.vcpu.write_register(&x86Reg::RAX, 0)?;
-		self.vcpu.write_register(&x86Reg::RBX, 0)?;
-		self.vcpu.write_register(&x86Reg::RCX, 0)?;
-		self.vcpu.write_register(&x86Reg::RDX, 0)?;
-		self.vcpu.write_register(&x86Reg::RSI, 0)?;
-		self.vcpu.write_register(&x86Reg::RDI, BOOT_INFO_ADDR)?;
-		self.vcpu.write_register(&x86Reg::R8, 0)?;
-		self.vcpu.write_register(&x86Reg::R9, 0)?;
-		self.vcpu.write_register(&x86Reg::R10, 0)?;
-		self.vcpu.write_register(&x86Reg::R11, 0)?;
-		self.vcpu.write_register(&x86Reg::R12, 0)?;
-		self.vcpu.write_register(&x86Reg::R13, 0)?;
-		self.vcpu.write_register(&x86Reg::R14, 0)?;
-		self.vcpu.write_register(&x86Reg::R15, 0)?;
-		self.setup_system_gdt()?;
-		self.setup_system_64bit()?;
-
-		Ok(())
-	}
-
-	fn kernel_path(&self) -> PathBuf {
-		self.kernel_path.clone()
-	}
-
-	fn host_address(&self, addr: usize) -> usize {
-		addr + self.vm_start
-	}
-
-	fn virt_to_phys(&self, addr: usize) -> usize {
-		let executable_disable_mask: usize = !PageTableEntryFlags::EXECUTE_DISABLE.bits();
-		let mut page_table = self.host_address(BOOT_PML4 as usize) as *const usize;
-		let mut page_bits = 39;
-		let mut entry: usize = 0;
-
-		for _i in 0..4 {
-			let index = (addr >> page_bits) & ((1 << PAGE_MAP_BITS) - 1);
-			entry = unsafe { *page_table.add(index) & executable_disable_mask };
-
-			// bit 7 is set if this entry references a 1 GiB (PDPT) or 2 MiB (PDT) page.
-			if entry & PageTableEntryFlags::HUGE_PAGE.bits() != 0 {
-				return (entry & ((!0usize) << page_bits)) | (addr & !((!0usize) << page_bits));
-			} else {
-				page_table = self.host_address(entry & !((1 << PAGE_BITS) - 1)) as *const usize;
-				page_bits -= PAGE_MAP_BITS;
+	/// Runs the guest until the vCPU exits (`Ok(Some(exit_code))`), is stopped
+	/// by `Shutdown` or crashes.
+	fn run_loop(&mut self) -> Result<Option<i32>> {
+		loop {
+			if self.shutdown.is_requested() {
+				debug!("Stop vCPU {}", self.id);
+				return Ok(None);
 			}
-		}
-
-		(entry & ((!0usize) << PAGE_BITS)) | (addr & !((!0usize) << PAGE_BITS))
-	}
-
-	fn run(&mut self) -> Result<Option<i32>> {
-		//self.print_registers();
 
-		// Pause first CPU before first execution, so we have time to attach debugger
-		if self.id == 0 {
-			self.gdb_handle_exception(false);
-		}
-
-		debug!("Run vCPU {}", self.id);
-		loop {
 			/*if self.extint_pending == true {
 				let irq_info = self.vcpu.read_vmcs(VMCS_CTRL_VMENTRY_IRQ_INFO)?;
 				let flags = self.vcpu.read_register(&x86Reg::RFLAGS)?;
@@ -684,7 +621,10 @@ impl VirtualCPU for UhyveCPU {
 
 					match port {
 						SHUTDOWN_PORT => {
-							return Ok(None);
+							let size = (qualification & 7) + 1;
+							let value = self.vcpu.read_register(&x86Reg::RAX)?
+								& ((1u64 << (size * 8)) - 1);
+							return Ok(Some(debug_exit_code(value as u32)));
 						}
 						UHYVE_UART_PORT => {
 							let al = (self.vcpu.read_register(&x86Reg::RAX)? & 0xFF) as u8;
@@ -760,6 +700,98 @@ impl VirtualCPU for UhyveCPU {
 		}
 	}
 
+	pub fn get_vcpu(&self) -> &vCPU {
+		&self.vcpu
+	}
+}
+
+impl VirtualCPU for UhyveCPU {
+	fn init(&mut self, entry_point: u64) -> Result<()> {
+		self.setup_capabilities()?;
+		self.setup_msr()?;
+
+		self.vcpu
+			.write_vmcs(VMCS_CTRL_EXC_BITMAP, (1 << 3) | (1 << 1))?;
+		self.vcpu.write_vmcs(VMCS_CTRL_TPR_THRESHOLD, 0)?;
+		self.vcpu.write_vmcs(VMCS_GUEST_SYSENTER_EIP, 0)?;
+		self.vcpu.write_vmcs(VMCS_GUEST_SYSENTER_ESP, 0)?;
+
+		debug!("Setup general purpose registers");
+		self.vcpu.write_register(&x86Reg::RIP, entry_point)?;
+		self.vcpu.write_register(&x86Reg::RFLAGS, 0x2)?;
+		// create temporary stack to boot the kernel
+		self.vcpu.write_register(&x86Reg::RSP, 0x200000 - 0x1000)?;
+		self.vcpu.write_register(&x86Reg::RBP, 0)?;
+		self.vcpu.write_register(&x86Reg::RAX, 0)?;
+		self.vcpu.write_register(&x86Reg::RBX, 0)?;
+		self.vcpu.write_register(&x86Reg::RCX, 0)?;
+		self.vcpu.write_register(&x86Reg::RDX, 0)?;
+		self.vcpu.write_register(&x86Reg::RSI, 0)?;
+		self.vcpu.write_register(&x86Reg::RDI, BOOT_INFO_ADDR)?;
+		self.vcpu.write_register(&x86Reg::R8, 0)?;
+		self.vcpu.write_register(&x86Reg::R9, 0)?;
+		self.vcpu.write_register(&x86Reg::R10, 0)?;
+		self.vcpu.write_register(&x86Reg::R11, 0)?;
+		self.vcpu.write_register(&x86Reg::R12, 0)?;
+		self.vcpu.write_register(&x86Reg::R13, 0)?;
+		self.vcpu.write_register(&x86Reg::R14, 0)?;
+		self.vcpu.write_register(&x86Reg::R15, 0)?;
+		self.setup_system_gdt()?;
+		self.setup_system_64bit()?;
+
+		Ok(())
+	}
+
+	fn kernel_path(&self) -> PathBuf {
+		self.kernel_path.clone()
+	}
+
+	fn guest_files(&self) -> &GuestFiles {
+		&self.files
+	}
+
+	fn host_address(&self, addr: usize) -> usize {
+		guest_phys_to_offset(addr) + self.vm_start
+	}
+
+	fn virt_to_phys(&self, addr: usize) -> usize {
+		let executable_disable_mask: usize = !PageTableEntryFlags::EXECUTE_DISABLE.bits();
+		let mut page_table = self.host_address(BOOT_PML4 as usize) as *const usize;
+		let mut page_bits = 39;
+		let mut entry: usize = 0;
+
+		for _i in 0..4 {
+			let index = (addr >> page_bits) & ((1 << PAGE_MAP_BITS) - 1);
+			entry = unsafe { *page_table.add(index) & executable_disable_mask };
+
+			// bit 7 is set if this entry references a 1 GiB (PDPT) or 2 MiB (PDT) page.
+			if entry & PageTableEntryFlags::HUGE_PAGE.bits() != 0 {
+				return (entry & ((!0usize) << page_bits)) | (addr & !((!0usize) << page_bits));
+			} else {
+				page_table = self.host_address(entry & !((1 << PAGE_BITS) - 1)) as *const usize;
+				page_bits -= PAGE_MAP_BITS;
+			}
+		}
+
+		(entry & ((!0usize) << PAGE_BITS)) | (addr & !((!0usize) << PAGE_BITS))
+	}
+
+	fn run(&mut self) -> Result<Option<i32>> {
+		//self.print_registers();
+
+		// Pause first CPU before first execution, so we have time to attach debugger
+		if self.id == 0 {
+			self.gdb_handle_exception(false);
+		}
+
+		debug!("Run vCPU {}", self.id);
+		self.vcpu_threads.register(self.id, &self.vcpu);
+		let result = self.run_loop();
+		self.vcpu_threads.unregister(self.id);
+
+		result
+	}
+
 	fn print_registers(&self) {
 		println!("\nDump state of CPU {}", self.id);
 		println!("VMCS:");
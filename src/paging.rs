@@ -167,6 +167,14 @@ impl PageSize for LargePageSize {
 	const MAP_EXTRA_FLAG: PageTableEntryFlags = PageTableEntryFlags::HUGE_PAGE;
 }
 
+/// A 1 GiB page mapped in the PDPT.
+pub enum HugePageSize {}
+impl PageSize for HugePageSize {
+	const SIZE: usize = 1024 * 1024 * 1024;
+	const MAP_LEVEL: usize = 2;
+	const MAP_EXTRA_FLAG: PageTableEntryFlags = PageTableEntryFlags::HUGE_PAGE;
+}
+
 /// Representation of any page table (PML4, PDPT, PD, PT) in memory.
 #[repr(C)]
 pub struct PageTable {
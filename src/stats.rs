@@ -0,0 +1,255 @@
+//! Statistics of the vCPU exits.
+//!
+//! Each vCPU counts its exits in its own `CpuStats` and adds them to the
+//! `VmStats` of the VM, when it stops. Therefore, the vCPUs don't
+//! synchronize while they run.
+
+use crate::consts::*;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Number of exits and the time, which the host spent handling them
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ExitStats {
+	pub count: u64,
+	pub time: Duration,
+}
+
+impl ExitStats {
+	fn add(&mut self, other: &ExitStats) {
+		self.count += other.count;
+		self.time += other.time;
+	}
+}
+
+/// Exit statistics of one vCPU
+#[derive(Debug, Default, Clone)]
+pub struct CpuStats {
+	/// Statistics per exit reason (e.g. `IoOut`)
+	pub exits: BTreeMap<&'static str, ExitStats>,
+	/// Statistics per I/O port, including the ports of the hypercalls
+	pub ports: BTreeMap<u16, ExitStats>,
+}
+
+impl CpuStats {
+	/// Starts the handling of an exit, which is recorded when the returned
+	/// timer is dropped. `port` is the I/O port of an I/O exit.
+	pub fn start(&mut self, exit: &'static str, port: Option<u16>) -> ExitTimer<'_> {
+		ExitTimer {
+			stats: self,
+			exit,
+			port,
+			start: Instant::now(),
+		}
+	}
+
+	fn record(&mut self, exit: &'static str, port: Option<u16>, time: Duration) {
+		let stats = ExitStats { count: 1, time };
+		self.exits.entry(exit).or_default().add(&stats);
+		if let Some(port) = port {
+			self.ports.entry(port).or_default().add(&stats);
+		}
+	}
+
+	pub fn add(&mut self, other: &CpuStats) {
+		for (exit, stats) in other.exits.iter() {
+			self.exits.entry(exit).or_default().add(stats);
+		}
+		for (port, stats) in other.ports.iter() {
+			self.ports.entry(*port).or_default().add(stats);
+		}
+	}
+
+	/// Statistics per hypercall of uhyve
+	pub fn hypercalls(&self) -> BTreeMap<&'static str, ExitStats> {
+		let mut hypercalls = BTreeMap::new();
+		for (port, stats) in self.ports.iter() {
+			if let Some(name) = hypercall_name(*port) {
+				hypercalls
+					.entry(name)
+					.or_insert_with(ExitStats::default)
+					.add(stats);
+			}
+		}
+		hypercalls
+	}
+}
+
+/// Records an exit in `CpuStats` when dropped
+pub struct ExitTimer<'a> {
+	stats: &'a mut CpuStats,
+	exit: &'static str,
+	port: Option<u16>,
+	start: Instant,
+}
+
+impl Drop for ExitTimer<'_> {
+	fn drop(&mut self) {
+		self.stats
+			.record(self.exit, self.port, self.start.elapsed());
+	}
+}
+
+/// Returns the name of the hypercall, which uses the I/O port `port`.
+pub fn hypercall_name(port: u16) -> Option<&'static str> {
+	match port {
+		SHUTDOWN_PORT => Some("shutdown"),
+		UHYVE_PORT_WRITE => Some("write"),
+		UHYVE_PORT_OPEN => Some("open"),
+		UHYVE_PORT_CLOSE => Some("close"),
+		UHYVE_PORT_READ => Some("read"),
+		UHYVE_PORT_EXIT => Some("exit"),
+		UHYVE_PORT_LSEEK => Some("lseek"),
+		UHYVE_PORT_NETWRITE => Some("netwrite"),
+		UHYVE_PORT_NETREAD => Some("netread"),
+		UHYVE_PORT_NETSTAT => Some("netstat"),
+		UHYVE_PORT_CMDSIZE => Some("cmdsize"),
+		UHYVE_PORT_CMDVAL => Some("cmdval"),
+		UHYVE_UART_PORT => Some("uart"),
+		UHYVE_PORT_UNLINK => Some("unlink"),
+		UHYVE_PORT_SNAPSHOT => Some("snapshot"),
+		UHYVE_PORT_CPU_START => Some("cpu_start"),
+		_ => None,
+	}
+}
+
+/// Exit statistics of all vCPUs of a VM
+#[derive(Debug, Default)]
+pub struct VmStats {
+	cpus: Mutex<BTreeMap<u32, CpuStats>>,
+}
+
+impl VmStats {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Adds the statistics of vCPU `id`.
+	pub fn add_cpu(&self, id: u32, stats: &CpuStats) {
+		self.cpus.lock().unwrap().entry(id).or_default().add(stats);
+	}
+
+	/// Returns the statistics of vCPU `id`.
+	pub fn cpu(&self, id: u32) -> Option<CpuStats> {
+		self.cpus.lock().unwrap().get(&id).cloned()
+	}
+
+	/// Returns the sum of the statistics of all vCPUs.
+	pub fn total(&self) -> CpuStats {
+		let mut total = CpuStats::default();
+		for stats in self.cpus.lock().unwrap().values() {
+			total.add(stats);
+		}
+		total
+	}
+}
+
+fn write_row(f: &mut fmt::Formatter<'_>, name: &str, stats: &ExitStats) -> fmt::Result {
+	let average = stats.time.as_secs_f64() / stats.count.max(1) as f64;
+	writeln!(
+		f,
+		"{:<20} {:>12} {:>14.3} {:>12.3}",
+		name,
+		stats.count,
+		stats.time.as_secs_f64() * 1e3,
+		average * 1e6
+	)
+}
+
+fn write_header(f: &mut fmt::Formatter<'_>, title: &str) -> fmt::Result {
+	writeln!(
+		f,
+		"{:<20} {:>12} {:>14} {:>12}",
+		title, "count", "time (ms)", "avg (us)"
+	)
+}
+
+impl fmt::Display for VmStats {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let total = self.total();
+
+		write_header(f, "exit")?;
+		for (exit, stats) in total.exits.iter() {
+			write_row(f, exit, stats)?;
+		}
+
+		writeln!(f)?;
+		write_header(f, "hypercall")?;
+		for (hypercall, stats) in total.hypercalls().iter() {
+			write_row(f, hypercall, stats)?;
+		}
+
+		writeln!(f)?;
+		write_header(f, "I/O port")?;
+		for (port, stats) in total.ports.iter() {
+			write_row(f, &format!("0x{:x}", port), stats)?;
+		}
+
+		writeln!(f)?;
+		write_header(f, "vCPU")?;
+		for (id, stats) in self.cpus.lock().unwrap().iter() {
+			let mut sum = ExitStats::default();
+			for exit in stats.exits.values() {
+				sum.add(exit);
+			}
+			write_row(f, &id.to_string(), &sum)?;
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_stats() {
+		let mut cpu0 = CpuStats::default();
+		drop(cpu0.start("IoOut", Some(UHYVE_PORT_WRITE)));
+		drop(cpu0.start("IoOut", Some(UHYVE_PORT_WRITE)));
+		drop(cpu0.start("Hlt", None));
+		let mut cpu1 = CpuStats::default();
+		drop(cpu1.start("IoIn", Some(0xcfc)));
+
+		let stats = VmStats::new();
+		stats.add_cpu(0, &cpu0);
+		stats.add_cpu(1, &cpu1);
+
+		let total = stats.total();
+		assert_eq!(total.exits["IoOut"].count, 2);
+		assert_eq!(total.exits["IoIn"].count, 1);
+		assert_eq!(total.exits["Hlt"].count, 1);
+		assert_eq!(total.ports[&UHYVE_PORT_WRITE].count, 2);
+		assert_eq!(total.ports[&0xcfc].count, 1);
+
+		let hypercalls = total.hypercalls();
+		assert_eq!(hypercalls.len(), 1);
+		assert_eq!(hypercalls["write"].count, 2);
+
+		assert_eq!(stats.cpu(1).unwrap().exits.len(), 1);
+		assert!(stats.cpu(2).is_none());
+		assert!(stats.to_string().contains("write"));
+	}
+
+	#[test]
+	fn test_average_of_many_exits() {
+		// the count doesn't fit into 32 bits
+		let mut cpu = CpuStats::default();
+		cpu.exits.insert(
+			"Hlt",
+			ExitStats {
+				count: 1 << 32,
+				time: Duration::from_secs(1 << 32),
+			},
+		);
+		let stats = VmStats::new();
+		stats.add_cpu(0, &cpu);
+
+		let output = stats.to_string();
+		let row = output.lines().find(|line| line.starts_with("Hlt")).unwrap();
+		assert!(row.ends_with("1000000.000"));
+	}
+}
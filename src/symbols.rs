@@ -0,0 +1,104 @@
+//! Symbolization of guest addresses by the symbol table of the kernel.
+
+use crate::error::*;
+use goblin::elf;
+use goblin::elf64::header::ET_DYN;
+use std::fs;
+use std::iter::FromIterator;
+use std::path::Path;
+
+/// Function symbols of the kernel, sorted by their start address
+#[derive(Debug, Default)]
+pub struct Symbols {
+	symbols: Vec<(u64, u64, String)>,
+}
+
+impl Symbols {
+	/// Reads the symbols of the kernel `path`, whose image starts at `base`.
+	/// `base` is used only for relocatable kernels.
+	pub fn load(path: &Path, base: u64) -> Result<Symbols> {
+		let buffer = fs::read(path).map_err(|_| Error::InvalidFile(path.to_path_buf()))?;
+		let elf = elf::Elf::parse(&buffer).map_err(|_| Error::InvalidFile(path.to_path_buf()))?;
+		let offset = if elf.header.e_type == ET_DYN { base } else { 0 };
+
+		Ok(elf
+			.syms
+			.iter()
+			.filter(|sym| sym.is_function() && sym.st_value != 0)
+			.filter_map(|sym| {
+				let name = elf.strtab.get_at(sym.st_name)?;
+				Some((sym.st_value + offset, sym.st_size, name.to_string()))
+			})
+			.collect())
+	}
+
+	/// Returns the function, which contains `addr`, and the offset of `addr`
+	/// in the function.
+	pub fn lookup(&self, addr: u64) -> Option<(&str, u64)> {
+		let index = match self.symbols.binary_search_by_key(&addr, |sym| sym.0) {
+			Ok(index) => index,
+			Err(0) => return None,
+			Err(index) => index - 1,
+		};
+		let (start, size, name) = &self.symbols[index];
+		if addr - start < (*size).max(1) {
+			Some((name, addr - start))
+		} else {
+			None
+		}
+	}
+
+	/// Formats `addr` as `function+offset` (if known) for the output.
+	pub fn format(&self, addr: u64) -> String {
+		match self.lookup(addr) {
+			Some((name, offset)) => format!("0x{:016x} {}+0x{:x}", addr, name, offset),
+			None => format!("0x{:016x} ?", addr),
+		}
+	}
+}
+
+impl FromIterator<(u64, u64, String)> for Symbols {
+	/// Collects the symbols (start address, size, name).
+	fn from_iter<I: IntoIterator<Item = (u64, u64, String)>>(iter: I) -> Self {
+		let mut symbols = iter.into_iter().collect::<Vec<_>>();
+		symbols.sort_unstable_by_key(|sym| sym.0);
+		Symbols { symbols }
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_lookup() {
+		let symbols = vec![
+			(0x2000, 0, "third".to_string()),
+			(0x1000, 0x100, "first".to_string()),
+			(0x1100, 0x20, "second".to_string()),
+		]
+		.into_iter()
+		.collect::<Symbols>();
+
+		assert_eq!(symbols.lookup(0x1000), Some(("first", 0)));
+		assert_eq!(symbols.lookup(0x10ff), Some(("first", 0xff)));
+		assert_eq!(symbols.lookup(0x1110), Some(("second", 0x10)));
+		assert_eq!(symbols.lookup(0x1120), None);
+		assert_eq!(symbols.lookup(0x2000), Some(("third", 0)));
+		assert_eq!(symbols.lookup(0x2001), None);
+		assert_eq!(symbols.lookup(0xfff), None);
+		assert!(symbols.format(0x1104).ends_with("second+0x4"));
+	}
+
+	#[test]
+	fn test_load() {
+		// the test binary is an ELF file with a symbol table
+		let path = std::env::current_exe().unwrap();
+		let symbols = Symbols::load(&path, 0).unwrap();
+		assert!(!symbols.symbols.is_empty());
+
+		// aliases share the address => compare only the offset
+		let (start, _, _) = &symbols.symbols[symbols.symbols.len() / 2];
+		assert_eq!(symbols.lookup(*start).unwrap().1, 0);
+	}
+}
@@ -3,6 +3,7 @@ use crate::error::*;
 use core_affinity::CoreId;
 #[cfg(target_os = "linux")]
 use log::debug;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{env, io};
 
 pub fn parse_mem(mem: &str) -> Result<usize> {
@@ -22,6 +23,108 @@ pub fn parse_mem(mem: &str) -> Result<usize> {
 	Ok(num * factor)
 }
 
+/// Parses a duration with the suffix *ms*, *s*, *m* or *h*. A number
+/// without suffix is a number of seconds.
+///
+/// Example:
+/// ```rust
+/// # use std::time::Duration;
+/// # use uhyvelib::utils::parse_duration;
+/// assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+/// assert_eq!(parse_duration("5m").unwrap(), Duration::from_secs(300));
+/// ```
+pub fn parse_duration(duration: &str) -> Result<Duration> {
+	let invalid = || Error::InvalidArgument(format!("invalid duration {}", duration));
+	let split = duration
+		.find(|c: char| !c.is_ascii_digit())
+		.unwrap_or(duration.len());
+	let num = duration[..split].parse::<u64>().map_err(|_| invalid())?;
+
+	let secs = |factor: u64| {
+		num.checked_mul(factor)
+			.map(Duration::from_secs)
+			.ok_or_else(invalid)
+	};
+	match &duration[split..] {
+		"ms" => Ok(Duration::from_millis(num)),
+		"" | "s" => secs(1),
+		"m" => secs(60),
+		"h" => secs(60 * 60),
+		_ => Err(invalid()),
+	}
+}
+
+/// Returns the number of days since 1970-01-01 of a date of the proleptic
+/// Gregorian calendar.
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+	let year = if month <= 2 { year - 1 } else { year };
+	let era = year.div_euclid(400);
+	let year_of_era = year - era * 400;
+	let month = i64::from(month);
+	let day_of_year =
+		(153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + i64::from(day) - 1;
+	let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+	era * 146_097 + day_of_era - 719_468
+}
+
+/// Returns the date (year, month, day) of a number of days since 1970-01-01.
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+	let days = days + 719_468;
+	let era = days.div_euclid(146_097);
+	let day_of_era = days - era * 146_097;
+	let year_of_era =
+		(day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+	let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+	let mp = (5 * day_of_year + 2) / 153;
+	let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+	let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+	(year, month, day)
+}
+
+/// Parses a point in time (UTC) as `YYYY-MM-DDTHH:MM:SS` (optionally with a
+/// space instead of `T` and a trailing `Z`) or as seconds since the epoch.
+///
+/// Example:
+/// ```rust
+/// # use std::time::{Duration, UNIX_EPOCH};
+/// # use uhyvelib::utils::parse_time;
+/// assert_eq!(
+///     parse_time("2000-01-01T00:00:00Z").unwrap(),
+///     UNIX_EPOCH + Duration::from_secs(946_684_800)
+/// );
+/// assert_eq!(parse_time("60").unwrap(), UNIX_EPOCH + Duration::from_secs(60));
+/// ```
+pub fn parse_time(time: &str) -> Result<SystemTime> {
+	let invalid = || Error::InvalidArgument(format!("invalid time {}", time));
+	if let Ok(secs) = time.parse::<u64>() {
+		return Ok(UNIX_EPOCH + Duration::from_secs(secs));
+	}
+
+	let fields = time
+		.trim_end_matches('Z')
+		.split(&['-', 'T', ' ', ':'][..])
+		.map(|field| field.parse::<u32>().map_err(|_| invalid()))
+		.collect::<Result<Vec<_>>>()?;
+	let (year, month, day, hour, minute, second) = match fields.as_slice() {
+		[year, month, day, hour, minute, second] => (*year, *month, *day, *hour, *minute, *second),
+		_ => return Err(invalid()),
+	};
+	if year < 1970
+		|| !(1..=12).contains(&month)
+		|| !(1..=31).contains(&day)
+		|| hour > 23
+		|| minute > 59
+		|| second > 59
+	{
+		return Err(invalid());
+	}
+
+	let days = days_from_civil(year.into(), month, day) as u64;
+	let secs = days * 86400 + u64::from(hour) * 3600 + u64::from(minute) * 60 + u64::from(second);
+	Ok(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
 /// Example:
 /// ```rust
 /// # use uhyvelib::utils::parse_u32;
@@ -131,6 +234,39 @@ pub fn transparent_hugepages_available() -> io::Result<bool> {
 	Ok(true)
 }
 
+/// Returns the NUMA node of the host CPU `cpu`. The node is derived from the
+/// `node<N>` link in `/sys/devices/system/cpu/cpu<cpu>`.
+#[cfg(target_os = "linux")]
+pub fn numa_node_of_cpu(cpu: usize) -> io::Result<u32> {
+	let cpu_dir = format!("/sys/devices/system/cpu/cpu{}", cpu);
+	for entry in std::fs::read_dir(&cpu_dir)? {
+		let name = entry?.file_name();
+		if let Some(node) = name
+			.to_str()
+			.and_then(|name| name.strip_prefix("node"))
+			.and_then(|node| node.parse::<u32>().ok())
+		{
+			return Ok(node);
+		}
+	}
+
+	debug!("`{}` doesn't belong to a NUMA node", cpu_dir);
+	Err(io::ErrorKind::NotFound.into())
+}
+
+/// Returns the sorted and deduplicated list of NUMA nodes, to which the
+/// given cores belong.
+#[cfg(target_os = "linux")]
+pub fn numa_nodes_of_cores(cores: &[CoreId]) -> io::Result<Vec<u32>> {
+	let mut nodes = cores
+		.iter()
+		.map(|core| numa_node_of_cpu(core.id))
+		.collect::<io::Result<Vec<u32>>>()?;
+	nodes.sort_unstable();
+	nodes.dedup();
+	Ok(nodes)
+}
+
 /// Filter available to only contain the subset of CPUs specified in affinity
 pub fn filter_cpu_affinity(available: Vec<CoreId>, affinity: Vec<u32>) -> Vec<CoreId> {
 	let filtered_cpu_affinity: Vec<core_affinity::CoreId> = available
@@ -173,6 +309,34 @@ pub fn parse_cpu_affinity(args: Vec<&str>) -> Result<Vec<u32>> {
 	}
 }
 
+/// Parses the list of NUMA nodes passed via the commandline argument
+/// --numa-node. Nodes and inclusive ranges of nodes are delimited by commas.
+/// The result is sorted and deduplicated.
+/// Example:
+/// ```rust
+/// # use uhyvelib::utils::parse_numa_nodes;
+/// assert_eq!(parse_numa_nodes("2,0-1").unwrap(), vec![0, 1, 2]);
+/// ```
+pub fn parse_numa_nodes(list: &str) -> Result<Vec<u32>> {
+	let mut nodes = Vec::new();
+	for nodes_range in list.split(',') {
+		let range = parse_u32_range(nodes_range.trim())
+			.ok()
+			.filter(|range| !range.is_empty())
+			.ok_or_else(|| {
+				Error::InvalidArgument(format!(
+					"invalid NUMA node {} in the list {}",
+					nodes_range, list
+				))
+			})?;
+		nodes.extend(range);
+	}
+
+	nodes.sort_unstable();
+	nodes.dedup();
+	Ok(nodes)
+}
+
 mod tests {
 	#[cfg(test)]
 	use crate::utils::*;
@@ -195,6 +359,40 @@ mod tests {
 		parse_u32_range(str).unwrap();
 	}
 
+	#[test]
+	fn test_parse_duration() {
+		assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+		assert_eq!(parse_duration("30").unwrap(), Duration::from_secs(30));
+		assert_eq!(parse_duration("2h").unwrap(), Duration::from_secs(7200));
+		assert!(parse_duration("").is_err());
+		assert!(parse_duration("1d").is_err());
+		assert!(parse_duration("m").is_err());
+		assert!(parse_duration("5124095576030432h").is_err());
+	}
+
+	#[test]
+	fn test_civil_days() {
+		assert_eq!(days_from_civil(1970, 1, 1), 0);
+		assert_eq!(days_from_civil(2000, 3, 1), 11017);
+		assert_eq!(civil_from_days(11017), (2000, 3, 1));
+		assert_eq!(civil_from_days(-1), (1969, 12, 31));
+		for days in (0..40_000).step_by(7) {
+			let (year, month, day) = civil_from_days(days);
+			assert_eq!(days_from_civil(year, month, day), days);
+		}
+	}
+
+	#[test]
+	fn test_parse_time() {
+		assert_eq!(
+			parse_time("2021-06-15 12:30:45").unwrap(),
+			UNIX_EPOCH + Duration::from_secs(1_623_760_245)
+		);
+		assert!(parse_time("2021-13-01T00:00:00").is_err());
+		assert!(parse_time("2021-06-15").is_err());
+		assert!(parse_time("yesterday").is_err());
+	}
+
 	#[test]
 	fn test_parse_cpu_affinity() {
 		assert_eq!(
@@ -215,6 +413,28 @@ mod tests {
 		assert!(parse_cpu_affinity(vec![too_large.to_string().as_ref()]).is_err());
 	}
 
+	#[test]
+	fn test_parse_numa_nodes() {
+		assert_eq!(parse_numa_nodes("1").unwrap(), [1]);
+		assert_eq!(parse_numa_nodes("3,0-1,1").unwrap(), [0, 1, 3]);
+		assert!(parse_numa_nodes("").is_err());
+		assert!(parse_numa_nodes("0,,1").is_err());
+		assert!(parse_numa_nodes("2-1").is_err());
+		let err = parse_numa_nodes("0,x").unwrap_err().to_string();
+		assert!(err.contains("NUMA node x"));
+	}
+
+	#[test]
+	#[cfg(target_os = "linux")]
+	fn test_numa_nodes_of_cores() {
+		// every online CPU belongs to a node, if the host supports NUMA at all
+		if std::path::Path::new("/sys/devices/system/node").is_dir() {
+			let nodes = numa_nodes_of_cores(&[CoreId { id: 0 }, CoreId { id: 0 }]).unwrap();
+			assert_eq!(nodes.len(), 1);
+		}
+		assert!(numa_node_of_cpu(usize::MAX).is_err());
+	}
+
 	#[test]
 	fn test_filter_cpu_affinity() {
 		let vec = vec![CoreId { id: 2 }, CoreId { id: 7 }, CoreId { id: 13 }];
@@ -8,16 +8,24 @@ use goblin::elf64::reloc::*;
 use log::{debug, error, warn};
 use nix::errno::errno;
 use raw_cpuid::CpuId;
+use std::collections::HashMap;
 use std::convert::TryInto;
+use std::ffi::CStr;
 use std::io::Write;
 use std::net::Ipv4Addr;
+use std::ops::Range;
 use std::os::unix::ffi::OsStrExt;
 use std::path::PathBuf;
-use std::ptr::write;
+use std::ptr::{write, write_volatile};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 use std::time::{Duration, Instant, SystemTime};
 use std::{fmt, mem, slice};
 use std::{fs, io};
 
+use crate::acpi;
+use crate::console::Console;
 use crate::consts::*;
 use crate::debug_manager::DebugManager;
 use crate::error::*;
@@ -25,6 +33,8 @@ use crate::error::*;
 pub use crate::linux::uhyve::*;
 #[cfg(target_os = "macos")]
 pub use crate::macos::uhyve::*;
+use crate::stats::VmStats;
+use crate::symbols::Symbols;
 
 const MHZ_TO_HZ: u64 = 1000000;
 const KHZ_TO_HZ: u64 = 1000;
@@ -58,6 +68,10 @@ pub struct BootInfo {
 	pub hcip: [u8; 4],
 	pub hcgateway: [u8; 4],
 	pub hcmask: [u8; 4],
+	/// Start of the guest memory above 4 GiB (0 if the memory fits below the 32-bit gap)
+	pub high_mem_base: u64,
+	/// End of the guest memory above 4 GiB
+	pub high_mem_limit: u64,
 }
 
 impl BootInfo {
@@ -89,6 +103,8 @@ impl BootInfo {
 			hcip: [255, 255, 255, 255],
 			hcgateway: [255, 255, 255, 255],
 			hcmask: [255, 255, 255, 0],
+			high_mem_base: 0,
+			high_mem_limit: 0,
 		}
 	}
 }
@@ -131,11 +147,336 @@ impl fmt::Debug for BootInfo {
 		writeln!(f, "current_boot_id {}", self.current_boot_id)?;
 		writeln!(f, "uartport 0x{:x}", self.uartport)?;
 		writeln!(f, "single_kernel {}", self.single_kernel)?;
-		writeln!(f, "uhyve {}", self.uhyve)
+		writeln!(f, "uhyve {}", self.uhyve)?;
+		writeln!(f, "high_mem_base 0x{:x}", self.high_mem_base)?;
+		writeln!(f, "high_mem_limit 0x{:x}", self.high_mem_limit)
 	}
 }
 
-#[derive(Debug, Copy, Clone)]
+/// Event, which triggers a snapshot of the running VM
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SnapshotTrigger {
+	/// The host sends `SIGUSR1` to uhyve
+	Signal,
+	/// The guest writes to `UHYVE_PORT_SNAPSHOT`
+	Hypercall,
+}
+
+impl FromStr for SnapshotTrigger {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self> {
+		match s {
+			"signal" => Ok(SnapshotTrigger::Signal),
+			"hypercall" => Ok(SnapshotTrigger::Hypercall),
+			_ => Err(Error::InvalidArgument(format!(
+				"unknown snapshot trigger {}",
+				s
+			))),
+		}
+	}
+}
+
+/// Handling of guest accesses to I/O ports and MMIO addresses, which uhyve
+/// doesn't emulate
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UnknownAccessPolicy {
+	/// Stop the VM with a diagnostic of the access
+	Strict,
+	/// Log and ignore the access. Reads return all-ones.
+	Ignore,
+	/// Trap to the attached debugger. Without a debugger, the access is
+	/// ignored like `Ignore`.
+	Debug,
+}
+
+// `#[derive(Default)]` with `#[default]` requires Rust 1.62, but the x86
+// crate still needs a nightly with `llvm_asm!`, which predates it.
+impl Default for UnknownAccessPolicy {
+	fn default() -> Self {
+		UnknownAccessPolicy::Ignore
+	}
+}
+
+impl FromStr for UnknownAccessPolicy {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self> {
+		match s {
+			"strict" => Ok(UnknownAccessPolicy::Strict),
+			"ignore" => Ok(UnknownAccessPolicy::Ignore),
+			"debug" => Ok(UnknownAccessPolicy::Debug),
+			_ => Err(Error::InvalidArgument(format!(
+				"unknown policy for unknown accesses {}",
+				s
+			))),
+		}
+	}
+}
+
+/// Access of the guest to an I/O port or MMIO address, which uhyve doesn't
+/// emulate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownAccess {
+	/// I/O port or physical MMIO address
+	pub addr: u64,
+	pub mmio: bool,
+	pub write: bool,
+	pub size: usize,
+	/// Written data, empty for reads
+	pub data: Vec<u8>,
+	pub rip: u64,
+}
+
+impl fmt::Display for UnknownAccess {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let space = if self.mmio {
+			"MMIO address"
+		} else {
+			"I/O port"
+		};
+		let direction = if self.write { "write to" } else { "read from" };
+		write!(
+			f,
+			"{} {} 0x{:x} (size {}",
+			direction, space, self.addr, self.size
+		)?;
+		if self.write {
+			write!(f, ", data 0x")?;
+			for byte in self.data.iter().rev() {
+				write!(f, "{:02x}", byte)?;
+			}
+		}
+		write!(f, ") at RIP 0x{:x}", self.rip)
+	}
+}
+
+/// Coordinates the start of the application processors. The boot processor
+/// runs immediately, while the other vCPUs wait until the guest starts them.
+///
+/// By default, the guest starts the next vCPU by incrementing `cpu_online` in
+/// the boot information. A guest, which requests the start of the boot
+/// processor by `UHYVE_PORT_CPU_START`, switches to an explicit startup.
+/// Afterwards, each vCPU is only started by its own request.
+pub struct CpuStartup {
+	state: Mutex<CpuStartupState>,
+	cond: Condvar,
+}
+
+struct CpuStartupState {
+	started: Vec<bool>,
+	explicit: bool,
+	cancelled: bool,
+}
+
+impl CpuStartup {
+	pub fn new(num_cpus: u32) -> Self {
+		let mut started = vec![false; num_cpus as usize];
+		if let Some(boot_processor) = started.first_mut() {
+			*boot_processor = true;
+		}
+
+		CpuStartup {
+			state: Mutex::new(CpuStartupState {
+				started,
+				explicit: false,
+				cancelled: false,
+			}),
+			cond: Condvar::new(),
+		}
+	}
+
+	/// Marks vCPU `id` as started and wakes it up. `prepare` is called
+	/// before, e.g. to set up the boot information of the vCPU. Returns false
+	/// if the vCPU doesn't exist or is already started.
+	///
+	/// A request for the boot processor enables the explicit startup.
+	pub fn start<F: FnOnce()>(&self, id: u32, prepare: F) -> bool {
+		let mut state = self.state.lock().unwrap();
+		if id == 0 {
+			state.explicit = true;
+			return true;
+		}
+
+		match state.started.get_mut(id as usize) {
+			Some(is_started) if !*is_started => {
+				prepare();
+				*is_started = true;
+				self.cond.notify_all();
+				true
+			}
+			_ => false,
+		}
+	}
+
+	/// Wakes up all waiting vCPUs without starting them, e.g. when the VM stops.
+	pub fn cancel(&self) {
+		self.state.lock().unwrap().cancelled = true;
+		self.cond.notify_all();
+	}
+
+	/// Returns true if vCPU `id` is started.
+	pub fn is_started(&self, id: u32) -> bool {
+		let state = self.state.lock().unwrap();
+		state.started.get(id as usize).cloned().unwrap_or(false)
+	}
+
+	/// Returns true if the guest starts the vCPUs explicitly.
+	pub fn is_explicit(&self) -> bool {
+		self.state.lock().unwrap().explicit
+	}
+
+	/// Restores the startup of a snapshot, in which the vCPUs `started` are
+	/// running.
+	pub fn restore(&self, started: &[bool], explicit: bool) {
+		let mut state = self.state.lock().unwrap();
+		for (is_started, restored) in state.started.iter_mut().zip(started.iter()) {
+			*is_started = *restored;
+		}
+		state.explicit = explicit;
+		self.cond.notify_all();
+	}
+
+	/// Blocks until vCPU `id` is started. Without the explicit startup,
+	/// `is_next` is polled and the vCPU starts as soon as it returns true.
+	/// Returns false if the startup is cancelled before.
+	pub fn wait<F: Fn() -> bool>(&self, id: u32, is_next: F) -> bool {
+		let mut state = self.state.lock().unwrap();
+		loop {
+			if state.cancelled {
+				return false;
+			}
+
+			let explicit = state.explicit;
+			match state.started.get_mut(id as usize) {
+				Some(true) => return true,
+				Some(is_started) if !explicit && is_next() => {
+					*is_started = true;
+					return true;
+				}
+				Some(_) => {}
+				None => panic!("vCPU {} doesn't exist", id),
+			}
+			state = self
+				.cond
+				.wait_timeout(state, Duration::from_millis(1))
+				.unwrap()
+				.0;
+		}
+	}
+}
+
+/// Outcome of a VM run
+#[derive(Debug, Clone)]
+pub enum VmOutcome {
+	/// The guest has exited with the exit code
+	Exit(i32),
+	/// vCPU `cpu` has crashed, e.g. by a triple fault or an error of the
+	/// hypervisor while the guest is running
+	Crash { cpu: u32, error: Option<Error> },
+	/// uhyve has failed to create, load or restore the VM
+	Error(Error),
+	/// The guest hasn't finished within the timeout
+	Timeout(Duration),
+	/// The watchdog has detected vCPUs, which make no progress
+	Hang { cpus: Vec<u32> },
+	/// The guest has reported a panic on vCPU `cpu`
+	Panic { cpu: u32 },
+}
+
+impl fmt::Display for VmOutcome {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			VmOutcome::Exit(code) => write!(f, "The guest has exited with {}", code),
+			VmOutcome::Crash { cpu, error: None } => write!(f, "CPU {} has crashed", cpu),
+			VmOutcome::Crash {
+				cpu,
+				error: Some(err),
+			} => write!(f, "CPU {} has crashed: {}", cpu, err),
+			VmOutcome::Error(err) => write!(f, "Unable to set up the VM: {}", err),
+			VmOutcome::Timeout(timeout) => {
+				write!(f, "The guest hasn't finished within {:?}", timeout)
+			}
+			VmOutcome::Hang { cpus } => write!(f, "CPUs {:?} make no progress", cpus),
+			VmOutcome::Panic { cpu } => write!(f, "The guest has panicked on CPU {}", cpu),
+		}
+	}
+}
+
+/// Returns the exit code of the guest, which has written `value` to the
+/// isa-debug-exit port.
+pub fn debug_exit_code(value: u32) -> i32 {
+	((value << 1) | 1) as i32
+}
+
+/// VM-wide stop of all vCPUs. The first request determines the outcome of
+/// the VM, later requests are ignored.
+pub struct Shutdown {
+	requested: AtomicBool,
+	outcome: Mutex<Option<VmOutcome>>,
+	cond: Condvar,
+}
+
+impl Shutdown {
+	pub fn new() -> Self {
+		Shutdown {
+			requested: AtomicBool::new(false),
+			outcome: Mutex::new(None),
+			cond: Condvar::new(),
+		}
+	}
+
+	/// Requests the stop of the VM with `outcome`. Returns false if the stop
+	/// was already requested.
+	pub fn request(&self, outcome: VmOutcome) -> bool {
+		let mut current = self.outcome.lock().unwrap();
+		if current.is_some() {
+			return false;
+		}
+
+		*current = Some(outcome);
+		self.requested.store(true, Ordering::SeqCst);
+		self.cond.notify_all();
+		true
+	}
+
+	/// Returns true if the vCPUs have to stop.
+	pub fn is_requested(&self) -> bool {
+		self.requested.load(Ordering::SeqCst)
+	}
+
+	/// Blocks until the stop is requested and returns the outcome of the VM.
+	pub fn wait(&self) -> VmOutcome {
+		let mut current = self.outcome.lock().unwrap();
+		loop {
+			match &*current {
+				Some(outcome) => return outcome.clone(),
+				None => current = self.cond.wait(current).unwrap(),
+			}
+		}
+	}
+
+	/// Blocks like `wait`, but returns `None` after `timeout`.
+	pub fn wait_timeout(&self, timeout: Duration) -> Option<VmOutcome> {
+		let current = self.outcome.lock().unwrap();
+		let (current, _) = self
+			.cond
+			.wait_timeout_while(current, timeout, |outcome| outcome.is_none())
+			.unwrap();
+		current.clone()
+	}
+}
+
+impl Default for Shutdown {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+/// Configuration of a VM. The default configuration disables all optional
+/// features, but the size of the guest memory and the number of CPUs have
+/// to be set.
+#[derive(Debug, Copy, Clone, Default)]
 pub struct Parameter<'a> {
 	pub mem_size: usize,
 	pub num_cpus: u32,
@@ -147,6 +488,116 @@ pub struct Parameter<'a> {
 	pub mask: Option<&'a str>,
 	pub nic: Option<&'a str>,
 	pub gdbport: Option<u32>,
+	pub mem_path: Option<&'a str>,
+	pub memfd: bool,
+	pub hugetlb: Option<usize>,
+	pub numa_nodes: Option<&'a [u32]>,
+	pub prefault: bool,
+	pub mlock: bool,
+	pub snapshot_on: Option<SnapshotTrigger>,
+	pub snapshot_path: Option<&'a str>,
+	pub cpu_model: Option<&'a str>,
+	pub stats: Option<&'a Arc<VmStats>>,
+	pub timeout: Option<Duration>,
+	pub watchdog: Option<Duration>,
+	pub unknown_access: Option<UnknownAccessPolicy>,
+	pub console: Option<&'a str>,
+	pub rtc_start: Option<SystemTime>,
+	pub kvm_pv: bool,
+	pub tsc_khz: Option<u32>,
+	pub profile: Option<&'a str>,
+}
+
+/// Host file, which was opened on behalf of the guest
+#[derive(Debug, Clone)]
+pub struct GuestFile {
+	/// File descriptor of the host
+	pub fd: i32,
+	pub path: PathBuf,
+	pub flags: i32,
+	pub mode: i32,
+}
+
+/// Files, which are currently opened by the guest of one VM, indexed by the
+/// file descriptor of the guest. Snapshots use this table to reopen the files.
+#[derive(Debug, Default)]
+pub struct GuestFiles {
+	files: Mutex<HashMap<i32, GuestFile>>,
+}
+
+impl GuestFiles {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Returns the host file descriptor of the guest file descriptor `fd`.
+	/// Descriptors, which the guest hasn't opened (e.g. stdout), are passed
+	/// through.
+	pub fn host_fd(&self, fd: i32) -> i32 {
+		self.files
+			.lock()
+			.unwrap()
+			.get(&fd)
+			.map_or(fd, |file| file.fd)
+	}
+
+	/// Adds `file` to the opened files of the guest and returns its file
+	/// descriptor of the guest. The guest gets the host file descriptor, unless
+	/// a file, which is restored from a snapshot, already uses this number.
+	pub fn insert(&self, file: GuestFile) -> i32 {
+		let mut files = self.files.lock().unwrap();
+		let fd = if files.contains_key(&file.fd) {
+			(3..).find(|fd| !files.contains_key(fd)).unwrap()
+		} else {
+			file.fd
+		};
+		files.insert(fd, file);
+		fd
+	}
+
+	/// Adds `file` with the guest file descriptor `fd`, e.g. when it is
+	/// restored from a snapshot.
+	pub fn insert_at(&self, fd: i32, file: GuestFile) {
+		self.files.lock().unwrap().insert(fd, file);
+	}
+
+	/// Removes the file with the guest file descriptor `fd`.
+	pub fn remove(&self, fd: i32) -> Option<GuestFile> {
+		self.files.lock().unwrap().remove(&fd)
+	}
+
+	/// Returns all opened files with their guest file descriptors.
+	pub fn to_vec(&self) -> Vec<(i32, GuestFile)> {
+		self.files
+			.lock()
+			.unwrap()
+			.iter()
+			.map(|(fd, file)| (*fd, file.clone()))
+			.collect()
+	}
+}
+
+/// Arguments and environment variables, which uhyve passes to the
+/// application. A restored VM gets the command of the restoring process,
+/// so that every clone of a snapshot may get its own command.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GuestCommand {
+	/// Arguments of the application without the kernel path
+	pub args: Vec<String>,
+	pub env: Vec<(String, String)>,
+}
+
+impl GuestCommand {
+	/// Returns the arguments after `--` and the environment of uhyve.
+	pub fn from_process() -> Self {
+		GuestCommand {
+			args: std::env::args()
+				.skip_while(|argument| argument != "--")
+				.skip(1)
+				.collect(),
+			env: std::env::vars().collect(),
+		}
+	}
 }
 
 #[repr(C, packed)]
@@ -194,6 +645,8 @@ struct SysExit {
 const MAX_ARGC: usize = 128;
 // FIXME: Do not use a fix number of environment variables
 const MAX_ENVC: usize = 128;
+/// Maximal number of frames of a backtrace
+const MAX_BACKTRACE_FRAMES: usize = 32;
 
 #[repr(C, packed)]
 struct SysCmdsize {
@@ -215,6 +668,13 @@ struct SysUnlink {
 	ret: i32,
 }
 
+#[repr(C, packed)]
+struct SysCpuStart {
+	id: u32,
+	stack: u64,
+	ret: i32,
+}
+
 pub trait VirtualCPU {
 	/// Initialize the cpu to start running the code ad entry_point.
 	fn init(&mut self, entry_point: u64) -> Result<()>;
@@ -228,47 +688,160 @@ pub trait VirtualCPU {
 	fn virt_to_phys(&self, addr: usize) -> usize;
 	/// Returns the (host) path of the kernel binary.
 	fn kernel_path(&self) -> PathBuf;
+	/// Returns the files, which the guest of the VM has opened.
+	fn guest_files(&self) -> &GuestFiles;
 
-	fn cmdsize(&self, args_ptr: usize) -> Result<()> {
-		let syssize = unsafe { &mut *(args_ptr as *mut SysCmdsize) };
-		syssize.argc = 0;
-		syssize.envc = 0;
+	/// Returns the console of the guest. Without a console, the guest reads
+	/// stdin of uhyve directly.
+	fn console(&self) -> Option<&Arc<Console>> {
+		None
+	}
 
-		let mut counter: i32 = 0;
-		let mut separator_pos: i32 = 0;
-		let path = self.kernel_path();
-		let mut found_separator = false;
-		syssize.argsz[0] = path.as_os_str().len() as i32 + 1;
+	/// Returns true if a blocking hypercall has to return early, because the
+	/// VM stops or pauses.
+	fn is_interrupted(&self) -> bool {
+		false
+	}
 
-		for argument in std::env::args() {
-			if !found_separator && argument == "--" {
-				separator_pos = counter + 1;
-				found_separator = true;
+	/// Returns the arguments and environment variables of the application.
+	fn command(&self) -> GuestCommand {
+		GuestCommand::from_process()
+	}
+
+	/// Returns the boot information, which uhyve has passed to the guest.
+	fn boot_info(&self) -> BootInfo {
+		unsafe {
+			std::ptr::read_unaligned(self.host_address(BOOT_INFO_ADDR as usize) as *const BootInfo)
+		}
+	}
+
+	/// Returns the guest-physical address of the page tables, which the vCPU
+	/// currently uses. By default, these are the boot page tables.
+	fn page_table(&self) -> u64 {
+		BOOT_PML4
+	}
+
+	/// Reads a u64 from the guest virtual address `addr` by the page tables
+	/// at `pml4`. In contrast to `virt_to_phys`, the page tables are checked
+	/// => returns `None` if the address isn't mapped, e.g. because the guest
+	/// state is corrupted.
+	fn read_guest_u64(&self, pml4: u64, addr: u64) -> Option<u64> {
+		let boot_info = self.boot_info();
+		let is_guest_mem = |phys: u64, len: u64| match phys.checked_add(len) {
+			Some(end) => {
+				end <= boot_info.limit
+					|| (phys >= boot_info.high_mem_base && end <= boot_info.high_mem_limit)
 			}
+			None => false,
+		};
+		let read = |phys: u64| unsafe {
+			std::ptr::read_unaligned(self.host_address(phys as usize) as *const u64)
+		};
+
+		if addr & 0x7 != 0 {
+			return None;
+		}
 
-			if found_separator && counter >= separator_pos {
-				syssize.argsz[(counter - separator_pos + 1) as usize] = argument.len() as i32 + 1;
+		let mut table = pml4;
+		let mut page_bits = 39;
+		let mut phys = None;
+		while phys.is_none() {
+			if !is_guest_mem(table, PAGE_SIZE as u64) {
+				return None;
+			}
+			let index = (addr >> page_bits) & ((1 << PAGE_MAP_BITS) - 1);
+			let entry = read(table + index * 8);
+			if entry & PageTableEntryFlags::PRESENT.bits() as u64 == 0 {
+				return None;
 			}
 
-			counter += 1;
+			let frame = entry & 0x000f_ffff_ffff_f000 & (!0u64 << page_bits);
+			if page_bits == PAGE_BITS as u64
+				|| entry & PageTableEntryFlags::HUGE_PAGE.bits() as u64 != 0
+			{
+				phys = Some(frame | (addr & !(!0u64 << page_bits)));
+			} else {
+				table = entry & 0x000f_ffff_ffff_f000;
+				page_bits -= PAGE_MAP_BITS as u64;
+			}
 		}
 
-		if found_separator && counter >= separator_pos {
-			syssize.argc = counter - separator_pos + 1;
+		let phys = phys.unwrap();
+		if is_guest_mem(phys, 8) {
+			Some(read(phys))
 		} else {
-			syssize.argc = 1;
+			None
 		}
+	}
 
-		counter = 0;
-		for (key, value) in std::env::vars() {
-			if counter < MAX_ENVC.try_into().unwrap() {
-				syssize.envsz[counter as usize] = (key.len() + value.len()) as i32 + 2;
-				counter += 1;
+	/// Returns the return addresses on the stack of the guest, which starts
+	/// at the frame pointer `rbp`, beginning with `rip`.
+	fn backtrace(&self, rip: u64, rbp: u64) -> Vec<u64> {
+		let pml4 = self.page_table();
+		let mut frames = vec![rip];
+		let mut rbp = rbp;
+		while frames.len() < MAX_BACKTRACE_FRAMES && rbp != 0 {
+			// the frame pointer is controlled by the guest and may be garbage
+			match (
+				self.read_guest_u64(pml4, rbp),
+				rbp.checked_add(8)
+					.and_then(|addr| self.read_guest_u64(pml4, addr)),
+			) {
+				(Some(next), Some(ret)) if ret != 0 => {
+					frames.push(ret);
+					// the stack grows downwards
+					if next <= rbp {
+						break;
+					}
+					rbp = next;
+				}
+				_ => break,
 			}
 		}
-		syssize.envc = counter;
+		frames
+	}
+
+	/// Prints the symbolized stack of the guest, which starts at `rip` and the
+	/// frame pointer `rbp`.
+	fn print_backtrace(&self, rip: u64, rbp: u64) {
+		let symbols =
+			Symbols::load(&self.kernel_path(), self.boot_info().base).unwrap_or_else(|err| {
+				warn!("Unable to read the symbols of the kernel: {}", err);
+				Symbols::default()
+			});
+
+		println!("Stack:");
+		println!("------");
+		for (i, addr) in self.backtrace(rip, rbp).iter().enumerate() {
+			println!("#{:<2} {}", i, symbols.format(*addr));
+		}
+	}
+
+	fn cmdsize(&self, args_ptr: usize) -> Result<()> {
+		let syssize = unsafe { &mut *(args_ptr as *mut SysCmdsize) };
+		let command = self.command();
+		let path = self.kernel_path();
+
+		syssize.argsz[0] = path.as_os_str().len() as i32 + 1;
+		let mut counter = 1;
+		for argument in command.args.iter().take(MAX_ARGC - 1) {
+			syssize.argsz[counter] = argument.len() as i32 + 1;
+			counter += 1;
+		}
+		syssize.argc = counter as i32;
+
+		if command.args.len() >= MAX_ARGC {
+			warn!("Too many arguments!");
+		}
+
+		counter = 0;
+		for (key, value) in command.env.iter().take(MAX_ENVC) {
+			syssize.envsz[counter] = (key.len() + value.len()) as i32 + 2;
+			counter += 1;
+		}
+		syssize.envc = counter as i32;
 
-		if counter >= MAX_ENVC.try_into().unwrap() {
+		if counter >= MAX_ENVC {
 			warn!("Environment is too large!");
 		}
 
@@ -278,71 +851,41 @@ pub trait VirtualCPU {
 	/// Copies the arguments end environment of the application into the VM's memory.
 	fn cmdval(&self, args_ptr: usize) -> Result<()> {
 		let syscmdval = unsafe { &*(args_ptr as *const SysCmdval) };
-
-		let mut counter: i32 = 0;
-		let argv = self.host_address(syscmdval.argv as usize);
-		let mut found_separator = false;
-		let mut separator_pos: i32 = 0;
+		let command = self.command();
+
+		// Copies `bytes` as null-terminated string to the buffer, whose
+		// guest address is the entry `index` of the array at `array`.
+		let copy = |array: usize, index: usize, bytes: &[&[u8]]| {
+			let ptr = unsafe {
+				self.host_address(
+					*((array + index * mem::size_of::<usize>()) as *mut *mut u8) as usize,
+				)
+			};
+			let len = bytes.iter().map(|part| part.len()).sum::<usize>();
+			let slice = unsafe { slice::from_raw_parts_mut(ptr as *mut u8, len + 1) };
+
+			let mut pos = 0;
+			for part in bytes {
+				slice[pos..pos + part.len()].copy_from_slice(part);
+				pos += part.len();
+			}
+			slice[len] = 0;
+		};
 
 		// copy kernel path as first argument
-		{
-			let path = self.kernel_path().into_os_string();
-
-			let argvptr = unsafe { self.host_address(*(argv as *mut *mut u8) as usize) };
-			let len = path.len();
-			let slice = unsafe { slice::from_raw_parts_mut(argvptr as *mut u8, len + 1) };
-
-			// Create string for environment variable
-			slice[0..len].copy_from_slice(path.as_bytes());
-			slice[len] = 0;
-		}
+		let argv = self.host_address(syscmdval.argv as usize);
+		let path = self.kernel_path().into_os_string();
+		copy(argv, 0, &[path.as_bytes()]);
 
 		// Copy the application arguments into the vm memory
-		for argument in std::env::args() {
-			if !found_separator && argument == "--" {
-				separator_pos = counter + 1;
-				found_separator = true;
-			}
-
-			if found_separator && counter >= separator_pos {
-				let argvptr = unsafe {
-					self.host_address(
-						*((argv + (counter - separator_pos + 1) as usize * mem::size_of::<usize>())
-							as *mut *mut u8) as usize,
-					)
-				};
-				let len = argument.len();
-				let slice = unsafe { slice::from_raw_parts_mut(argvptr as *mut u8, len + 1) };
-
-				// Create string for environment variable
-				slice[0..len].copy_from_slice(argument.as_bytes());
-				slice[len] = 0;
-			}
-
-			counter += 1;
+		for (i, argument) in command.args.iter().take(MAX_ARGC - 1).enumerate() {
+			copy(argv, i + 1, &[argument.as_bytes()]);
 		}
 
 		// Copy the environment variables into the vm memory
-		counter = 0;
 		let envp = self.host_address(syscmdval.envp as usize);
-		for (key, value) in std::env::vars() {
-			if counter < MAX_ENVC.try_into().unwrap() {
-				let envptr = unsafe {
-					self.host_address(
-						*((envp + counter as usize * mem::size_of::<usize>()) as *mut *mut u8)
-							as usize,
-					)
-				};
-				let len = key.len() + value.len();
-				let slice = unsafe { slice::from_raw_parts_mut(envptr as *mut u8, len + 2) };
-
-				// Create string for environment variable
-				slice[0..key.len()].copy_from_slice(key.as_bytes());
-				slice[key.len()..(key.len() + 1)].copy_from_slice("=".to_string().as_bytes());
-				slice[(key.len() + 1)..(len + 1)].copy_from_slice(value.as_bytes());
-				slice[len + 1] = 0;
-				counter += 1;
-			}
+		for (i, (key, value)) in command.env.iter().take(MAX_ENVC).enumerate() {
+			copy(envp, i, &[key.as_bytes(), b"=", value.as_bytes()]);
 		}
 
 		Ok(())
@@ -359,6 +902,33 @@ pub trait VirtualCPU {
 		Ok(())
 	}
 
+	/// Handles the request of the guest to start an application processor.
+	/// The vCPU enters the kernel with its id as `current_boot_id` and, if
+	/// the guest passes a stack, with this stack as `current_stack_address`.
+	/// As the boot information holds only one stack, the guest has to wait
+	/// until the vCPU is online before it starts the next one.
+	fn cpu_start(&self, args_ptr: usize, startup: &CpuStartup) -> Result<()> {
+		let syscpu = unsafe { &mut *(args_ptr as *mut SysCpuStart) };
+		let boot_info = self.host_address(BOOT_INFO_ADDR as usize) as *mut BootInfo;
+		let (id, stack) = (syscpu.id, syscpu.stack);
+
+		let is_started = startup.start(id, || unsafe {
+			if stack != 0 {
+				write_volatile(&mut (*boot_info).current_stack_address, stack);
+			}
+			write_volatile(&mut (*boot_info).current_boot_id, id);
+		});
+		if is_started {
+			debug!("Start vCPU {} with stack 0x{:x}", id, stack);
+			syscpu.ret = 0;
+		} else {
+			debug!("Unable to start vCPU {}", id);
+			syscpu.ret = -1;
+		}
+
+		Ok(())
+	}
+
 	/// Reads the exit code from an VM and returns it
 	fn exit(&self, args_ptr: usize) -> i32 {
 		let sysexit = unsafe { &*(args_ptr as *const SysExit) };
@@ -369,11 +939,21 @@ pub trait VirtualCPU {
 	fn open(&self, args_ptr: usize) -> Result<()> {
 		unsafe {
 			let sysopen = &mut *(args_ptr as *mut SysOpen);
-			sysopen.ret = libc::open(
-				self.host_address(sysopen.name as usize) as *const i8,
-				sysopen.flags,
-				sysopen.mode,
-			);
+			let name = self.host_address(sysopen.name as usize) as *const i8;
+			let fd = libc::open(name, sysopen.flags, sysopen.mode);
+
+			sysopen.ret = if fd >= 0 {
+				let path =
+					PathBuf::from(std::ffi::OsStr::from_bytes(CStr::from_ptr(name).to_bytes()));
+				self.guest_files().insert(GuestFile {
+					fd,
+					path,
+					flags: sysopen.flags,
+					mode: sysopen.mode,
+				})
+			} else {
+				fd
+			};
 		}
 
 		Ok(())
@@ -383,7 +963,11 @@ pub trait VirtualCPU {
 	fn close(&self, args_ptr: usize) -> Result<()> {
 		unsafe {
 			let sysclose = &mut *(args_ptr as *mut SysClose);
-			sysclose.ret = libc::close(sysclose.fd);
+			sysclose.ret = libc::close(self.guest_files().host_fd(sysclose.fd));
+
+			if sysclose.ret == 0 {
+				self.guest_files().remove(sysclose.fd);
+			}
 		}
 
 		Ok(())
@@ -395,8 +979,19 @@ pub trait VirtualCPU {
 			let sysread = &mut *(args_ptr as *mut SysRead);
 			let buffer = self.virt_to_phys(sysread.buf as usize);
 
+			if let (0, Some(console)) = (sysread.fd, self.console()) {
+				let buf =
+					slice::from_raw_parts_mut(self.host_address(buffer) as *mut u8, sysread.len);
+				// a stop or snapshot of the VM interrupts the read
+				sysread.ret = match console.read(buf, || self.is_interrupted()) {
+					Some(len) => len as isize,
+					None => -(libc::EINTR as isize),
+				};
+				return Ok(());
+			}
+
 			let bytes_read = libc::read(
-				sysread.fd,
+				self.guest_files().host_fd(sysread.fd),
 				self.host_address(buffer) as *mut libc::c_void,
 				sysread.len,
 			);
@@ -415,11 +1010,12 @@ pub trait VirtualCPU {
 		let syswrite = unsafe { &*(args_ptr as *const SysWrite) };
 		let mut bytes_written: usize = 0;
 		let buffer = self.virt_to_phys(syswrite.buf as usize);
+		let fd = self.guest_files().host_fd(syswrite.fd);
 
 		while bytes_written != syswrite.len {
 			unsafe {
 				let step = libc::write(
-					syswrite.fd,
+					fd,
 					self.host_address(buffer + bytes_written) as *const libc::c_void,
 					syswrite.len - bytes_written,
 				);
@@ -438,8 +1034,11 @@ pub trait VirtualCPU {
 	fn lseek(&self, args_ptr: usize) -> Result<()> {
 		unsafe {
 			let syslseek = &mut *(args_ptr as *mut SysLseek);
-			syslseek.offset =
-				libc::lseek(syslseek.fd, syslseek.offset as i64, syslseek.whence) as isize;
+			syslseek.offset = libc::lseek(
+				self.guest_files().host_fd(syslseek.fd),
+				syslseek.offset as i64,
+				syslseek.whence,
+			) as isize;
 		}
 
 		Ok(())
@@ -460,6 +1059,111 @@ fn create_gdt_entry(flags: u64, base: u64, limit: u64) -> u64 {
 		| (limit & 0x0000ffffu64)
 }
 
+/// Returns the guest physical address ranges, which are backed by the guest memory.
+///
+/// The guest memory is mapped contiguously from address 0 up to the 32-bit gap.
+/// The remaining memory is relocated above 4 GiB, so that the guest gets the
+/// full requested memory size.
+pub fn guest_mem_regions(mem_size: usize) -> Vec<Range<usize>> {
+	if mem_size <= KVM_32BIT_GAP_START {
+		vec![0..mem_size]
+	} else {
+		vec![
+			0..KVM_32BIT_GAP_START,
+			KVM_32BIT_MAX_MEM_SIZE..KVM_32BIT_MAX_MEM_SIZE + mem_size - KVM_32BIT_GAP_START,
+		]
+	}
+}
+
+/// Translates a guest physical address into an offset within the guest memory.
+pub fn guest_phys_to_offset(addr: usize) -> usize {
+	if addr >= KVM_32BIT_MAX_MEM_SIZE {
+		addr - KVM_32BIT_GAP_SIZE
+	} else {
+		addr
+	}
+}
+
+/// Creates the boot page tables, which identity-map the guest memory.
+///
+/// We use only a single PML4/PDPTE. Each GiB, which is completely backed by
+/// guest memory, is mapped by a 1 GiB page if `gib_pages` is set. All other
+/// GiBs get their own PDE with 2 MB pages. The 32-bit gap is not mapped.
+unsafe fn init_page_tables(mem_addr: *mut u8, mem_size: usize, gib_pages: bool) {
+	let pml4 = &mut *((mem_addr as u64 + BOOT_PML4) as *mut PageTable);
+	let pdpte = &mut *((mem_addr as u64 + BOOT_PDPTE) as *mut PageTable);
+
+	// per default is the memory zeroed, which we allocate by the system call mmap
+	pml4.entries[0].set(
+		BOOT_PDPTE as usize,
+		PageTableEntryFlags::PRESENT | PageTableEntryFlags::WRITABLE,
+	);
+	pml4.entries[511].set(
+		BOOT_PML4 as usize,
+		PageTableEntryFlags::PRESENT | PageTableEntryFlags::WRITABLE,
+	);
+
+	let regions: Vec<Range<usize>> = guest_mem_regions(mem_size)
+		.into_iter()
+		.map(|r| r.start..align_up!(r.end, LargePageSize::SIZE))
+		.collect();
+	let mem_end = regions.last().unwrap().end;
+	let gib_count = align_up!(mem_end, HugePageSize::SIZE) / HugePageSize::SIZE;
+	if gib_count > pdpte.entries.len() {
+		warn!(
+			"Boot page tables cover only the first {} GiB of the guest memory",
+			pdpte.entries.len()
+		);
+	}
+
+	let mut pde_addr = BOOT_PDE;
+	for (i, entry) in pdpte.entries.iter_mut().enumerate().take(gib_count) {
+		let start = i * HugePageSize::SIZE;
+		let end = start + HugePageSize::SIZE;
+
+		if gib_pages && regions.iter().any(|r| r.start <= start && end <= r.end) {
+			entry.set(
+				start,
+				PageTableEntryFlags::PRESENT
+					| PageTableEntryFlags::WRITABLE
+					| PageTableEntryFlags::HUGE_PAGE,
+			);
+			continue;
+		}
+
+		if !regions.iter().any(|r| r.start < end && start < r.end) {
+			continue;
+		}
+
+		if pde_addr >= BOOT_PDE_END {
+			warn!(
+				"Boot page tables cover only the first {} GiB of the guest memory",
+				i
+			);
+			break;
+		}
+
+		let pde = &mut *((mem_addr as u64 + pde_addr) as *mut PageTable);
+		for (j, pde_entry) in pde.entries.iter_mut().enumerate() {
+			let addr = start + j * LargePageSize::SIZE;
+			if regions.iter().any(|r| r.contains(&addr)) {
+				pde_entry.set(
+					addr,
+					PageTableEntryFlags::PRESENT
+						| PageTableEntryFlags::WRITABLE
+						| PageTableEntryFlags::HUGE_PAGE,
+				);
+			}
+		}
+
+		entry.set(
+			pde_addr as usize,
+			PageTableEntryFlags::PRESENT | PageTableEntryFlags::WRITABLE,
+		);
+		pde_addr += PAGE_SIZE as u64;
+	}
+}
+
 pub trait Vm {
 	/// Returns the number of cores for the vm.
 	fn num_cpus(&self) -> u32;
@@ -472,6 +1176,42 @@ pub trait Vm {
 	fn create_cpu(&self, id: u32) -> Result<Box<dyn VirtualCPU>>;
 	fn set_boot_info(&mut self, header: *const BootInfo);
 	fn cpu_online(&self) -> u32;
+	/// Returns true if the CPUID of the guest announces 1 GiB pages. By
+	/// default, the guest sees the CPUID of the host.
+	fn gib_pages(&self) -> bool {
+		CpuId::new()
+			.get_extended_processor_and_feature_identifiers()
+			.map_or(false, |info| info.has_1gib_pages())
+	}
+	/// Returns the coordinator of the vCPU startup.
+	fn cpu_startup(&self) -> &CpuStartup;
+	/// Returns the VM-wide stop of the vCPUs.
+	fn shutdown(&self) -> &Shutdown;
+	/// Forces all vCPUs to leave the guest, so that they notice a stop.
+	fn kick_cpus(&self) {}
+	/// Returns the TSC frequency of the guest in kHz, if the hypervisor
+	/// knows it. Otherwise, the frequency is detected from the host.
+	fn tsc_khz(&self) -> Option<u32> {
+		None
+	}
+	/// Stops all vCPUs of the VM. Only the first call determines the outcome.
+	fn stop(&self, outcome: VmOutcome) {
+		debug!("Stop VM: {}", outcome);
+		if self.shutdown().request(outcome) {
+			self.cpu_startup().cancel();
+			self.kick_cpus();
+		}
+	}
+	/// Announces `id` as the id of the next core, which enters the kernel.
+	fn set_current_boot_id(&self, id: u32) {
+		let (mem_addr, _) = self.guest_mem();
+
+		#[allow(clippy::cast_ptr_alignment)]
+		unsafe {
+			let boot_info = mem_addr.offset(BOOT_INFO_ADDR as isize) as *mut BootInfo;
+			write_volatile(&mut (*boot_info).current_boot_id, id);
+		}
+	}
 	fn get_ip(&self) -> Option<Ipv4Addr>;
 	fn get_gateway(&self) -> Option<Ipv4Addr>;
 	fn get_mask(&self) -> Option<Ipv4Addr>;
@@ -481,12 +1221,9 @@ pub trait Vm {
 	fn init_guest_mem(&self) {
 		debug!("Initialize guest memory");
 
-		let (mem_addr, _) = self.guest_mem();
+		let (mem_addr, mem_size) = self.guest_mem();
 
 		unsafe {
-			let pml4 = &mut *((mem_addr as u64 + BOOT_PML4) as *mut PageTable);
-			let pdpte = &mut *((mem_addr as u64 + BOOT_PDPTE) as *mut PageTable);
-			let pde = &mut *((mem_addr as u64 + BOOT_PDE) as *mut PageTable);
 			let gdt_entry: u64 = mem_addr as u64 + BOOT_GDT;
 
 			// initialize GDT
@@ -496,35 +1233,10 @@ pub trait Vm {
 			*((gdt_entry + 2 * mem::size_of::<*mut u64>() as u64) as *mut u64) =
 				create_gdt_entry(0xC093, 0, 0xFFFFF); /* data */
 
-			/* For simplicity we currently use 2MB pages and only a single
-			PML4/PDPTE/PDE. */
+			let gib_pages = self.gib_pages();
+			debug!("Guest supports 1 GiB pages: {}", gib_pages);
 
-			// per default is the memory zeroed, which we allocate by the system call mmap
-			/*libc::memset(pml4 as *mut _ as *mut libc::c_void, 0x00, PAGE_SIZE);
-			libc::memset(pdpte as *mut _ as *mut libc::c_void, 0x00, PAGE_SIZE);
-			libc::memset(pde as *mut _ as *mut libc::c_void, 0x00, PAGE_SIZE);*/
-
-			pml4.entries[0].set(
-				BOOT_PDPTE as usize,
-				PageTableEntryFlags::PRESENT | PageTableEntryFlags::WRITABLE,
-			);
-			pml4.entries[511].set(
-				BOOT_PML4 as usize,
-				PageTableEntryFlags::PRESENT | PageTableEntryFlags::WRITABLE,
-			);
-			pdpte.entries[0].set(
-				BOOT_PDE as usize,
-				PageTableEntryFlags::PRESENT | PageTableEntryFlags::WRITABLE,
-			);
-
-			for i in 0..512 {
-				pde.entries[i].set(
-					i * LargePageSize::SIZE,
-					PageTableEntryFlags::PRESENT
-						| PageTableEntryFlags::WRITABLE
-						| PageTableEntryFlags::HUGE_PAGE,
-				);
-			}
+			init_page_tables(mem_addr, mem_size, gib_pages);
 		}
 	}
 
@@ -590,10 +1302,20 @@ pub trait Vm {
 		self.set_boot_info(boot_info);
 
 		write(&mut (*boot_info).base, start_address);
-		write(&mut (*boot_info).limit, vm_mem_length as u64); // memory size
-		write(&mut (*boot_info).possible_cpus, 1);
+		// memory below the 32-bit gap, the remaining memory is relocated above 4 GiB
+		let mem_regions = guest_mem_regions(vm_mem_length);
+		write(&mut (*boot_info).limit, mem_regions[0].end as u64);
+		if let Some(high_mem) = mem_regions.get(1) {
+			debug!(
+				"Relocate guest memory to 0x{:x} - 0x{:x}",
+				high_mem.start, high_mem.end
+			);
+			write(&mut (*boot_info).high_mem_base, high_mem.start as u64);
+			write(&mut (*boot_info).high_mem_limit, high_mem.end as u64);
+		}
+		write(&mut (*boot_info).possible_cpus, self.num_cpus());
 		#[cfg(target_os = "linux")]
-		write(&mut (*boot_info).uhyve, 0x3); // announce uhyve and pci support
+		write(&mut (*boot_info).uhyve, 0x7); // announce uhyve, pci support and UHYVE_PORT_CPU_START
 		#[cfg(not(target_os = "linux"))]
 		write(&mut (*boot_info).uhyve, 0x1); // announce uhyve
 		write(&mut (*boot_info).current_boot_id, 0);
@@ -619,14 +1341,19 @@ pub trait Vm {
 			Err(err) => panic!("SystemTime before UNIX EPOCH! Error: {}", err),
 		}
 
-		let cpuid = CpuId::new();
-		let mhz: u32 = detect_freq_from_cpuid(&cpuid).unwrap_or_else(|_| {
-			debug!("Failed to detect from cpuid");
-			detect_freq_from_cpuid_hypervisor_info(&cpuid).unwrap_or_else(|_| {
-				debug!("Failed to detect from hypervisor_info");
-				get_cpu_frequency_from_os().unwrap_or(0)
-			})
-		});
+		let mhz: u32 = match self.tsc_khz() {
+			Some(khz) => (khz + 500) / 1000,
+			None => {
+				let cpuid = CpuId::new();
+				detect_freq_from_cpuid(&cpuid).unwrap_or_else(|_| {
+					debug!("Failed to detect from cpuid");
+					detect_freq_from_cpuid_hypervisor_info(&cpuid).unwrap_or_else(|_| {
+						debug!("Failed to detect from hypervisor_info");
+						get_cpu_frequency_from_os().unwrap_or(0)
+					})
+				})
+			}
+		};
 		debug!("detected a cpu frequency of {} Mhz", mhz);
 		write(&mut (*boot_info).cpu_freq, mhz);
 		if (*boot_info).cpu_freq == 0 {
@@ -635,6 +1362,19 @@ pub trait Vm {
 
 		// load kernel and determine image size
 		let vm_slice = std::slice::from_raw_parts_mut(vm_mem, vm_mem_length);
+
+		// uhyve emulates the power management of the ACPI tables only on Linux
+		#[cfg(target_os = "linux")]
+		{
+			let tables = acpi::create_tables(ACPI_TABLES_ADDR, self.num_cpus());
+			let start = ACPI_TABLES_ADDR as usize;
+			let end = start + tables.len();
+			assert!(end as u64 <= ACPI_TABLES_END, "ACPI tables are too large");
+			if end <= vm_mem_length {
+				debug!("Write ACPI tables to 0x{:x} - 0x{:x}", start, end);
+				vm_slice[start..end].copy_from_slice(&tables);
+			}
+		}
 		let mut image_size = 0;
 		elf.program_headers
 			.iter()
@@ -654,7 +1394,7 @@ pub trait Vm {
 						program_header.p_vaddr, program_header.p_filesz, program_header.p_offset
 					);
 
-					if region_start + program_header.p_memsz as usize > vm_mem_length {
+					if region_start + program_header.p_memsz as usize > mem_regions[0].end {
 						error!("Guest memory size isn't large enough");
 						return Err(Error::NotEnoughMemory);
 					}
@@ -787,12 +1527,45 @@ fn get_cpu_frequency_from_os() -> std::result::Result<u32, ()> {
 	}
 }
 
+/// Waits for the debugger, if `specs` gives a port for it.
+fn wait_for_debugger(specs: &Parameter<'_>) -> Result<Option<DebugManager>> {
+	match specs.gdbport {
+		Some(port) => DebugManager::new(port).map(Some).map_err(|err| {
+			Error::InvalidArgument(format!("unable to wait for gdb on port {}: {}", port, err))
+		}),
+		None => Ok(None),
+	}
+}
+
+pub fn create_vm(path: PathBuf, specs: &super::vm::Parameter<'_>) -> Result<Uhyve> {
+	// If we are given a port, create new DebugManager.
+	let gdb = wait_for_debugger(specs)?;
+
+	let vm = Uhyve::new(path, specs, gdb)?;
+
+	Ok(vm)
+}
+
+/// Creates a VM from the snapshot at `snapshot` and returns it together with
+/// the states of its vCPUs. vCPUs, which the guest hasn't started, have no
+/// state.
+#[cfg(target_os = "linux")]
+pub fn restore_vm(
+	snapshot: &std::path::Path,
+	specs: &super::vm::Parameter<'_>,
+) -> Result<(Uhyve, Vec<Option<crate::linux::snapshot::VcpuState>>)> {
+	let gdb = wait_for_debugger(specs)?;
+
+	Uhyve::restore(snapshot, specs, gdb)
+}
+
 #[cfg(test)]
 mod tests {
 	#[cfg(target_os = "linux")]
 	use crate::linux::tests::has_vm_support;
 
 	use super::*;
+	use std::sync::Arc;
 
 	// test is derived from
 	// https://github.com/gz/rust-cpuid/blob/master/examples/tsc_frequency.rs
@@ -881,6 +1654,84 @@ mod tests {
 		assert!(freq < 10000); //More than 10Ghz is probably wrong
 	}
 
+	#[test]
+	fn test_guest_mem_regions() {
+		assert_eq!(guest_mem_regions(0x4000000), vec![0..0x4000000]);
+		assert_eq!(
+			guest_mem_regions(KVM_32BIT_GAP_START),
+			vec![0..KVM_32BIT_GAP_START]
+		);
+		assert_eq!(
+			guest_mem_regions(8 << 30),
+			vec![
+				0..KVM_32BIT_GAP_START,
+				KVM_32BIT_MAX_MEM_SIZE..(8 << 30) + KVM_32BIT_GAP_SIZE
+			]
+		);
+		let total: usize = guest_mem_regions(64 << 30).iter().map(|r| r.len()).sum();
+		assert_eq!(total, 64 << 30);
+	}
+
+	#[test]
+	fn test_guest_phys_to_offset() {
+		assert_eq!(guest_phys_to_offset(0x1000), 0x1000);
+		assert_eq!(
+			guest_phys_to_offset(KVM_32BIT_GAP_START - 1),
+			KVM_32BIT_GAP_START - 1
+		);
+		assert_eq!(
+			guest_phys_to_offset(KVM_32BIT_MAX_MEM_SIZE),
+			KVM_32BIT_GAP_START
+		);
+		assert_eq!(
+			guest_phys_to_offset(KVM_32BIT_MAX_MEM_SIZE + 0x1000),
+			KVM_32BIT_GAP_START + 0x1000
+		);
+	}
+
+	#[test]
+	fn test_init_page_tables() {
+		let mut mem = vec![0u64; BOOT_PDE_END as usize / mem::size_of::<u64>()];
+		let mem_addr = mem.as_mut_ptr() as *mut u8;
+		let pdpte = unsafe { &*((mem_addr as u64 + BOOT_PDPTE) as *const PageTable) };
+
+		// 8 GiB with 1 GiB pages, the memory behind the 32-bit gap is relocated above 4 GiB
+		unsafe { init_page_tables(mem_addr, 8 * HugePageSize::SIZE, true) };
+		for i in 0..9 {
+			assert!(pdpte.entries[i].is_present());
+			assert_eq!(pdpte.entries[i].is_hugepage(), i != 3 && i != 8);
+		}
+		assert!(!pdpte.entries[9].is_present());
+		assert_eq!(pdpte.entries[3].address(), BOOT_PDE as usize);
+		assert_eq!(pdpte.entries[4].address(), KVM_32BIT_MAX_MEM_SIZE);
+		let pde = unsafe { &*((mem_addr as u64 + BOOT_PDE) as *const PageTable) };
+		let gap_index = (KVM_32BIT_GAP_START % HugePageSize::SIZE) / LargePageSize::SIZE;
+		assert!(pde.entries[gap_index - 1].is_present());
+		assert!(!pde.entries[gap_index].is_present());
+		assert!(!pde.entries[511].is_present());
+		let pde =
+			unsafe { &*((mem_addr as u64 + BOOT_PDE + PAGE_SIZE as u64) as *const PageTable) };
+		let high_index = (KVM_32BIT_GAP_SIZE / LargePageSize::SIZE) - 1;
+		assert!(pde.entries[high_index].is_present());
+		assert!(!pde.entries[high_index + 1].is_present());
+
+		// 2 GiB + 4 MB without 1 GiB pages
+		mem.iter_mut().for_each(|x| *x = 0);
+		unsafe { init_page_tables(mem_addr, 2 * HugePageSize::SIZE + 0x400000, false) };
+		for i in 0..3 {
+			assert!(pdpte.entries[i].is_present());
+			assert!(!pdpte.entries[i].is_hugepage());
+		}
+		let pde =
+			unsafe { &*((mem_addr as u64 + BOOT_PDE + 2 * PAGE_SIZE as u64) as *const PageTable) };
+		assert!(pde.entries[1].is_present());
+		assert_eq!(
+			pde.entries[1].address(),
+			2 * HugePageSize::SIZE + LargePageSize::SIZE
+		);
+		assert!(!pde.entries[2].is_present());
+	}
+
 	#[cfg(target_os = "linux")]
 	#[test]
 	fn test_vm_load_min_size_1024() {
@@ -896,19 +1747,49 @@ mod tests {
 			&Parameter {
 				mem_size: 1024,
 				num_cpus: 1,
-				verbose: false,
 				hugepage: true,
-				mergeable: false,
-				ip: None,
-				gateway: None,
-				mask: None,
-				nic: None,
-				gdbport: None,
+				..Default::default()
 			},
 		);
 		assert!(vm.is_err());
 	}
 
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn test_gdb_port_in_use() {
+		use nix::sys::socket::*;
+		use std::os::unix::io::FromRawFd;
+
+		// in contrast to TcpListener::bind, the socket doesn't use
+		// SO_REUSEADDR => the gdb server can't bind the port again
+		let fd = socket(
+			AddressFamily::Inet,
+			SockType::Stream,
+			SockFlag::SOCK_CLOEXEC,
+			None,
+		)
+		.unwrap();
+		let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+		let addr = InetAddr::new(IpAddr::new_v4(0, 0, 0, 0), 0);
+		bind(fd, &SockAddr::new_inet(addr)).unwrap();
+		listen(fd, 1).unwrap();
+		let port = listener.local_addr().unwrap().port();
+
+		let mut path = PathBuf::new();
+		path.push(env!("CARGO_MANIFEST_DIR"));
+		path.push("/benches_data/hello_world");
+		let vm = create_vm(
+			path,
+			&Parameter {
+				mem_size: 0x2000000,
+				num_cpus: 1,
+				gdbport: Some(port.into()),
+				..Default::default()
+			},
+		);
+		assert!(matches!(vm, Err(Error::InvalidArgument(_))));
+	}
+
 	#[cfg(target_os = "linux")]
 	#[test]
 	fn test_vm_load_min_size_102400() {
@@ -924,14 +1805,8 @@ mod tests {
 			&Parameter {
 				mem_size: 102400,
 				num_cpus: 1,
-				verbose: false,
 				hugepage: true,
-				mergeable: false,
-				ip: None,
-				gateway: None,
-				mask: None,
-				nic: None,
-				gdbport: None,
+				..Default::default()
 			},
 		)
 		.expect("Unable to create VM");
@@ -941,13 +1816,248 @@ mod tests {
 			assert!(res.is_err());
 		}
 	}
-}
 
-pub fn create_vm(path: PathBuf, specs: &super::vm::Parameter<'_>) -> Result<Uhyve> {
-	// If we are given a port, create new DebugManager.
-	let gdb = specs.gdbport.map(|port| DebugManager::new(port).unwrap());
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn test_vm_load_large_mem_size() {
+		if !has_vm_support() {
+			return;
+		}
 
-	let vm = Uhyve::new(path, specs, gdb)?;
+		let mem_size = 8 << 30;
+		let mut path = PathBuf::new();
+		path.push(env!("CARGO_MANIFEST_DIR"));
+		path.push("benches_data/hello_world");
+		let mut vm = create_vm(
+			path,
+			&Parameter {
+				mem_size,
+				num_cpus: 1,
+				hugepage: true,
+				..Default::default()
+			},
+		)
+		.expect("Unable to create VM");
+		unsafe {
+			vm.load_kernel().expect("Unable to load the kernel");
+
+			let (vm_mem, _) = vm.guest_mem();
+			let boot_info = &*(vm_mem.offset(BOOT_INFO_ADDR as isize) as *const BootInfo);
+			let (limit, high_mem_base, high_mem_limit) = (
+				boot_info.limit,
+				boot_info.high_mem_base,
+				boot_info.high_mem_limit,
+			);
+			assert_eq!(limit, KVM_32BIT_GAP_START as u64);
+			assert_eq!(high_mem_base, KVM_32BIT_MAX_MEM_SIZE as u64);
+			assert_eq!(
+				(limit - boot_info.base) + (high_mem_limit - high_mem_base),
+				mem_size as u64 - boot_info.base
+			);
+		}
+	}
 
-	Ok(vm)
+	#[test]
+	fn test_guest_fd() {
+		let file = |fd| GuestFile {
+			fd,
+			path: PathBuf::from("/tmp/file"),
+			flags: 0,
+			mode: 0,
+		};
+
+		// a restored file uses the guest file descriptor 2000
+		let files = GuestFiles::new();
+		files.insert_at(2000, file(2001));
+		assert_eq!(files.host_fd(2000), 2001);
+		let fd = files.insert(file(2000));
+		assert_ne!(fd, 2000);
+		assert_eq!(files.host_fd(fd), 2000);
+		assert_eq!(files.host_fd(1), 1);
+
+		assert_eq!(files.remove(2000).map(|file| file.fd), Some(2001));
+		assert_eq!(files.host_fd(2000), 2000);
+	}
+
+	struct CommandCpu(GuestCommand, GuestFiles);
+
+	impl VirtualCPU for CommandCpu {
+		fn init(&mut self, _entry_point: u64) -> Result<()> {
+			Ok(())
+		}
+		fn run(&mut self) -> Result<Option<i32>> {
+			Ok(None)
+		}
+		fn print_registers(&self) {}
+		fn host_address(&self, addr: usize) -> usize {
+			addr
+		}
+		fn virt_to_phys(&self, addr: usize) -> usize {
+			addr
+		}
+		fn kernel_path(&self) -> PathBuf {
+			PathBuf::from("kernel")
+		}
+		fn guest_files(&self) -> &GuestFiles {
+			&self.1
+		}
+		fn command(&self) -> GuestCommand {
+			self.0.clone()
+		}
+	}
+
+	#[test]
+	fn test_guest_command() {
+		let cpu = CommandCpu(
+			GuestCommand {
+				args: vec!["-v".to_string()],
+				env: vec![("A".to_string(), "bc".to_string())],
+			},
+			GuestFiles::new(),
+		);
+
+		let mut size: SysCmdsize = unsafe { mem::zeroed() };
+		cpu.cmdsize(&mut size as *mut SysCmdsize as usize).unwrap();
+		assert_eq!({ size.argc }, 2);
+		assert_eq!({ size.argsz }[..2], [7, 3]);
+		assert_eq!({ size.envc }, 1);
+		assert_eq!({ size.envsz }[0], 5);
+
+		let mut strings = vec![vec![0xffu8; 8]; 3];
+		let argv = [strings[0].as_mut_ptr(), strings[1].as_mut_ptr()];
+		let envp = [strings[2].as_mut_ptr()];
+		let val = SysCmdval {
+			argv: argv.as_ptr() as *const u8,
+			envp: envp.as_ptr() as *const u8,
+		};
+		cpu.cmdval(&val as *const SysCmdval as usize).unwrap();
+		assert_eq!(&strings[0][..7], b"kernel\0");
+		assert_eq!(&strings[1][..3], b"-v\0");
+		assert_eq!(&strings[2][..5], b"A=bc\0");
+	}
+
+	#[test]
+	fn test_cpu_startup_explicit() {
+		let startup = CpuStartup::new(3);
+		assert!(startup.is_started(0));
+		assert!(!startup.is_started(1));
+
+		// the request for the boot processor enables the explicit startup
+		assert!(startup.start(0, || panic!("boot processor is already running")));
+		let mut prepared = false;
+		assert!(startup.start(2, || prepared = true));
+		assert!(prepared);
+		assert!(!startup.start(2, || {}));
+		assert!(!startup.start(3, || {}));
+
+		assert!(startup.wait(2, || panic!("cpu_online isn't checked anymore")));
+		assert!(!startup.is_started(1));
+	}
+
+	#[test]
+	fn test_cpu_startup_restore() {
+		let startup = CpuStartup::new(3);
+		startup.restore(&[true, false, true], true);
+		assert!(startup.is_explicit());
+		assert!(startup.is_started(2));
+		assert!(!startup.start(2, || {}));
+		assert!(startup.start(1, || {}));
+	}
+
+	#[test]
+	fn test_cpu_startup_cpu_online() {
+		use std::sync::atomic::AtomicU32;
+
+		let startup = Arc::new(CpuStartup::new(2));
+		let cpu_online = Arc::new(AtomicU32::new(0));
+
+		let waiter = {
+			let startup = startup.clone();
+			let cpu_online = cpu_online.clone();
+			std::thread::spawn(move || startup.wait(1, || cpu_online.load(Ordering::SeqCst) == 1))
+		};
+
+		cpu_online.store(1, Ordering::SeqCst);
+		assert!(waiter.join().unwrap());
+		assert!(startup.is_started(1));
+	}
+
+	#[test]
+	fn test_cpu_startup_cancel() {
+		let startup = Arc::new(CpuStartup::new(2));
+		let waiter = {
+			let startup = startup.clone();
+			std::thread::spawn(move || startup.wait(1, || false))
+		};
+
+		startup.cancel();
+		assert!(!waiter.join().unwrap());
+		assert!(!startup.is_started(1));
+	}
+
+	#[test]
+	fn test_shutdown() {
+		let shutdown = Arc::new(Shutdown::new());
+		assert!(!shutdown.is_requested());
+
+		let waiter = {
+			let shutdown = shutdown.clone();
+			std::thread::spawn(move || shutdown.wait())
+		};
+
+		assert!(shutdown.request(VmOutcome::Exit(3)));
+		assert!(!shutdown.request(VmOutcome::Crash {
+			cpu: 0,
+			error: None
+		}));
+		assert!(shutdown.is_requested());
+		match waiter.join().unwrap() {
+			VmOutcome::Exit(code) => assert_eq!(code, 3),
+			outcome => panic!("unexpected outcome: {}", outcome),
+		}
+	}
+
+	#[test]
+	fn test_debug_exit_code() {
+		assert_eq!(debug_exit_code(0), 1);
+		// success code of many test frameworks
+		assert_eq!(debug_exit_code(0x10), 33);
+		assert_eq!(debug_exit_code(0x7f), 255);
+	}
+
+	#[test]
+	fn test_unknown_access() {
+		assert_eq!(
+			"strict".parse::<UnknownAccessPolicy>().unwrap(),
+			UnknownAccessPolicy::Strict
+		);
+		assert!("panic".parse::<UnknownAccessPolicy>().is_err());
+		assert_eq!(UnknownAccessPolicy::default(), UnknownAccessPolicy::Ignore);
+
+		let write = UnknownAccess {
+			addr: 0x80,
+			mmio: false,
+			write: true,
+			size: 2,
+			data: vec![0x34, 0x12],
+			rip: 0x1000,
+		};
+		assert_eq!(
+			write.to_string(),
+			"write to I/O port 0x80 (size 2, data 0x1234) at RIP 0x1000"
+		);
+
+		let read = UnknownAccess {
+			addr: 0xfec0_0000,
+			mmio: true,
+			write: false,
+			size: 4,
+			data: Vec::new(),
+			rip: 0x2000,
+		};
+		assert_eq!(
+			read.to_string(),
+			"read from MMIO address 0xfec00000 (size 4) at RIP 0x2000"
+		);
+	}
 }
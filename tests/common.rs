@@ -1,5 +1,8 @@
 use std::{path::PathBuf, process::Command};
-use uhyvelib::{uhyve_run, vm::Parameter};
+use uhyvelib::{
+	uhyve_run,
+	vm::{Parameter, VmOutcome},
+};
 
 /// Uses Cargo to build a kernel in the `tests/test-kernels/` directory.
 /// Returns a path to the build binary.
@@ -26,14 +29,11 @@ pub fn run_simple_vm(kernel_path: PathBuf) {
 	let params = Parameter {
 		mem_size: 32 * 1024 * 1024,
 		num_cpus: 2,
-		verbose: false,
 		hugepage: true,
-		mergeable: false,
-		ip: None,
-		gateway: None,
-		mask: None,
-		nic: None,
-		gdbport: None,
+		..Default::default()
 	};
-	uhyve_run(kernel_path, &params, None);
+	match uhyve_run(kernel_path, &params, None) {
+		VmOutcome::Exit(_) => {}
+		outcome => panic!("{}", outcome),
+	}
 }